@@ -0,0 +1,100 @@
+use std::collections::BTreeMap;
+
+use crate::lease_gen::{RIHist, RIHists};
+
+/// How reuse intervals are grouped before lease assignment.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum RIBinning {
+    /// Every distinct RI is its own candidate lease
+    #[default]
+    Exact,
+    /// `ri_bins` bins per doubling of the RI
+    Log,
+    /// Up to `ri_bins` bins per histogram, each with about the same number of samples
+    Quantile,
+}
+
+/// Merges the RIs of every histogram into bins. A bin keeps the total count and phase costs of
+/// its RIs under its longest RI, so a lease at a bin edge gets exactly the hits it would get on
+/// the exact histogram, and the cost of any lease is overestimated by at most
+/// `max_relative_error` of the result.
+pub fn bin_ri_hists(ri_hists: &RIHists, binning: RIBinning, bins: u64) -> RIHists {
+    let bins = bins.max(1);
+    let binned = ri_hists
+        .ri_hists
+        .iter()
+        .map(|(&ref_id, ri_hist)| {
+            let binned_hist = match binning {
+                RIBinning::Exact => ri_hist.clone(),
+                RIBinning::Log => merge_bins(ri_hist, |ri, _| log_bin(ri, bins)),
+                RIBinning::Quantile => {
+                    let total: u64 = ri_hist.values().map(|(count, _)| count).sum();
+                    //bin of the first sample of each RI, so a heavy RI never spans two bins
+                    merge_bins(ri_hist, |_, before| before * bins / total.max(1))
+                }
+            };
+            (ref_id, binned_hist)
+        })
+        .collect();
+    RIHists::new(binned)
+}
+
+//the log bin of an RI: floor(log2(ri) * bins)
+fn log_bin(ri: u64, bins: u64) -> u64 {
+    ((ri.max(1) as f64).log2() * bins as f64).floor() as u64
+}
+
+//merges consecutive RIs that `bin` maps to the same bin; `bin` gets the RI and the number of
+//samples with a shorter RI
+fn merge_bins(ri_hist: &RIHist, bin: impl Fn(u64, u64) -> u64) -> RIHist {
+    let mut groups: Vec<(u64, Vec<u64>)> = Vec::new();
+    let mut before = 0;
+    for (&ri, (count, _)) in ri_hist.iter() {
+        let ri_bin = bin(ri, before);
+        match groups.last_mut() {
+            Some((last_bin, ris)) if *last_bin == ri_bin => ris.push(ri),
+            _ => groups.push((ri_bin, vec![ri])),
+        }
+        before += count;
+    }
+
+    let mut binned = RIHist::new();
+    for (_, ris) in groups {
+        let edge = *ris.last().unwrap();
+        let entry = binned.entry(edge).or_insert((0, BTreeMap::new()));
+        for ri in ris {
+            let (count, phase_costs) = &ri_hist[&ri];
+            entry.0 += count;
+            for (&phase, &(head, tail)) in phase_costs.iter() {
+                let cost = entry.1.entry(phase).or_insert((0, 0));
+                cost.0 += head;
+                cost.1 += tail;
+            }
+        }
+    }
+    binned
+}
+
+/// Largest factor, minus one, by which binning lengthened any sampled RI. This bounds the
+/// relative overestimate of every lease cost; a log binning never exceeds `2^(1/bins) - 1`.
+pub fn max_relative_error(exact: &RIHists, binned: &RIHists) -> f64 {
+    let mut max_error: f64 = 0.0;
+    for (ref_id, ri_hist) in exact.ri_hists.iter() {
+        let binned_hist = &binned.ri_hists[ref_id];
+        for &ri in ri_hist.keys() {
+            let (&edge, _) = binned_hist.range(ri..).next().unwrap();
+            max_error = max_error.max(edge as f64 / ri.max(1) as f64 - 1.0);
+        }
+    }
+    max_error
+}
+
+/// Number of (reference, set, RI) entries, which is the number of candidate leases `get_ppuc`
+/// generates from a base lease of 0.
+pub fn num_entries(ri_hists: &RIHists) -> usize {
+    ri_hists
+        .ri_hists
+        .values()
+        .map(|ri_hist| ri_hist.len())
+        .sum()
+}
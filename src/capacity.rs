@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+
+/// Cache capacity, in blocks, available to the program in each phase, and optionally in each
+/// (phase, set). Phases and sets it does not list get the whole cache.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CapacitySchedule {
+    /// Blocks of the whole cache available in a phase, spread over the sets in proportion to
+    /// their capacities
+    pub phases: BTreeMap<u64, u64>,
+    /// Blocks of one set available in a phase, overriding the phase's share
+    pub sets: BTreeMap<(u64, u64), u64>,
+}
+
+impl CapacitySchedule {
+    /// Entries that can never apply: phases the trace does not have, or sets past the cache's
+    /// `num_sets`.
+    pub fn mismatches(&self, samples_per_phase: &BTreeMap<u64, u64>, num_sets: u64) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        for &phase in self.phases.keys() {
+            if !samples_per_phase.contains_key(&phase) {
+                mismatches.push(format!("phase {} is not in the trace", phase));
+            }
+        }
+        for &(phase, set) in self.sets.keys() {
+            if !samples_per_phase.contains_key(&phase) {
+                mismatches.push(format!(
+                    "phase {} of set {} is not in the trace",
+                    phase, set
+                ));
+            }
+            if set >= num_sets {
+                mismatches.push(format!(
+                    "set {} of phase {} is not in the cache's {} sets",
+                    set, phase, num_sets
+                ));
+            }
+        }
+        mismatches
+    }
+}
+
+/// Reads a capacity schedule: one `phase capacity` or `phase set capacity` line per entry,
+/// capacities in cache blocks, fields separated by whitespace or commas and `#` starting a
+/// comment.
+pub fn parse_capacity_schedule(path: &str) -> Result<CapacitySchedule, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| format!("cannot read {}: {}", path, err))?;
+    let mut schedule = CapacitySchedule::default();
+    for (line_num, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        let fields = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|field| !field.is_empty())
+            .map(|field| field.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|err| format!("{}:{}: {}", path, line_num + 1, err))?;
+        match fields[..] {
+            [] => {}
+            [phase, capacity] => {
+                schedule.phases.insert(phase, capacity);
+            }
+            [phase, set, capacity] => {
+                schedule.sets.insert((phase, set), capacity);
+            }
+            _ => {
+                return Err(format!(
+                    "{}:{}: expected `phase capacity` or `phase set capacity`",
+                    path,
+                    line_num + 1
+                ));
+            }
+        }
+    }
+    Ok(schedule)
+}
@@ -1,4 +1,10 @@
-use clap::Parser;
+use crate::binning::RIBinning;
+use crate::capacity::{CapacitySchedule, parse_capacity_schedule};
+use crate::io::{LeaseLayout, TraceFormat, packed_max_entries};
+use crate::lease_gen::{LeaseGrid, PruneStrategy, TableLimit};
+use crate::phase_detect::PhaseDetection;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
 
 #[derive(Parser)]
 #[command(
@@ -34,7 +40,7 @@ pub struct Cli {
     #[arg(short, long)]
     pub verbose: bool,
 
-    /// Number of elements in the lease lookup table
+    /// Number of elements in the lease lookup table (packed tables are bound by the memory instead)
     // #[arg(short = 'L', long, default_value = "128")]
     #[arg(short = 'L', long, default_value = "4096")]
     pub llt_size: u64,
@@ -58,6 +64,288 @@ pub struct Cli {
     /// Use given or empirically derived sampling rate
     #[arg(short = 'E', long, default_value = "yes")]
     pub empirical_sample_rate: String,
+
+    /// Input trace uses 16-byte records with full 32-bit phase and reference ids
+    #[arg(short = 'W', long)]
+    pub wide_ids: bool,
+
+    /// How to rank references when a phase has more leases than the lease lookup table holds
+    #[arg(short = 'P', long, value_enum, default_value_t = PruneStrategy::LeaseLength)]
+    pub prune_strategy: PruneStrategy,
+
+    /// Also solve for the exact optimal leases and report the optimality gap (small traces only).
+    /// With C-SHEL or more than one dual lease per phase the optimum is only a lower bound
+    #[arg(short = 'O', long)]
+    pub optimal: bool,
+
+    /// Sweeps of local search to run over the greedy leases (0 disables it)
+    #[arg(short = 'R', long, default_value = "0")]
+    pub refine_iterations: u64,
+
+    /// Time limit for the local search in milliseconds
+    #[arg(long, default_value = "1000")]
+    pub refine_millis: u64,
+
+    /// Number of references per phase that may get a dual (probabilistic) lease; only the
+    /// per-entry layout holds more than one
+    #[arg(long, default_value = "1")]
+    pub max_dual_leases_per_phase: u64,
+
+    /// Layout of the lease tables written for the hardware
+    #[arg(long, value_enum, default_value_t = LeaseLayout::Single)]
+    pub lease_layout: LeaseLayout,
+
+    /// Give every reference a distribution over several leases instead of a short/long pair
+    #[arg(long)]
+    pub lease_distributions: bool,
+
+    /// References without a lease table entry bypass the cache (lease 0) instead of getting a lease of 1
+    #[arg(long)]
+    pub bypass: bool,
+
+    /// Lease every reference starts from and keeps without a lease table entry, also written to
+    /// the lease table header (0 with --bypass, otherwise 1)
+    #[arg(long, conflicts_with = "bypass")]
+    pub default_lease: Option<u64>,
+
+    /// Choose each phase's default lease to maximize hits of references outside the lease table
+    #[arg(long)]
+    pub optimize_default_lease: bool,
+
+    /// Longest lease the lease counters can hold, in accesses
+    #[arg(long)]
+    pub max_lease: Option<u64>,
+
+    /// Lease counters count in units of 2^k accesses
+    #[arg(long, default_value = "0")]
+    pub lease_granularity: u64,
+
+    /// Group reuse intervals into bins before lease assignment
+    #[arg(long, value_enum, default_value_t = RIBinning::Exact)]
+    pub ri_binning: RIBinning,
+
+    /// Bins per doubling of the RI (log binning) or per histogram (quantile binning)
+    #[arg(long, default_value = "8")]
+    pub ri_bins: u64,
+
+    /// Merge the most similar phases until their lease tables fit the lease memory. Needs the
+    /// deduplicated layout, whose phase map sends every phase to its merged phase's table
+    #[arg(long)]
+    pub merge_phases: bool,
+
+    /// Also assign leases on the exact histograms and report the loss and time of binning
+    /// against them in binning_report.txt
+    #[arg(long)]
+    pub binning_report: bool,
+
+    /// Threads for building the RI histograms
+    #[arg(long, default_value = "1")]
+    pub threads: usize,
+
+    /// Megabytes the partial RI histograms and the use-time phases may take while reading the
+    /// trace; past it they are spilled to disk. Only the reading is bounded: the merged histograms,
+    /// and the C-SHEL tail pass that charges them, stay in memory
+    #[arg(long)]
+    pub memory_limit: Option<u64>,
+
+    /// Infer phases from the samples instead of using the phase ids of the trace
+    #[arg(long)]
+    pub detect_phases: bool,
+
+    /// Samples per window of the phase detection
+    #[arg(long, default_value = "1000")]
+    pub phase_window: usize,
+
+    /// Reference-set similarity below which a window starts a new phase (0 to 1)
+    #[arg(long, default_value = "0.5")]
+    pub phase_sensitivity: f64,
+
+    /// File of the cache blocks available per phase (`phase capacity`) or per phase and set
+    /// (`phase set capacity`); the whole cache otherwise
+    #[arg(long, value_parser = parse_capacity_schedule)]
+    pub capacity_schedule: Option<CapacitySchedule>,
+
+    /// Blocks every set can use, comma-separated, for way-partitioned caches or disabled ways;
+    /// an even share of the cache size otherwise
+    #[arg(long, value_delimiter = ',')]
+    pub set_capacities: Option<Vec<u64>>,
+}
+
+impl Cli {
+    /// Parses the command line, then checks the arguments clap cannot check one at a time.
+    pub fn parse_validated() -> Self {
+        let cli = Self::parse();
+        if let Err(err) = cli.validate() {
+            err.exit();
+        }
+        cli
+    }
+
+    /// Checks arguments against each other: `--set-capacities` must give every set of the
+    /// cache at least one block and at most its ways, so ways may be partitioned or disabled,
+    /// `--default-lease` must fit the lease counters and `--merge-phases` needs the
+    /// deduplicated layout.
+    pub fn validate(&self) -> Result<(), clap::Error> {
+        let error =
+            |message: String| Err(Cli::command().error(ErrorKind::ValueValidation, message));
+        if let Some(lease) = self.default_lease
+            && !self.lease_grid().is_representable(lease)
+        {
+            return error(format!(
+                "--default-lease {} does not fit the lease counters, the nearest lease is {}",
+                lease,
+                self.lease_grid().representable(lease)
+            ));
+        }
+        if self.merge_phases && self.lease_layout != LeaseLayout::Deduplicated {
+            return error(format!(
+                "--merge-phases needs the deduplicated layout to map phases to merged tables, not {:?}",
+                self.lease_layout
+            ));
+        }
+        let Some(capacities) = &self.set_capacities else {
+            return Ok(());
+        };
+        let num_ways = match self.set_associativity {
+            0 => self.cache_size,
+            ways => ways,
+        };
+        let num_sets = self.cache_size / num_ways.max(1);
+        if capacities.len() as u64 != num_sets {
+            return error(format!(
+                "--set-capacities gives {} capacities for a cache with {} sets",
+                capacities.len(),
+                num_sets
+            ));
+        }
+        if let Some(set) = capacities.iter().position(|&capacity| capacity == 0) {
+            return error(format!("--set-capacities gives set {} no blocks", set));
+        }
+        if let Some(set) = capacities.iter().position(|&capacity| capacity > num_ways) {
+            return error(format!(
+                "--set-capacities gives set {} {} blocks, more than its {} ways",
+                set, capacities[set], num_ways
+            ));
+        }
+        let total: u64 = capacities.iter().sum();
+        if total > self.cache_size {
+            return error(format!(
+                "--set-capacities add up to {} blocks, more than the cache size of {}",
+                total, self.cache_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Lease every reference starts with and falls back to without a lease table entry: the
+    /// `--default-lease`, or else 0 with `--bypass` and 1 otherwise.
+    pub fn base_lease(&self) -> u64 {
+        match self.default_lease {
+            Some(lease) => lease,
+            None if self.bypass => 0,
+            None => self.lease_grid().representable(1),
+        }
+    }
+
+    /// Leases the hardware's lease counters can hold.
+    pub fn lease_grid(&self) -> LeaseGrid {
+        LeaseGrid {
+            max_lease: self.max_lease.unwrap_or(u64::MAX),
+            granularity: self.lease_granularity,
+        }
+    }
+
+    /// Lease table entries the allocator may assign across `num_phases` phases: `llt_size` per
+    /// phase, or with packed tables whatever the lease memory holds after the phase headers.
+    pub fn table_limit(&self, num_phases: u64) -> TableLimit {
+        match self.lease_layout {
+            LeaseLayout::Packed => {
+                TableLimit::Shared(packed_max_entries(self.mem_size, num_phases))
+            }
+            _ => TableLimit::PerPhase(self.llt_size),
+        }
+    }
+
+    /// Histogram memory limit in bytes.
+    pub fn memory_limit_bytes(&self) -> Option<u64> {
+        self.memory_limit.map(|megabytes| megabytes << 20)
+    }
+
+    /// Dual leases per phase the allocator and pruning may assign: `max_dual_leases_per_phase`,
+    /// capped at the one dual lease reference a phase header holds unless every table entry has
+    /// its own probability.
+    pub fn max_dual_leases(&self) -> u64 {
+        match self.lease_layout {
+            LeaseLayout::PerEntry => self.max_dual_leases_per_phase,
+            _ => self.max_dual_leases_per_phase.min(1),
+        }
+    }
+
+    /// Most phases the lease memory holds in the chosen layout, capped by `max_scopes` unless
+    /// tables are deduplicated or packed.
+    pub fn max_phases(&self, max_scopes: u64) -> u64 {
+        let max_phases = self.lease_layout.max_phases(self.mem_size, self.llt_size);
+        match self.lease_layout {
+            LeaseLayout::Deduplicated | LeaseLayout::Packed => max_phases,
+            _ => max_scopes.min(max_phases),
+        }
+    }
+
+    /// Blocks of `set`, scaled by `scale` before the division by the number of sets so that
+    /// an even share of the cache rounds like `scale * cache_size / num_sets`.
+    pub fn set_capacity_share(&self, set: u64, num_sets: u64, scale: u64) -> u64 {
+        match &self.set_capacities {
+            //one per set, checked by `validate`
+            Some(capacities) => scale * capacities[set as usize],
+            None => scale * self.cache_size / num_sets,
+        }
+    }
+
+    /// Cache budget of a (phase, set) over `num_samples` samples of the phase: the set's blocks
+    /// in the phase, per sample, in sampled accesses. The capacity schedule may cut a phase's
+    /// capacity, shared between the sets in proportion to their own capacities, or set one
+    /// set's directly.
+    pub fn set_budget(
+        &self,
+        phase: u64,
+        set: u64,
+        num_sets: u64,
+        num_samples: u64,
+        sample_rate: u64,
+    ) -> u64 {
+        let schedule = self.capacity_schedule.as_ref();
+        let set_blocks = schedule.and_then(|schedule| schedule.sets.get(&(phase, set)));
+        let phase_blocks = schedule.and_then(|schedule| schedule.phases.get(&phase));
+        let set_capacity = match (set_blocks, phase_blocks) {
+            (Some(&blocks), _) => num_samples * blocks,
+            (None, Some(&blocks)) => {
+                let cache_blocks: u64 = (0..num_sets)
+                    .map(|set| self.set_capacity_share(set, num_sets, num_sets))
+                    .sum();
+                num_samples * blocks * self.set_capacity_share(set, num_sets, num_sets)
+                    / cache_blocks.max(1)
+            }
+            (None, None) => self.set_capacity_share(set, num_sets, num_samples),
+        };
+        set_capacity * sample_rate
+    }
+
+    /// Phase detection settings, when it is on.
+    pub fn phase_detection(&self) -> Option<PhaseDetection> {
+        self.detect_phases.then_some(PhaseDetection {
+            window: self.phase_window,
+            sensitivity: self.phase_sensitivity,
+            max_phases: self.trace_format().max_phases(),
+        })
+    }
+
+    pub fn trace_format(&self) -> TraceFormat {
+        if self.wide_ids {
+            TraceFormat::Wide
+        } else {
+            TraceFormat::Packed
+        }
+    }
 }
 
 impl Default for Cli {
@@ -77,6 +365,30 @@ impl Default for Cli {
             debug: false,
             sampling_rate: 256,
             empirical_sample_rate: "yes".to_string(),
+            wide_ids: false,
+            prune_strategy: PruneStrategy::LeaseLength,
+            optimal: false,
+            refine_iterations: 0,
+            refine_millis: 1000,
+            max_dual_leases_per_phase: 1,
+            lease_layout: LeaseLayout::Single,
+            lease_distributions: false,
+            bypass: false,
+            default_lease: None,
+            optimize_default_lease: false,
+            max_lease: None,
+            lease_granularity: 0,
+            ri_binning: RIBinning::Exact,
+            ri_bins: 8,
+            binning_report: false,
+            merge_phases: false,
+            threads: 1,
+            memory_limit: None,
+            detect_phases: false,
+            phase_window: 1000,
+            phase_sensitivity: 0.5,
+            capacity_schedule: None,
+            set_capacities: None,
         }
     }
 }
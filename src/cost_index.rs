@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+
+use crate::lease_gen::{LeaseOperationContext, RIHist, RefKey};
+
+//prefix sums over the RIs of one (reference, set) histogram, shortest RI first
+struct RefCostIndex {
+    ris: Vec<u64>,
+    //samples with an RI up to ris[i]
+    count_prefix: Vec<u64>,
+    //sum of count * RI up to ris[i]
+    ri_prefix: Vec<u64>,
+    total_count: u64,
+    //C-SHEL only: per phase the cost touches, head cost up to ris[i] and the tail cost of ris[i]
+    phases: BTreeMap<u64, (Vec<u64>, Vec<u64>)>,
+}
+
+impl RefCostIndex {
+    fn new(ri_hist: &RIHist, cshel: bool) -> Self {
+        let mut index = Self {
+            ris: Vec::with_capacity(ri_hist.len()),
+            count_prefix: Vec::with_capacity(ri_hist.len()),
+            ri_prefix: Vec::with_capacity(ri_hist.len()),
+            total_count: 0,
+            phases: BTreeMap::new(),
+        };
+        let (mut count_sum, mut ri_sum) = (0, 0);
+        for (&ri, (count, _)) in ri_hist.iter() {
+            count_sum += count;
+            ri_sum += count * ri;
+            index.ris.push(ri);
+            index.count_prefix.push(count_sum);
+            index.ri_prefix.push(ri_sum);
+        }
+        index.total_count = count_sum;
+        if cshel {
+            let touched: Vec<u64> = ri_hist
+                .values()
+                .flat_map(|(_, phase_costs)| phase_costs.keys().cloned())
+                .collect();
+            for phase in touched {
+                index.phases.entry(phase).or_insert_with(|| {
+                    let mut head_sum = 0;
+                    let mut head_prefix = Vec::with_capacity(ri_hist.len());
+                    let mut tails = Vec::with_capacity(ri_hist.len());
+                    for (_, phase_costs) in ri_hist.values() {
+                        let (head, tail) = phase_costs.get(&phase).cloned().unwrap_or((0, 0));
+                        head_sum += head;
+                        head_prefix.push(head_sum);
+                        tails.push(tail);
+                    }
+                    (head_prefix, tails)
+                });
+            }
+        }
+        index
+    }
+
+    //number of RIs up to a lease
+    fn covered(&self, lease: u64) -> usize {
+        self.ris.partition_point(|&ri| ri <= lease)
+    }
+
+    //SHEL: sum of count * min(RI, lease)
+    fn shel_cost(&self, lease: u64) -> u64 {
+        match self.covered(lease) {
+            0 => self.total_count * lease,
+            n => self.ri_prefix[n - 1] + (self.total_count - self.count_prefix[n - 1]) * lease,
+        }
+    }
+
+    //C-SHEL: head costs of the RIs up to a lease in a phase, plus the tail cost of the longest one
+    fn cshel_cost(&self, phase: u64, lease: u64) -> u64 {
+        let Some((head_prefix, tails)) = self.phases.get(&phase) else {
+            return 0;
+        };
+        match self.covered(lease) {
+            0 => 0,
+            n => head_prefix[n - 1] + tails[n - 1],
+        }
+    }
+}
+
+/// Prefix sums of every (reference, set) histogram, so that the cost of a lease change in a
+/// (phase, set) cell takes a binary search instead of a scan of the histogram. Gives the same
+/// costs as `shel_phase_ref_cost` and `cshel_phase_ref_cost`.
+pub struct CostIndex {
+    cshel: bool,
+    sample_rate: u64,
+    refs: BTreeMap<RefKey, RefCostIndex>,
+}
+
+impl CostIndex {
+    pub fn new(cshel: bool, context: &LeaseOperationContext) -> Self {
+        Self {
+            cshel,
+            sample_rate: context.sample_rate,
+            refs: context
+                .ri_hists
+                .ri_hists
+                .iter()
+                .map(|(&ref_id, ri_hist)| (ref_id, RefCostIndex::new(ri_hist, cshel)))
+                .collect(),
+        }
+    }
+
+    /// Whether a lease change of a (reference, set) can cost anything in a phase: its own phase
+    /// under SHEL, the phases its reuses span under C-SHEL.
+    pub fn touches(&self, phase: u64, ref_id: RefKey) -> bool {
+        match self.refs.get(&ref_id) {
+            None => false,
+            Some(_) if !self.cshel => phase == ref_id.phase,
+            Some(index) => index.phases.contains_key(&phase),
+        }
+    }
+
+    /// The (phase, set) cells a lease change of a reference can cost anything in, over all the
+    /// sets it is sampled in.
+    pub fn touched_cells(&self, ref_id: RefKey) -> Vec<(u64, u64)> {
+        let mut cells: Vec<(u64, u64)> = self
+            .refs
+            .range(ref_id.with_set(0)..=ref_id.with_set(u64::MAX))
+            .flat_map(|(set_ref, index)| {
+                let phases: Vec<u64> = match self.cshel {
+                    true => index.phases.keys().cloned().collect(),
+                    false => vec![set_ref.phase],
+                };
+                phases.into_iter().map(|phase| (phase, set_ref.set))
+            })
+            .collect();
+        cells.sort();
+        cells
+    }
+
+    /// Sampled hits and SHEL cost, before scaling by the sample rate, that a (reference, set)
+    /// gains moving from `old_lease` to `new_lease`.
+    pub fn marginal_hits_cost(&self, ref_id: RefKey, old_lease: u64, new_lease: u64) -> (u64, u64) {
+        let Some(index) = self.refs.get(&ref_id) else {
+            return (0, 0);
+        };
+        let hits = |lease: u64| match index.covered(lease) {
+            0 => 0,
+            n => index.count_prefix[n - 1],
+        };
+        (
+            hits(new_lease) - hits(old_lease),
+            index.shel_cost(new_lease) - index.shel_cost(old_lease),
+        )
+    }
+
+    /// Cost in a phase of moving a (reference, set) from `old_lease` to `new_lease`.
+    pub fn cost(&self, phase: u64, ref_id: RefKey, old_lease: u64, new_lease: u64) -> u64 {
+        if !self.touches(phase, ref_id) {
+            return 0;
+        }
+        let index = &self.refs[&ref_id];
+        let (old_cost, new_cost) = match self.cshel {
+            true => (
+                index.cshel_cost(phase, old_lease),
+                index.cshel_cost(phase, new_lease),
+            ),
+            false => (index.shel_cost(old_lease), index.shel_cost(new_lease)),
+        };
+        (new_cost - old_cost) * self.sample_rate
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{BTreeMap, BinaryHeap};
+
+use crate::{
+    cli::Cli,
+    cost_index::CostIndex,
+    helpers::{alpha_to_f64, fixed_alpha, is_meaningful_alpha},
+    lease_gen::*,
+};
+
+/// Lease assignment where every reference gets a discrete lease distribution instead of a
+/// short/long pair. Each reference starts with all of its probability on the base lease; the
+/// allocator takes PPUCs in the same order as `shel_cshel` and moves as much probability from a
+/// lease to the next as every (phase, set) budget the move touches can pay for. Probabilities
+/// are fixed-point fractions on the `discretize_width` grid, so costs are charged for the
+/// probabilities the hardware will use, rounded up.
+///
+/// References that end up with two leases are reported as dual leases; those with more are in
+/// `lease_distributions`.
+pub fn distribution_leases(
+    cshel: bool,
+    cli: &Cli,
+    context: &LeaseOperationContext,
+) -> Option<LeaseResults> {
+    let width = cli.discretize_width;
+    let one: u64 = 1 << width;
+    let grid = cli.lease_grid();
+    let lease_hits = get_lease_hits_on_grid(context.ri_hists, grid);
+    let budget = budget_per_cell(cli, context);
+    let trace_length = context.samples_per_phase.values().sum::<u64>() * context.sample_rate;
+    let base_lease = cli.base_lease();
+    let cost_index = CostIndex::new(cshel, context);
+    let table_limit = cli.table_limit(context.num_phases());
+
+    //{ref_id: {lease: probability}}, all probability starting on the base lease
+    let mut masses: BTreeMap<RefKey, BTreeMap<u64, u64>> = BTreeMap::new();
+    let mut used = vec![0; budget.len()];
+    let mut ppuc_tree = BinaryHeap::new();
+    let hulls = ppuc_hulls(context.ri_hists, base_lease, grid);
+    for &ref_id in context.ri_hists.ri_hists.keys() {
+        let phase_ref = ref_id.phase_ref();
+        if let std::collections::btree_map::Entry::Vacant(entry) = masses.entry(phase_ref) {
+            entry.insert(BTreeMap::from([(base_lease, one)]));
+            let base_cost = lease_cost_per_cell(&cost_index, context, phase_ref, 0, base_lease);
+            for (used, cost) in used.iter_mut().zip(base_cost.iter()) {
+                *used += cost;
+            }
+        }
+        push_highest_ppuc(&mut ppuc_tree, &hulls, ref_id, base_lease);
+    }
+    //lease table entries per phase; a reference needs one once any probability leaves the base lease
+    let mut entries: BTreeMap<u64, u64> = BTreeMap::new();
+
+    while let Some(new_lease) = ppuc_tree.pop() {
+        let ref_id = new_lease.ref_id.phase_ref();
+        let (old_lease, lease) = (new_lease.old_lease, new_lease.lease);
+        let ref_masses = masses.get(&ref_id).unwrap();
+        let available = *ref_masses.get(&old_lease).unwrap_or(&0);
+        //nothing left on the lease this PPUC starts from
+        if available == 0 {
+            continue;
+        }
+        let needs_entry = ref_masses.len() == 1 && ref_masses.contains_key(&base_lease);
+        if needs_entry && table_limit.is_full(&entries, ref_id.phase) {
+            continue;
+        }
+
+        let cost = lease_cost_per_cell(&cost_index, context, ref_id, old_lease, lease);
+        let mass = cost
+            .iter()
+            .enumerate()
+            .filter(|(_, cost)| **cost > 0)
+            .map(|(cell, cost)| fixed_alpha(budget[cell].saturating_sub(used[cell]), *cost, width))
+            .min()
+            .unwrap_or(one)
+            .min(available);
+        //too little budget left to move a probability the hardware can represent
+        if !is_meaningful_alpha(mass, width) {
+            continue;
+        }
+
+        //rounded up, since the probability left behind may move again and rounding down would
+        //undercharge every move; the mass is small enough that this still fits the budget
+        for (used, cost) in used.iter_mut().zip(cost.iter()) {
+            *used += ((*cost as u128 * mass as u128).div_ceil(one as u128)) as u64;
+        }
+        let ref_masses = masses.get_mut(&ref_id).unwrap();
+        *ref_masses.get_mut(&old_lease).unwrap() -= mass;
+        if ref_masses[&old_lease] == 0 {
+            ref_masses.remove(&old_lease);
+        }
+        *ref_masses.entry(lease).or_insert(0) += mass;
+        if needs_entry {
+            *entries.entry(ref_id.phase).or_insert(0) += 1;
+        }
+        push_highest_ppuc(&mut ppuc_tree, &hulls, new_lease.ref_id, lease);
+        //the probability left on the old lease competes again for the next lease
+        if mass < available {
+            push_highest_ppuc(&mut ppuc_tree, &hulls, new_lease.ref_id, old_lease);
+        }
+    }
+
+    let mut leases = BTreeMap::new();
+    let mut dual_leases = BTreeMap::new();
+    let mut lease_distributions = BTreeMap::new();
+    for (ref_id, ref_masses) in masses {
+        let (&short_lease, _) = ref_masses.iter().next().unwrap();
+        leases.insert(ref_id, short_lease);
+        match ref_masses.len() {
+            1 => {}
+            2 => {
+                let (&long_lease, &long_mass) = ref_masses.iter().nth(1).unwrap();
+                dual_leases.insert(ref_id, (alpha_to_f64(long_mass, width), long_lease));
+            }
+            _ => {
+                let distribution = ref_masses
+                    .iter()
+                    .map(|(&lease, &mass)| (lease, alpha_to_f64(mass, width)))
+                    .collect();
+                lease_distributions.insert(ref_id, distribution);
+            }
+        }
+    }
+    let mut lease_results = LeaseResults::new(leases, dual_leases, lease_hits, trace_length);
+    lease_results.lease_distributions = lease_distributions;
+    lease_results.default_lease = base_lease;
+    Some(lease_results)
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use crate::lease_gen::{RIHist, RefKey, split_reuse_cost};
+use crate::spill::SpillRun;
+
+/// (reference, set, RI, phase) cell of an RI histogram.
+pub type FlatCell = (RefKey, u64, u64);
+/// (count, head cost, tail cost) of a cell. The count sits on the reference's own phase.
+pub type FlatValue = (u64, u64, u64);
+
+//rough heap footprint of one cell while building, map overhead included
+const CELL_BYTES: u64 = 96;
+
+/// Builds the histograms of one shard of (reference, set) pairs. Counts and head costs go into
+/// flat cells, which are written to disk as a run whenever they pass the memory limit. The
+/// limit bounds these partial histograms only: the result is the nested histograms, in memory.
+///
+/// Cells only ever add up, so the runs are merged by reading them one at a time into the
+/// nested histograms. C-SHEL tail costs are only charged to RIs that have a head, so the first
+/// tail merges every head and the tail pass then charges the merged histograms in place.
+pub struct FlatHistBuilder {
+    cshel: bool,
+    max_cells: usize,
+    cells: BTreeMap<FlatCell, FlatValue>,
+    runs: Vec<SpillRun>,
+    merged: Option<BTreeMap<RefKey, RIHist>>,
+}
+
+impl FlatHistBuilder {
+    pub fn new(cshel: bool, memory_limit: Option<u64>) -> Self {
+        Self {
+            cshel,
+            max_cells: memory_limit
+                .map_or(usize::MAX, |bytes| (bytes / CELL_BYTES).max(1) as usize),
+            cells: BTreeMap::new(),
+            runs: Vec::new(),
+            merged: None,
+        }
+    }
+
+    /// Adds one sample of a (reference, set) with the given RI, use time and next phase transition.
+    pub fn add_sample(
+        &mut self,
+        key: RefKey,
+        ri: u64,
+        use_time: u64,
+        next_phase_tuple: (u64, u64),
+        is_head: bool,
+    ) {
+        if !self.cshel {
+            self.add((key, ri, key.phase), (1, 0, 0));
+        } else if is_head {
+            let (this_phase_cost, next_phase_cost) =
+                split_reuse_cost(use_time, ri, next_phase_tuple);
+            self.add((key, ri, key.phase), (1, this_phase_cost, 0));
+            if next_phase_cost > 0 {
+                self.add((key, ri, next_phase_tuple.1), (0, next_phase_cost, 0));
+            }
+        } else {
+            if self.merged.is_none() {
+                self.merged = Some(self.merge());
+            }
+            let ri_hists = self.merged.as_mut().unwrap();
+            let Some(ref_hist) = ri_hists.get_mut(&key) else {
+                return;
+            };
+            for (&ri_other, (_, phase_costs)) in ref_hist.range_mut(..ri) {
+                let (this_phase_tail_cost, next_phase_tail_cost) =
+                    split_reuse_cost(use_time, ri_other, next_phase_tuple);
+                phase_costs.entry(key.phase).or_insert((0, 0)).1 += this_phase_tail_cost;
+                if next_phase_tail_cost > 0 {
+                    phase_costs.entry(next_phase_tuple.1).or_insert((0, 0)).1 +=
+                        next_phase_tail_cost;
+                }
+            }
+        }
+    }
+
+    fn add(&mut self, cell: FlatCell, value: FlatValue) {
+        let sum = self.cells.entry(cell).or_insert((0, 0, 0));
+        sum.0 += value.0;
+        sum.1 += value.1;
+        sum.2 += value.2;
+        if self.cells.len() >= self.max_cells {
+            let cells = std::mem::take(&mut self.cells);
+            self.runs.push(SpillRun::write(cells.into_iter().map(
+                |((key, ri, phase), (count, head, tail))| {
+                    [
+                        key.phase,
+                        key.reference,
+                        key.set,
+                        ri,
+                        phase,
+                        count,
+                        head,
+                        tail,
+                    ]
+                },
+            )));
+        }
+    }
+
+    // reads the spilled runs and the cells still in memory into the nested histograms
+    fn merge(&mut self) -> BTreeMap<RefKey, RIHist> {
+        let mut ri_hists: BTreeMap<RefKey, RIHist> = BTreeMap::new();
+        let mut add = |(key, ri, phase): FlatCell, (count, head, tail): FlatValue| {
+            let ri_tuple = ri_hists
+                .entry(key)
+                .or_default()
+                .entry(ri)
+                .or_insert_with(|| (0, BTreeMap::new()));
+            ri_tuple.0 += count;
+            let costs = ri_tuple.1.entry(phase).or_insert((0, 0));
+            costs.0 += head;
+            costs.1 += tail;
+        };
+        for run in std::mem::take(&mut self.runs) {
+            for [phase, reference, set, ri, cell_phase, count, head, tail] in run.read() {
+                add(
+                    (RefKey::new(phase, reference, set), ri, cell_phase),
+                    (count, head, tail),
+                );
+            }
+        }
+        for (cell, value) in std::mem::take(&mut self.cells) {
+            add(cell, value);
+        }
+        ri_hists
+    }
+
+    pub fn finish(mut self) -> BTreeMap<RefKey, RIHist> {
+        match self.merged.take() {
+            Some(ri_hists) => ri_hists,
+            None => self.merge(),
+        }
+    }
+}
@@ -26,3 +26,33 @@ pub fn binary_search(vector: &[(u64, u64)], value: u64) -> Option<(u64, u64)> {
         }
     }
 }
+
+/// Dual-lease alpha as a fixed-point fraction with `width` fractional bits, so that
+/// `alpha == 1 << width` means 1.0. This is the same grid `discretize` writes for the hardware.
+///
+/// Returns the largest alpha for which `scale_by_alpha(cost, alpha, width) <= remaining_budget`,
+/// capped at 1.0.
+pub fn fixed_alpha(remaining_budget: u64, cost: u64, width: u64) -> u64 {
+    let one = 1u64 << width;
+    if cost == 0 {
+        return one;
+    }
+    let alpha = (remaining_budget as u128) * (one as u128) / (cost as u128);
+    alpha.min(one as u128) as u64
+}
+
+/// `cost * alpha`, rounded down so that an applied cost never exceeds the budget it was derived from.
+pub fn scale_by_alpha(cost: u64, alpha: u64, width: u64) -> u64 {
+    (((cost as u128) * (alpha as u128)) >> width) as u64
+}
+
+pub fn alpha_to_f64(alpha: u64, width: u64) -> f64 {
+    alpha as f64 / (1u64 << width) as f64
+}
+
+/// Whether a fixed-point alpha is above the threshold for a meaningful dual lease,
+/// `1 - (2^width - 1.5) / (2^width - 1)`, evaluated without rounding.
+pub fn is_meaningful_alpha(alpha: u64, width: u64) -> bool {
+    let one = 1u128 << width;
+    2 * (alpha as u128) * (one - 1) > one
+}
@@ -1,10 +1,17 @@
 use crate::cli::Cli;
-use crate::lease_gen::{LeaseResults, RIHists, process_sample_cost};
+use crate::flat_hist::FlatHistBuilder;
+use crate::lease_gen::{
+    LeaseOperationContext, LeaseResults, PruneStrategy, RIHist, RIHists, RefKey, budget_per_cell,
+    process_sample_cost,
+};
+use crate::phase_detect::{DetectedPhase, PhaseDetection, PhaseSegments, detect_phases};
+use crate::spill::SpillRun;
 use core::time;
 use csv::ReaderBuilder;
 use serde::Deserialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
 use std::collections::BinaryHeap;
-use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
@@ -12,27 +19,68 @@ use zstd::stream::read::Decoder;
 
 #[derive(Debug)]
 pub struct Sample {
-    pub phase_id_ref: u32,
+    pub phase: u64,
+    pub reference: u64,
     pub ri: u32,
     pub tag: u32,
     pub time: u64,
 }
 
-// Function to parse a sample from CSV and extract relevant fields
-fn parse_sample(sample: &Sample, set_mask: u32) -> (u64, u64, u64, u64) {
-    // let tag = u32::from_str_radix(&sample.tag, 16).expect("Invalid tag format");
-    // let phase_id_ref = u64::from_str_radix(&sample.phase_id_ref, 16).expect("Invalid phase_id_ref");
-    // let ri = u64::from_str_radix(&sample.backward_ri, 16).expect("Invalid backward_ri");
+/// Record layout of a compressed binary trace.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// 12-byte records: `phase_id_ref` (8-bit phase, 24-bit reference), `ri`, `tag`.
+    #[default]
+    Packed,
+    /// 16-byte records: `phase`, `reference`, `ri`, `tag`, each a full 32-bit word.
+    Wide,
+}
 
-    let tag = sample.tag;
-    let phase_id_ref = u64::from(sample.phase_id_ref) | ((tag & set_mask) as u64) << 32;
-    let ri = u64::from(sample.ri);
+impl TraceFormat {
+    pub fn record_size(&self) -> usize {
+        match self {
+            TraceFormat::Packed => 12,
+            TraceFormat::Wide => 16,
+        }
+    }
+
+    /// Number of distinct phase ids the phase field holds.
+    pub fn max_phases(&self) -> usize {
+        match self {
+            TraceFormat::Packed => 1 << 8,
+            TraceFormat::Wide => 1 << 32,
+        }
+    }
+}
 
-    let set = (tag & set_mask) as u64;
-    let set_phase_id_ref = phase_id_ref | (set << 32);
-    // println!("1set_phase_id_ref: {:x}, phase_id_ref: {:x}, tag: {:x}, set << 24: {}",
-    //         set_phase_id_ref, phase_id_ref, tag, set << 32);
-    (set_phase_id_ref, ri, phase_id_ref, set)
+// Read the next sample record, using `time` as its timestamp
+fn read_sample<R: Read>(reader: &mut R, format: TraceFormat, time: u64) -> Option<Sample> {
+    let mut buffer = [0u8; 16];
+    let buffer = &mut buffer[..format.record_size()];
+    reader.read_exact(buffer).ok()?;
+    let word = |i: usize| u32::from_le_bytes(buffer[i * 4..i * 4 + 4].try_into().unwrap());
+
+    let (phase, reference, ri, tag) = match format {
+        TraceFormat::Packed => {
+            let key = RefKey::from_phase_id_ref(word(0), 0);
+            (key.phase, key.reference, word(1), word(2))
+        }
+        TraceFormat::Wide => (word(0) as u64, word(1) as u64, word(2), word(3)),
+    };
+    Some(Sample {
+        phase,
+        reference,
+        ri,
+        tag,
+        time,
+    })
+}
+
+// Function to parse a sample and extract its set-qualified key and RI
+fn parse_sample(sample: &Sample, set_mask: u32) -> (RefKey, u64) {
+    let set = (sample.tag & set_mask) as u64;
+    let ri = u64::from(sample.ri);
+    (RefKey::new(sample.phase, sample.reference, set), ri)
 }
 
 /// Builds Reuse Interval (RI) histograms from a given input CSV file.
@@ -47,139 +95,204 @@ fn parse_sample(sample: &Sample, set_mask: u32) -> (u64, u64, u64, u64) {
 /// - `input_file`: Path to the input CSV file containing sample data.
 /// - `cshel`: Boolean flag indicating whether to process C-SHEL data.
 /// - `set_mask`: Mask used to extract the set from the tag.
+/// - `format`: Record layout of the trace.
+/// - `threads`: Threads building the histograms, each owning a shard of the (reference, set)
+///   pairs. Any count gives the same histograms.
+/// - `memory_limit`: Bytes the partial histograms may take while building, split between the
+///   threads, and the use-time phases may take while finding the phase transitions. Past it
+///   they are spilled to disk as sorted runs and merged at the end. The merged histograms are
+///   nested maps held in memory, and the C-SHEL tail pass charges them in place, so the limit
+///   does not bound the result.
+/// - `phases`: Detected phases replacing the phase ids of the trace, if any.
 ///
 /// # Returns
 /// A tuple containing:
 /// - `RIHists`: A struct containing the RI histograms.
-/// - `HashMap<u64, u64>`: A map of samples per phase.
+/// - `BTreeMap<u64, u64>`: A map of samples per phase.
 /// - `usize`: The number of first misses.
 /// - `u64`: The sampling rate.
 pub fn build_ri_hists(
     input_file: &str,
     cshel: bool,
     set_mask: u32,
-) -> (RIHists, HashMap<u64, u64>, usize, u64) {
-    let (phase_transitions, first_misses, sampling_rate) = build_phase_transitions(input_file);
+    format: TraceFormat,
+    threads: usize,
+    memory_limit: Option<u64>,
+    phases: Option<&PhaseSegments>,
+) -> (RIHists, BTreeMap<u64, u64>, usize, u64) {
     // let mut rdr = ReaderBuilder::new()
     //     .has_headers(true)
     //     .from_path(input_file)
     //     .expect("Failed to open input file");
 
-    let file = File::open(input_file).unwrap();
-    let decoder = Decoder::new(BufReader::new(file)).unwrap();
-    let mut reader = BufReader::new(decoder);
-    let mut buffer = [0u8; 12];
-
-    let mut ri_hists = HashMap::new();
-    let mut samples_per_phase = HashMap::new();
+    //the file is reopened for every pass: the decoder cannot seek back to the start
+    build_ri_hists_from_samples(
+        || in_phases(file_samples(input_file, format), phases),
+        cshel,
+        set_mask,
+        threads,
+        memory_limit,
+    )
+}
 
-    let mut process_sample = |sample: Sample, is_head: bool| {
-        let (set_phase_id_ref, ri, phase_id_ref, _) = parse_sample(&sample, set_mask);
-        let reuse_time = sample.time;
+/// Same as `build_ri_hists`, for a trace already in memory as `(phase_id_ref, forward_ri, tag)`
+/// tuples, with the index + 1 as the time of each sample. Produces exactly the histograms
+/// `build_ri_hists` gives for the same samples in a file.
+pub fn build_ri_hists_from_iter(
+    trace: &[(u32, i32, u32)],
+    cshel: bool,
+    set_mask: u32,
+    threads: usize,
+    memory_limit: Option<u64>,
+    phases: Option<&PhaseSegments>,
+) -> (RIHists, BTreeMap<u64, u64>, usize, u64) {
+    build_ri_hists_from_samples(
+        || in_phases(trace_samples(trace), phases),
+        cshel,
+        set_mask,
+        threads,
+        memory_limit,
+    )
+}
 
-        let test = ri as u32;
-        let mut ri_signed = ri as i32;
-        let use_time = if ri_signed < 0 {
-            reuse_time - (!ri_signed + 1) as u64
-        } else {
-            if ri_signed == i32::MAX {
-                0
-            } else {
-                // if reuse_time < ri_signed as u64 {
-                //     0 // If reuse time is less than RI, set to 0
-                // } else {
-                // }
-                reuse_time + ri_signed as u64
-            }
-        };
+/// Infers the phases of a trace file from its samples; see `detect_phases`.
+pub fn detect_trace_phases(
+    input_file: &str,
+    format: TraceFormat,
+    detection: PhaseDetection,
+) -> (PhaseSegments, Vec<DetectedPhase>) {
+    detect_phases(file_samples(input_file, format), detection)
+}
 
-        if ri_signed < 0 {
-            ri_signed = 0xFFFFFF; // Canonical value for negatives
-        }
+/// Infers the phases of an in-memory trace; see `detect_phases`.
+pub fn detect_trace_phases_from_iter(
+    trace: &[(u32, i32, u32)],
+    detection: PhaseDetection,
+) -> (PhaseSegments, Vec<DetectedPhase>) {
+    detect_phases(trace_samples(trace), detection)
+}
 
-        let next_phase_tuple = crate::helpers::binary_search(&phase_transitions, use_time)
-            .unwrap_or((reuse_time + 1, 0));
+// Samples of a compressed trace file, with the row number as time
+fn file_samples(input_file: &str, format: TraceFormat) -> impl Iterator<Item = Sample> {
+    let file = File::open(input_file).unwrap();
+    let decoder = Decoder::new(BufReader::new(file)).unwrap();
+    let mut reader = BufReader::new(decoder);
+    let mut row_num = 0;
+    std::iter::from_fn(move || {
+        row_num += 1;
+        read_sample(&mut reader, format, row_num)
+    })
+}
 
-        if cshel {
-            process_sample_cost(
-                &mut ri_hists,
-                set_phase_id_ref,
-                ri_signed as u64,
-                use_time,
-                next_phase_tuple,
-                is_head,
-            );
-            if is_head {
-                let phase_id = (phase_id_ref & 0xFF000000) >> 24;
-                *samples_per_phase.entry(phase_id).or_insert_with(|| 0) += 1;
-            }
-        } else {
-            let phase_id = (phase_id_ref & 0xFF000000) >> 24;
-            *samples_per_phase.entry(phase_id).or_insert(0) += 1;
-            ri_hists
-                .entry(set_phase_id_ref)
-                .or_insert_with(HashMap::new)
-                .entry(ri_signed as u64)
-                .and_modify(|e| e.0 += 1)
-                .or_insert((1, HashMap::new()))
-                .1
-                .entry(phase_id)
-                .or_insert((0, 0));
+// Samples with their phase ids replaced by the detected phases, if any. The phase is set on the
+// sample, so it is not limited by the width of the trace's phase field
+fn in_phases<'a>(
+    samples: impl Iterator<Item = Sample> + 'a,
+    phases: Option<&'a PhaseSegments>,
+) -> impl Iterator<Item = Sample> + 'a {
+    samples.map(move |mut sample| {
+        if let Some(phases) = phases {
+            sample.phase = phases.phase_at(sample.time);
         }
-    };
+        sample
+    })
+}
 
-    if cshel {
-        println!("Processing C-SHEL data");
-        for is_head in &[true, false] {
-            let mut row_num = 1;
-            while let Ok(()) = reader.read_exact(&mut buffer) {
-                let phase_id_ref = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
-                let ri = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
-                let tag = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
-                // let time         = u64::from_le_bytes(buffer[12..20].try_into().unwrap());
-                let time = row_num as u64; // Use row number as time for C-SHEL
-
-                process_sample(
-                    Sample {
-                        phase_id_ref,
-                        ri,
-                        tag,
-                        time,
-                    },
-                    *is_head,
-                );
-                row_num += 1;
+// Samples of an in-memory trace, with index + 1 as time
+fn trace_samples(trace: &[(u32, i32, u32)]) -> impl Iterator<Item = Sample> + '_ {
+    trace
+        .iter()
+        .enumerate()
+        .map(|(idx, &(phase_id_ref, forward_ri, tag))| {
+            let key = RefKey::from_phase_id_ref(phase_id_ref, 0);
+            Sample {
+                phase: key.phase,
+                reference: key.reference,
+                ri: forward_ri as u32,
+                tag,
+                time: (idx + 1) as u64,
             }
-        }
+        })
+}
+
+// Histograms of a trace that `samples` reads from the start on every call: once for the phase
+// transitions and, for C-SHEL, once more each for the head and the tail costs
+fn build_ri_hists_from_samples<I: Iterator<Item = Sample>>(
+    samples: impl Fn() -> I,
+    cshel: bool,
+    set_mask: u32,
+    threads: usize,
+    memory_limit: Option<u64>,
+) -> (RIHists, BTreeMap<u64, u64>, usize, u64) {
+    let (phase_transitions, first_misses, sampling_rate) =
+        phase_transitions_from_samples(samples(), memory_limit);
+    let mut samples_per_phase = BTreeMap::new();
+
+    let ri_hists = if threads <= 1 {
+        let mut builder = FlatHistBuilder::new(cshel, memory_limit);
+        for_each_hist_update(
+            samples,
+            cshel,
+            set_mask,
+            &phase_transitions,
+            &mut samples_per_phase,
+            |update| apply_hist_update(&mut builder, &update),
+        );
+        builder.finish()
     } else {
-        let mut row_num = 1;
-        while let Ok(()) = reader.read_exact(&mut buffer) {
-            let phase_id_ref = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
-            let ri = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
-            let tag = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
-            // let time         = u64::from_le_bytes(buffer[12..20].try_into().unwrap());
-            let time = row_num as u64; // Use row number as time for SHEL
-
-            process_sample(
-                Sample {
-                    phase_id_ref,
-                    ri,
-                    tag,
-                    time,
+        //a (reference, set) only ever goes to one shard, and each shard applies its updates in
+        //trace order, so the shards hold exactly the histograms a single thread builds
+        std::thread::scope(|scope| {
+            let (senders, workers): (Vec<_>, Vec<_>) = (0..threads)
+                .map(|_| {
+                    let (sender, receiver) =
+                        std::sync::mpsc::sync_channel::<Vec<HistUpdate>>(SHARD_QUEUE);
+                    let worker = scope.spawn(move || {
+                        let shard_limit = memory_limit.map(|bytes| bytes / threads as u64);
+                        let mut builder = FlatHistBuilder::new(cshel, shard_limit);
+                        for chunk in receiver {
+                            for update in &chunk {
+                                apply_hist_update(&mut builder, update);
+                            }
+                        }
+                        builder.finish()
+                    });
+                    (sender, worker)
+                })
+                .unzip();
+
+            let mut chunks: Vec<Vec<HistUpdate>> = (0..threads)
+                .map(|_| Vec::with_capacity(SHARD_CHUNK))
+                .collect();
+            for_each_hist_update(
+                samples,
+                cshel,
+                set_mask,
+                &phase_transitions,
+                &mut samples_per_phase,
+                |update| {
+                    let shard = shard_of(update.key, threads);
+                    chunks[shard].push(update);
+                    if chunks[shard].len() == SHARD_CHUNK {
+                        let chunk =
+                            std::mem::replace(&mut chunks[shard], Vec::with_capacity(SHARD_CHUNK));
+                        senders[shard].send(chunk).unwrap();
+                    }
                 },
-                false,
             );
-            row_num += 1;
-        }
-        // println!("Processing SHEL data");
-        // for result in rdr.deserialize() {
-        //     let sample: Sample = result.expect("Failed to deserialize sample");
-        //     process_sample(sample, false);
-        // }
-    }
+            for (sender, chunk) in senders.iter().zip(chunks) {
+                sender.send(chunk).unwrap();
+            }
+            drop(senders);
+
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().unwrap())
+                .collect::<BTreeMap<RefKey, RIHist>>()
+        })
+    };
 
     // println!("\nRI Hists: {:?}", ri_hists);
-    // println!("asdfasdfasdfasdf");
     // for (set_phase_id_ref, ri_map) in &ri_hists {
     //     let mut sorted_ri: Vec<_> = ri_map.iter().collect();
     //     sorted_ri.sort_by_key(|&(ri, _)| *ri);
@@ -196,127 +309,112 @@ pub fn build_ri_hists(
     )
 }
 
-pub fn build_ri_hists_from_iter(
-    trace: &Vec<(u32, i32, u32)>,
+//samples a worker gets at a time, and chunks that may wait for it
+const SHARD_CHUNK: usize = 4096;
+const SHARD_QUEUE: usize = 16;
+
+// One sample, ready to go into the histogram of its (reference, set)
+struct HistUpdate {
+    key: RefKey,
+    ri: u64,
+    use_time: u64,
+    next_phase_tuple: (u64, u64),
+    is_head: bool,
+}
+
+// Shard of a (reference, set), the same for every phase so a reference stays on one thread
+fn shard_of(key: RefKey, threads: usize) -> usize {
+    let hash = (key.reference.wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ key.set)
+        .wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    ((hash >> 32) % threads as u64) as usize
+}
+
+// Walks the passes over the trace, counting samples per phase and handing every sample to `sink`
+fn for_each_hist_update<I: Iterator<Item = Sample>>(
+    samples: impl Fn() -> I,
     cshel: bool,
     set_mask: u32,
-) -> (RIHists, HashMap<u64, u64>, usize, u64) {
-    // ... now do the same logic as before, using samples.iter() for the main loop ...
-    let mut ri_hists = HashMap::new();
-    let mut samples_per_phase: HashMap<u64, u64> = HashMap::new();
-
-    // For C-SHEL we need to process twice (heads and tails)
-    let mut process_sample = |sample: &(u32, i32, u32), is_head: bool, idx: usize| {
-        let (phase_id_ref, forward_ri, tag) = *sample;
-        let set = (tag & set_mask) as u32;
-        let set_phase_id_ref = phase_id_ref | (set << 24);
-        // println!("set_phase_id_ref: {:x}, phase_id_ref: {:x}, tag: {:x}, set << 24: {}",
-        //     set_phase_id_ref, phase_id_ref, tag, set << 24);
-        // let set_phase_id_ref = sample.time & set_mask as u32;
-        let ri = forward_ri;
-
-        let mut ri_signed = ri;
-
-        let phase_id = (phase_id_ref & 0xFF000000) >> 24;
-
-        if cshel {
-            // Compute phase transitions
-            let phase_transitions = build_phase_transitions_from_iter(trace);
-            let reuse_time = (idx + 1) as u32; // Use index+1 as time
-
-            let use_time = if ri < 0 {
-                reuse_time - ri.unsigned_abs()
-            } else if ri == i32::MAX {
+    phase_transitions: &[(u64, u64)],
+    samples_per_phase: &mut BTreeMap<u64, u64>,
+    mut sink: impl FnMut(HistUpdate),
+) {
+    let mut process_sample = |sample: Sample, is_head: bool| {
+        let (set_phase_id_ref, ri) = parse_sample(&sample, set_mask);
+        let reuse_time = sample.time;
+
+        let mut ri_signed = ri as i32;
+        let use_time = if ri_signed < 0 {
+            reuse_time.saturating_sub((!ri_signed + 1) as u64)
+        } else {
+            if ri_signed == i32::MAX {
                 0
             } else {
-                reuse_time + ri as u32
-            };
-
-            // Binary search for phase transition (stub below: replace with your real function)
-            // let next_phase_tuple = phase_transitions
-            //     .iter()
-            //     .find(|&&(t, _)| t > use_time)
-            //     .cloned()
-            //     .unwrap_or((reuse_time + 1, 0));
-
-            let next_phase_tuple =
-                crate::helpers::binary_search(&phase_transitions, use_time as u64)
-                    .unwrap_or((reuse_time as u64 + 1, 0));
-
-            process_sample_cost(
-                &mut ri_hists,
-                set_phase_id_ref as u64,
-                ri_signed as u64,
-                use_time as u64,
-                next_phase_tuple,
-                is_head,
-            );
-            if is_head {
-                *samples_per_phase
-                    .entry(phase_id as u64)
-                    .or_insert_with(|| 0) += 1;
+                // if reuse_time < ri_signed as u64 {
+                //     0 // If reuse time is less than RI, set to 0
+                // } else {
+                // }
+                reuse_time + ri_signed as u64
             }
-        } else {
-            *samples_per_phase.entry(phase_id as u64).or_insert(0) += 1;
-            ri_hists
-                .entry(set_phase_id_ref.into())
-                .or_insert_with(HashMap::new)
-                .entry(ri_signed as u64)
-                .and_modify(|e| e.0 += 1)
-                .or_insert((1, HashMap::new()))
-                .1
-                .entry(phase_id as u64)
-                .or_insert((0, 0));
+        };
+
+        if ri_signed < 0 {
+            ri_signed = 0xFFFFFF; // Canonical value for negatives
         }
+
+        //no transition after the use: the whole reuse stays in its own phase
+        let next_phase_tuple = crate::helpers::binary_search(phase_transitions, use_time)
+            .unwrap_or((use_time + ri_signed as u64 + 1, 0));
+
+        if is_head || !cshel {
+            *samples_per_phase.entry(sample.phase).or_insert(0) += 1;
+        }
+        sink(HistUpdate {
+            key: set_phase_id_ref,
+            ri: ri_signed as u64,
+            use_time,
+            next_phase_tuple,
+            is_head,
+        });
     };
-    let mut u_tags = std::collections::HashSet::new();
+
     if cshel {
-        // C-SHEL: process as heads and tails
+        println!("Processing C-SHEL data");
+        //tails are only charged to RIs already in the histogram, so every head goes in first
         for is_head in [true, false] {
-            for (idx, sample) in trace.iter().enumerate() {
-                process_sample(sample, is_head, idx);
-                u_tags.insert(sample.2); // store unique tags
+            for sample in samples() {
+                process_sample(sample, is_head);
             }
         }
     } else {
-        // SHEL: process once
-        for (idx, sample) in trace.iter().enumerate() {
-            process_sample(sample, false, idx);
-            u_tags.insert(sample.2); // store unique tags
+        for sample in samples() {
+            process_sample(sample, false);
         }
+        // println!("Processing SHEL data");
+        // for result in rdr.deserialize() {
+        //     let sample: Sample = result.expect("Failed to deserialize sample");
+        //     process_sample(sample, false);
+        // }
     }
+}
 
-    let sampling_rate = 1;
-    let first_misses = u_tags.len();
-
-    // println!("motheorei");
-    // // print the hist sorted by set_phase_id_ref and ri
-    // for (set_phase_id_ref, ri_map) in &ri_hists {
-    //     let mut sorted_ri: Vec<_> = ri_map.iter().collect();
-    //     sorted_ri.sort_by_key(|&(ri, _)| *ri);
-    //     println!(
-    //         "Set Phase ID Ref: {:x}, RIs: {:?}",
-    //         set_phase_id_ref, sorted_ri
-    //     );
-    // }
-    // println!("\nRI Hists: {:?}", ri_hists);
-
-    // return histograms and phase transitions if you want them all out
-    (
-        RIHists::new(ri_hists),
-        samples_per_phase,
-        first_misses,
-        sampling_rate,
-    )
+fn apply_hist_update(builder: &mut FlatHistBuilder, update: &HistUpdate) {
+    builder.add_sample(
+        update.key,
+        update.ri,
+        update.use_time,
+        update.next_phase_tuple,
+        update.is_head,
+    );
 }
 
 pub fn get_prl_hists(
     input_file: &str,
     num_bins: u64,
     set_mask: u32,
+    format: TraceFormat,
 ) -> (super::lease_gen::BinnedRIs, super::lease_gen::BinFreqs, u64) {
     let mut last_address: u64 = 0;
-    let mut all_keys: Vec<u64> = Vec::new();
+    let mut all_keys: Vec<RefKey> = Vec::new();
 
     // bin_freqs.insert(0, curr_bin_dict.clone());
     // bin_ri_distributions.insert(0, curr_ri_distribution_dict.clone());
@@ -364,12 +462,12 @@ pub fn get_prl_hists(
 
     let bin_width = ((last_address as f64) / (num_bins as f64)).ceil() as u64;
 
-    let mut bin_freqs = HashMap::<u64, HashMap<u64, u64>>::new();
-    let mut bin_ri_distributions = HashMap::<u64, HashMap<u64, HashMap<u64, u64>>>::new();
+    let mut bin_freqs = BTreeMap::<u64, BTreeMap<RefKey, u64>>::new();
+    let mut bin_ri_distributions = BTreeMap::<u64, BTreeMap<RefKey, BTreeMap<u64, u64>>>::new();
 
     let mut curr_bin: u64 = 0;
-    let mut curr_bin_dict = HashMap::<u64, u64>::new();
-    let mut curr_ri_distribution_dict = HashMap::<u64, HashMap<u64, u64>>::new();
+    let mut curr_bin_dict = BTreeMap::<RefKey, u64>::new();
+    let mut curr_ri_distribution_dict = BTreeMap::<RefKey, BTreeMap<u64, u64>>::new();
 
     // let mut rdr = ReaderBuilder::new()
     //     .has_headers(true)
@@ -382,21 +480,8 @@ pub fn get_prl_hists(
     let decoder = Decoder::new(BufReader::new(file)).unwrap();
     let mut reader = BufReader::new(decoder);
 
-    let mut buffer = [0u8; 12]; // Adjusted to read 16 bytes for Sample
     let mut row_num = 1; // Initialize row number for time
-    while let Ok(()) = reader.read_exact(&mut buffer) {
-        let phase_id_ref = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
-        let ri = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
-        let tag = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
-        // let time         = u32::from_le_bytes(buffer[12..20].try_into().unwrap());
-        let time = row_num as u64; // Use row number as time for C-SHEL
-
-        let sample = Sample {
-            phase_id_ref,
-            ri,
-            tag,
-            time,
-        };
+    while let Some(sample) = read_sample(&mut reader, format, row_num) {
         row_num += 1; // Increment row number for next sample
 
         //if outside of current bin, moved to the next
@@ -411,14 +496,14 @@ pub fn get_prl_hists(
             curr_bin += bin_width;
         }
 
-        let (addr, ri, _, _) = parse_sample(&sample, set_mask);
+        let (addr, ri) = parse_sample(&sample, set_mask);
 
         *curr_bin_dict.entry(addr).or_insert(0) += 1;
 
         // Update RI distributions
         *curr_ri_distribution_dict
             .entry(addr)
-            .or_insert_with(HashMap::new)
+            .or_default()
             .entry(ri)
             .or_insert(0) += 1;
 
@@ -434,7 +519,7 @@ pub fn get_prl_hists(
     //if a reference is not in a bin, add it with a frequency count of 0
     // let temp = bin_freqs.clone();
     // for (bin, _addrs) in &temp {
-    //     let bin_freqs_temp = bin_freqs.entry(*bin).or_insert(HashMap::new());
+    //     let bin_freqs_temp = bin_freqs.entry(*bin).or_insert(BTreeMap::new());
     //     for key in &all_keys {
     //         bin_freqs_temp.entry(*key).or_insert(0);
     //     }
@@ -458,9 +543,9 @@ pub fn get_prl_hists(
 ///
 /// This function analyzes a trace of memory accesses, where each entry is a tuple of
 /// `(phase_id_ref, forward_ri, tag)`. It computes the time of the next use for each sample,
-/// tracks unique tags (for first misses), and determines when the phase changes occur.
-/// The result is a vector of `(time, phase_id)` pairs indicating the time at which each phase transition happens,
-/// the number of unique tags (first misses), and the empirical sampling rate.
+/// and determines when the phase changes occur, exactly as `build_phase_transitions` does for a
+/// trace file. The result is a vector of `(time, phase_id)` pairs indicating the time at which
+/// each phase transition happens.
 ///
 /// # Arguments
 /// * `trace` - A reference to a vector of tuples representing the trace. Each tuple contains:
@@ -469,136 +554,65 @@ pub fn get_prl_hists(
 ///     - `tag`: Unique identifier for the memory access.
 ///
 /// # Returns
-/// A tuple containing:
 /// * `Vec<(u64, u64)>` - Vector of phase transitions as (time, phase_id).
-/// * `usize` - Number of first misses (unique tags).
-/// * `u64` - Empirical sampling rate.
 ///
 /// # Example
 /// ```
-/// use clam::io::build_phase_transitions_from_iter;
+/// use lease_generation::io::build_phase_transitions_from_iter;
 /// let trace = vec![(0x01000000, 5, 42), (0x02000000, 3, 43)];
 /// let transitions = build_phase_transitions_from_iter(&trace);
 /// ```
-pub fn build_phase_transitions_from_iter(trace: &Vec<(u32, i32, u32)>) -> (Vec<(u64, u64)>) {
-    // let mut u_tags = std::collections::HashMap::<u32, bool>::new();
-    let mut sample_hash = std::collections::HashMap::new();
-    let mut last_sample_time = 0;
-    // let mut sample_num: u64 = 0;
-
-    // for (idx, sample) in trace.iter().enumerate() {
-    //     let (phase_id_ref, forward_ri, tag) = *sample;
-    //     // store unique tags
-    //     u_tags.insert(tag, false);
-    //     let phase_id = (phase_id_ref & 0xFF000000) >> 24;
-    //     // let reuse_time = sample.time;
-    //     let current_time = (idx + 1) as u32; // Use index + 1 as time
-    //     let next_use = if (forward_ri as i32) < 0 {
-    //         current_time - (forward_ri).unsigned_abs()
-    //     } else if forward_ri == i32::MAX {
-    //         0
-    //     } else {
-    //         current_time + forward_ri as u32
-    //     };
-    //     sample_hash.insert(next_use, phase_id);
-    //     last_sample_time = current_time;
-    //     sample_num += 1;
-    // }
-    for (idx, sample) in trace.iter().enumerate() {
-        let (phase_id_ref, forward_ri, tag) = *sample;
-        // store unique tags
-        // u_tags.insert(tag, false);
-        let phase_id = (phase_id_ref & 0xFF000000) >> 24;
-        let current_time = (idx + 1) as u32; // Use index + 1 as time
-        sample_hash.insert(current_time, phase_id);
-        // last_sample_time = current_time;
-        // sample_num += 1;
-    }
-
-    // let sampling_rate = (last_sample_time as f64 / sample_num as f64).round() as u64;
-    // let first_misses = u_tags.len();
-
-    let mut sorted_samples: Vec<_> = sample_hash.iter().collect();
-    sorted_samples.sort_by_key(|a| a.0);
-
-    let mut phase_transitions = vec![(0u64, 0u64)];
-    let mut current_phase = 0u64;
-
-    for &(&time, &phase_id) in &sorted_samples {
-        if (phase_id as u64) != current_phase {
-            phase_transitions.push((time as u64, phase_id as u64));
-            current_phase = phase_id as u64;
-        }
-    }
-
-    phase_transitions
+pub fn build_phase_transitions_from_iter(trace: &[(u32, i32, u32)]) -> Vec<(u64, u64)> {
+    phase_transitions_from_samples(trace_samples(trace), None).0
 }
 
-pub fn build_phase_transitions(input_file: &str) -> (Vec<(u64, u64)>, usize, u64) {
+pub fn build_phase_transitions(
+    input_file: &str,
+    format: TraceFormat,
+) -> (Vec<(u64, u64)>, usize, u64) {
     // println!("Reading input from: {}", input_file);
-    let mut rdr = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .from_reader(File::open(input_file).unwrap());
-    let mut u_tags = HashMap::<u64, bool>::new();
-    let mut sample_hash = HashMap::new();
+    phase_transitions_from_samples(file_samples(input_file, format), None)
+}
+
+//rough heap footprint of one (use time, phase) entry while finding the phase transitions
+const TRANSITION_BYTES: u64 = 48;
+
+// Phase transitions from the phase of every sample's use time: the phase the last sample with
+// that use time ran in. Use times come out of trace order, so they are kept in a map; past the
+// memory limit the map goes to disk as a sorted run, and the runs are merged with later runs
+// winning ties
+fn phase_transitions_from_samples(
+    samples: impl Iterator<Item = Sample>,
+    memory_limit: Option<u64>,
+) -> (Vec<(u64, u64)>, usize, u64) {
+    let max_entries = memory_limit.map_or(usize::MAX, |bytes| {
+        (bytes / TRANSITION_BYTES).max(1) as usize
+    });
+    let mut u_tags = std::collections::HashSet::<u32>::new();
+    let mut sample_hash = BTreeMap::new();
+    let mut runs = Vec::new();
     let mut last_sample_time: u64 = 0;
     let mut sample_num: u64 = 0;
 
-    let file = File::open(input_file).unwrap();
-    let decoder = Decoder::new(BufReader::new(file)).unwrap();
-    let mut reader = BufReader::new(decoder);
-
-    let mut buffer = [0u8; 12]; // Adjusted to read 12 bytes for Sample
-
-    let mut row_num = 1; // Initialize row number for time
-    while let Ok(()) = reader.read_exact(&mut buffer) {
-        let phase_id_ref = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
-        let ri = u32::from_le_bytes(buffer[4..8].try_into().unwrap());
-        let tag = u32::from_le_bytes(buffer[8..12].try_into().unwrap());
-        // let time         = u32::from_le_bytes(buffer[12..20].try_into().unwrap());
-        let time = row_num as u64; // Use row number as time for SHEL
-
-        let sample = Sample {
-            phase_id_ref,
-            ri,
-            tag,
-            time,
-        };
-        row_num += 1; // Increment row number for next sample
-
-        let tag = sample.tag;
-        let phase_id_ref = sample.phase_id_ref as u64;
+    for sample in samples {
         let ri = u64::from(sample.ri);
-        u_tags.insert(u64::from(tag), false);
-        // for result in rdr.deserialize() {
-        // let sample: Sample = result.unwrap();
-        // let ri = u64::from_str_radix(&sample.backward_ri, 16).unwrap();
-        //don't use end of benchmark infinite RIs
-
         //store unique tags
-        // u_tags.insert(u64::from_str_radix(&sample.tag, 16).unwrap(), false);
-        // let phase_id_ref = u64::from_str_radix(&sample.phase_id_ref, 16).unwrap();
-        let phase_id = (phase_id_ref & 0xFF000000) >> 24;
+        u_tags.insert(sample.tag);
         let reuse_time = sample.time;
-        // println!("phase_id_ref: {}, phase_id: {}, reuse_time: {}", phase_id_ref, phase_id, reuse_time);
         let use_time = if (ri as i32) < 0 {
             reuse_time + (ri as i32).unsigned_abs() as u64
+        } else if ri == i32::MAX as u64 {
+            0
         } else {
-            // if ri > i16::MAX as u64 {
-            //     0
-            // }
-            if ri == i32::MAX as u64 {
-                0
-            } else {
-                // println!("ri: {}, reuse_time: {}", ri as i32, reuse_time);
-                // if reuse_time < ri {
-                //     0 // If reuse time is less than RI, set to 0
-                // } else {
-                // }
-                reuse_time + ri
-            }
+            reuse_time + ri
         };
-        sample_hash.insert(use_time, phase_id);
+        sample_hash.insert(use_time, sample.phase);
+        if sample_hash.len() >= max_entries {
+            let spilled = std::mem::take(&mut sample_hash);
+            runs.push(SpillRun::write(
+                spilled.into_iter().map(|(time, phase)| [time, phase]),
+            ));
+        }
         //get empircal sampling rate
         last_sample_time = sample.time;
         sample_num += 1;
@@ -609,124 +623,102 @@ pub fn build_phase_transitions(input_file: &str) -> (Vec<(u64, u64)>, usize, u64
     //every data block is associated with at least one miss in the absense of hardware prefetching.
     let first_misses = u_tags.len();
 
-    let mut sorted_samples: Vec<_> = sample_hash.iter().collect();
-    sorted_samples.sort_by_key(|a| a.0);
-
     // Get phase transitions
     let mut phase_transitions = vec![(0u64, 0u64)]; // (time, phase_id)
     let mut current_phase = 0u64;
-
-    for &(&time, &phase_id) in &sorted_samples {
+    let mut sources: Vec<Box<dyn Iterator<Item = [u64; 2]> + '_>> = runs
+        .iter()
+        .map(|run| Box::new(run.read()) as Box<dyn Iterator<Item = _>>)
+        .collect();
+    sources.push(Box::new(
+        sample_hash.into_iter().map(|(time, phase)| [time, phase]),
+    ));
+    //(time, run, phase): of the runs holding a time, the last one written comes out last
+    let mut heap = BinaryHeap::new();
+    for (idx, source) in sources.iter_mut().enumerate() {
+        if let Some([time, phase]) = source.next() {
+            heap.push(Reverse((time, idx, phase)));
+        }
+    }
+    while let Some(Reverse((time, idx, mut phase_id))) = heap.pop() {
+        if let Some([next_time, next_phase]) = sources[idx].next() {
+            heap.push(Reverse((next_time, idx, next_phase)));
+        }
+        while let Some(&Reverse((same_time, same_idx, same_phase))) = heap.peek()
+            && same_time == time
+        {
+            heap.pop();
+            phase_id = same_phase;
+            if let Some([next_time, next_phase]) = sources[same_idx].next() {
+                heap.push(Reverse((next_time, same_idx, next_phase)));
+            }
+        }
         if phase_id != current_phase {
             phase_transitions.push((time, phase_id));
             current_phase = phase_id;
         }
     }
+
     (phase_transitions, first_misses, sampling_rate)
 }
 
-#[allow(unused_variables)]
-pub fn dump_leases(
-    lease_results: LeaseResults,
-    output_file: &str,
-    sampling_rate: u64,
-    first_misses: usize,
-) -> (u64, u64) {
-    let mut num_hits = 0;
+/// (phase, reference, short lease, long lease, short lease probability) of every lease, sorted
+/// by phase and then by reference.
+pub fn lease_vector(
+    lease_results: &LeaseResults,
+    discretize_width: u64,
+) -> Vec<(u64, u64, u64, u64, f64)> {
     //create lease output vector
     let mut lease_vector: Vec<(u64, u64, u64, u64, f64)> = Vec::new();
     for (&phase_address, &lease) in lease_results.leases.iter() {
-        let lease = if lease > 0 { lease } else { 1 };
-        let phase = (phase_address & 0xFF000000) >> 24;
-        let address = phase_address & 0x00FFFFFF;
+        //a lease of 0 is a bypass only when the allocator started references there
+        let lease = lease.max(lease_results.default_lease);
+        let phase = phase_address.phase;
+        let address = phase_address.reference;
         // println!("phase_address:{}, phase: {}, address: {:x}, lease: {:x}", phase_address, phase, address, lease);
-        if lease_results.dual_leases.contains_key(&phase_address) {
+        if let Some(distribution) = lease_results.lease_distributions.get(&phase_address) {
+            //a short/long table entry can only hold the two ends of a distribution;
+            //lease_distributions.txt carries all of it
+            lease_vector.push((
+                phase,
+                address,
+                lease,
+                distribution.last().unwrap().0,
+                hardware_percentage(distribution[0].1, discretize_width),
+            ));
+        } else if lease_results.dual_leases.contains_key(&phase_address) {
+            //predict with the probability the hardware will actually use
             lease_vector.push((
                 phase,
                 address,
                 lease,
                 lease_results.dual_leases.get(&phase_address).unwrap().1,
-                1.0 - lease_results.dual_leases.get(&phase_address).unwrap().0,
+                hardware_percentage(
+                    1.0 - lease_results.dual_leases.get(&phase_address).unwrap().0,
+                    discretize_width,
+                ),
             ));
         } else {
             lease_vector.push((phase, address, lease, 0, 1.0));
         }
     }
     lease_vector.sort_by_key(|a| (a.0, a.1)); //sort by phase and then by reference
+    lease_vector
+}
+
+#[allow(unused_variables)]
+pub fn dump_leases(
+    lease_results: LeaseResults,
+    output_file: &str,
+    sampling_rate: u64,
+    first_misses: usize,
+    discretize_width: u64,
+) -> (u64, u64) {
+    let mut num_hits = 0;
+    let lease_vector = lease_vector(&lease_results, discretize_width);
     //get number of predicted misses
-    for (phase, address, lease_short, lease_long, percentage) in lease_vector.iter() {
-        //reassemble phase address
-        let phase_address = address | phase << 24;
-
-        // println!("phase: {}, address: {:x}, lease_short: {:x}, lease_long: {:x}, percentage: {}", phase, address, lease_short, lease_long, percentage);
-        //we are assuming that our sampling captures all RIS
-        //by assuming the distribution is normal
-        //thus if an RI for a reference didn't occur during runtime
-        //(i.e., the base lease of 1 that all references get)
-        //we can assume the number of hits it gets is zero.
-        if lease_results
-            .lease_hits
-            .get(&phase_address)
-            .unwrap()
-            .get(lease_short)
-            .is_some()
-        {
-            num_hits += (*lease_results
-                .lease_hits
-                .get(&phase_address)
-                .unwrap()
-                .get(lease_short)
-                .unwrap() as f64
-                * (percentage))
-                .round() as u64;
-        }
-        if lease_results
-            .lease_hits
-            .get(&phase_address)
-            .unwrap()
-            .get(lease_long)
-            .is_some()
-        {
-            num_hits += (*lease_results
-                .lease_hits
-                .get(&phase_address)
-                .unwrap()
-                .get(lease_long)
-                .unwrap() as f64
-                * (1.0 - percentage))
-                .round() as u64;
-        }
-        if lease_results
-            .lease_hits
-            .get(&phase_address)
-            .unwrap()
-            .get(lease_long)
-            .is_some()
-        {
-            // println!(
-            //     "phase: {}, address: {:x}, lease_short: {:x}, lease_long: {:x}, hits: {}",
-            //     phase,
-            //     address,
-            //     lease_short,
-            //     lease_long,
-            //     *lease_results
-            //         .lease_hits
-            //         .get(&phase_address)
-            //         .unwrap()
-            //         .get(lease_long)
-            //         .unwrap()
-            // );
-        }
-
-        // println!("num hits: {}", num_hits);
-
-        // if let Some(lease_short_hits) = lease_results.lease_hits.get(&phase_address).and_then(|hits| hits.get(lease_short)) {
-        //     num_hits += (*lease_short_hits as f64 * percentage).round() as u64;
-        // }
-        // if let Some(lease_long_hits) = lease_results.lease_hits.get(&phase_address).and_then(|hits| hits.get(lease_long)) {
-        //     num_hits += (*lease_long_hits as f64 * (1.0 - percentage)).round() as u64;
-        // }
-    }
+    num_hits = lease_results.predicted_hits(discretize_width);
+    let output_file_dir = output_file;
     let output_file = format!("{}/leases.txt", output_file);
     // println!("Writing output to: {}", output_file);
     let mut file = File::create(output_file).expect("create failed");
@@ -758,6 +750,16 @@ pub fn dump_leases(
         .expect("write failed");
     }
 
+    dump_lease_distributions(&lease_results, output_file_dir, discretize_width);
+    if !lease_results.phase_default_leases.is_empty() {
+        let mut file =
+            File::create(format!("{}/default_leases.txt", output_file_dir)).expect("create failed");
+        for (phase, default_lease) in lease_results.phase_default_leases.iter() {
+            file.write_all(format!("{:x}, {:x}\n", phase, default_lease)[..].as_bytes())
+                .expect("write failed");
+        }
+    }
+
     // lease_vector
     // println!("sampling rate: {}, first misses: {}", sampling_rate, first_misses);
     (
@@ -765,17 +767,92 @@ pub fn dump_leases(
         lease_results.trace_length - num_hits * sampling_rate + first_misses as u64,
     )
 }
-// function for generating c-files
-pub fn gen_lease_c_file(
+/// Layout of the per-phase lease tables written for the hardware.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LeaseLayout {
+    /// One dual lease per phase, described in the phase header
+    #[default]
+    Single,
+    /// A long lease and short lease probability column for every table entry
+    PerEntry,
+    /// `Single` tables, stored once however many phases use them, behind a phase map: a word
+    /// with the number of phases, one with the number of tables, then the table of every phase
+    Deduplicated,
+    /// `Single` headers, all of them first, then every phase's table sized to its references
+    /// rather than `llt_size`. Each header holds the length and word offset of its table.
+    Packed,
+}
+
+impl LeaseLayout {
+    /// Number of `llt_size`-long columns that follow each phase header.
+    pub fn num_columns(&self) -> u64 {
+        match self {
+            LeaseLayout::Single | LeaseLayout::Deduplicated | LeaseLayout::Packed => 2,
+            LeaseLayout::PerEntry => 4,
+        }
+    }
+
+    /// Most phases `mem_size` bytes hold. With deduplicated tables that is when every phase
+    /// shares a single table, and with packed tables when every table is empty; distinct or
+    /// longer tables take it down.
+    pub fn max_phases(&self, mem_size: u64, llt_size: u64) -> u64 {
+        let table_words = self.num_columns() * llt_size + 16;
+        match self {
+            LeaseLayout::Deduplicated => {
+                let map_words = (mem_size / 4).saturating_sub(table_words) / 16 * 16;
+                map_words.saturating_sub(2)
+            }
+            LeaseLayout::Packed => mem_size / 64,
+            _ => mem_size / (table_words * 4),
+        }
+    }
+}
+
+/// Most distinct tables the deduplicated layout holds next to the phase map of `num_phases`
+/// phases.
+pub fn deduplicated_max_tables(mem_size: u64, llt_size: u64, num_phases: u64) -> u64 {
+    let table_words = LeaseLayout::Deduplicated.num_columns() * llt_size + 16;
+    (mem_size / 4).saturating_sub(phase_map_words(num_phases)) / table_words
+}
+
+/// Words of the deduplicated layout's phase map, padded to a multiple of 16.
+pub fn phase_map_words(num_phases: u64) -> u64 {
+    (num_phases + 2).div_ceil(16) * 16
+}
+
+/// Words of the packed layout: a header per phase and two per table entry.
+pub fn packed_words(num_phases: u64, num_entries: u64) -> u64 {
+    16 * num_phases + 2 * num_entries
+}
+
+/// Table entries the packed layout holds in `mem_size` bytes next to `num_phases` headers.
+pub fn packed_max_entries(mem_size: u64, num_phases: u64) -> u64 {
+    (mem_size / 4).saturating_sub(16 * num_phases) / 2
+}
+
+/// One phase of the lease image: a 16-word header, then one `llt_size`-long column per field
+/// (as long as the phase's table in the packed layout).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhaseImage {
+    pub header: Vec<(u64, &'static str)>,
+    pub columns: Vec<(&'static str, Vec<u64>)>,
+}
+
+// lays out the lease tables for every phase in the hardware's memory format, or says why they
+// do not fit it
+pub fn build_lease_image(
     mut lease_vector: Vec<(u64, u64, u64, u64, f64)>,
+    default_leases: &BTreeMap<u64, u64>,
     cli: &Cli,
     max_num_scopes: u64,
-    output_file: String,
-) {
+) -> Result<Vec<PhaseImage>, String> {
     type LeaseData = (u64, u64, f64, bool);
-    type PhaseLeaseMap = HashMap<u64, HashMap<u64, LeaseData>>;
+    type PhaseLeaseMap = BTreeMap<u64, BTreeMap<u64, LeaseData>>;
 
-    let mut phase_lease_arr: PhaseLeaseMap = HashMap::new();
+    let layout = cli.lease_layout;
+    //wider layouts fit fewer phases in the same memory
+    let max_num_scopes = cli.max_phases(max_num_scopes);
+    let mut phase_lease_arr: PhaseLeaseMap = BTreeMap::new();
     let mut phases: Vec<u64> = Vec::new();
     for lease in lease_vector.iter() {
         if !phases.contains(&lease.0) {
@@ -787,42 +864,325 @@ pub fn gen_lease_c_file(
     //Since we have no way of knowing without adding
     //another dependency how many phases a program has
     //create dummy phases for all phases that can fit in memory that aren't represented
-    for phase in 0..max_num_scopes {
+    //(deduplicated and packed tables: only up to the last phase, as empty tables cost little)
+    let num_dummy_phases = match layout {
+        LeaseLayout::Deduplicated | LeaseLayout::Packed => {
+            phases.iter().max().map_or(0, |phase| phase + 1)
+        }
+        _ => max_num_scopes,
+    };
+    for phase in 0..num_dummy_phases {
         if !phases.contains(&phase) {
             lease_vector.push((phase, 0, 0, 0, 1.0));
         }
     }
 
-    //convert lease vector to hashmap of leases per phase
+    //convert lease vector to hashmap of leases per phase, in lease counter units
+    let grid = cli.lease_grid();
     for (phase, address, lease_short, lease_long, percentage) in lease_vector.iter() {
+        for lease in [lease_short, lease_long] {
+            if !grid.is_representable(*lease) {
+                return Err(format!(
+                    "Lease {:x} of reference {:x} in phase {} does not fit the lease counters!",
+                    lease, address, phase
+                ));
+            }
+        }
         phase_lease_arr
             .entry(*phase)
             .or_default()
             .entry(*address)
-            .or_insert((*lease_short, *lease_long, *percentage, lease_long > &0));
+            .or_insert((
+                grid.counter_value(*lease_short),
+                grid.counter_value(*lease_long),
+                *percentage,
+                lease_long > &0,
+            ));
     }
-    let default_lease = 1;
+    let max_dual_leases = match layout {
+        LeaseLayout::Single | LeaseLayout::Deduplicated | LeaseLayout::Packed => 1,
+        LeaseLayout::PerEntry => cli.max_dual_leases_per_phase,
+    };
 
-    //make sure each phase can fit in the specified LLT
+    //make sure each phase can fit in the specified LLT; packed tables are only bound by the
+    //memory, checked below
     for (phase, phase_leases) in phase_lease_arr.iter() {
-        if phase_leases.len() > cli.llt_size as usize {
-            println!(
+        if layout != LeaseLayout::Packed && phase_leases.len() > cli.llt_size as usize {
+            return Err(format!(
                 "Leases for Phase {} don't fit in lease lookup table!",
                 phase
-            );
-            panic!();
+            ));
+        }
+        let num_dual_leases = phase_leases.values().filter(|lease| lease.3).count() as u64;
+        if num_dual_leases > max_dual_leases {
+            return Err(format!(
+                "Phase {} has {} dual leases but the {:?} layout holds {}!",
+                phase, num_dual_leases, layout, max_dual_leases
+            ));
         }
     }
 
     //make sure that all phases can fit in the memory allocated
-    if *phases.iter().max().unwrap() > max_num_scopes {
-        println!(
-            "Error: phases cannot fit in specified {} byte memory",
+    if *phases.iter().max().unwrap() >= max_num_scopes {
+        return Err(format!(
+            "phases cannot fit in specified {} byte memory",
             cli.mem_size
-        );
-        panic!();
+        ));
+    }
+
+    //packed tables start after every phase header
+    let mut table_offset = 16 * phase_lease_arr.len() as u64;
+    let mut image = Vec::new();
+    for i in 0..phase_lease_arr.len() {
+        let phase_leases = phase_lease_arr.get(&(i as u64)).unwrap();
+
+        let mut dual_lease_ref = (0, 0, 1.0);
+        let mut lease_phase: Vec<(u64, LeaseData)> = Vec::new();
+        for (lease_ref, lease_data) in phase_leases.iter() {
+            //convert hashmap of leases for phase to vector
+            lease_phase.push((*lease_ref, *lease_data));
+            //get dual lease if it exists;
+            if lease_data.3 {
+                dual_lease_ref = (*lease_ref, lease_data.1, lease_data.2);
+            }
+        }
+        lease_phase.sort_by_key(|a| a.0);
+
+        //a default lease chosen for the phase overrides the one from the command line
+        let default_lease = *default_leases.get(&(i as u64)).unwrap_or(&cli.base_lease());
+        if !grid.is_representable(default_lease) {
+            return Err(format!(
+                "Default lease {:x} of phase {} does not fit the lease counters!",
+                default_lease, i
+            ));
+        }
+        let default_lease = grid.counter_value(default_lease);
+        let mut header = vec![(default_lease, "default lease")];
+        match layout {
+            LeaseLayout::Single | LeaseLayout::Deduplicated | LeaseLayout::Packed => {
+                header.push((dual_lease_ref.1, "long lease value"));
+                header.push((
+                    discretize(dual_lease_ref.2, cli.discretize_width),
+                    "short lease probability",
+                ));
+            }
+            LeaseLayout::PerEntry => {
+                header.push((0, "unused"));
+                header.push((0, "unused"));
+            }
+        }
+        header.push((phase_leases.len() as u64, "num of references in phase"));
+        match layout {
+            LeaseLayout::Single | LeaseLayout::Deduplicated | LeaseLayout::Packed => {
+                header.push((dual_lease_ref.0 >> 2, "dual lease ref (word address)"))
+            }
+            LeaseLayout::PerEntry => header.push((0, "unused")),
+        }
+        if layout == LeaseLayout::Packed {
+            header.push((table_offset, "table offset (words)"));
+            table_offset += layout.num_columns() * phase_leases.len() as u64;
+        }
+        header.resize(16, (0, "unused"));
+
+        //pad every column out to the full table, unless tables are packed
+        let table_len = match layout {
+            LeaseLayout::Packed => lease_phase.len(),
+            _ => cli.llt_size as usize,
+        };
+        let column = |field: fn(&(u64, LeaseData)) -> u64| -> Vec<u64> {
+            let mut values: Vec<u64> = lease_phase.iter().map(field).collect();
+            values.resize(table_len, 0);
+            values
+        };
+        let mut columns = vec![
+            ("reference address", column(|lease| lease.0)),
+            ("lease0 value", column(|lease| lease.1.0)),
+        ];
+        if layout == LeaseLayout::PerEntry {
+            columns.push(("lease1 value", column(|lease| lease.1.1)));
+            let width = cli.discretize_width;
+            let mut probabilities: Vec<u64> = lease_phase
+                .iter()
+                .map(|lease| discretize(lease.1.2, width))
+                .collect();
+            probabilities.resize(cli.llt_size as usize, 0);
+            columns.push(("lease0 probability", probabilities));
+        }
+        image.push(PhaseImage { header, columns });
+    }
+
+    //the distinct tables and the phase map have to fit the memory together
+    if layout == LeaseLayout::Deduplicated {
+        let (_, tables) = deduplicate_tables(&image);
+        let words = phase_map_words(image.len() as u64)
+            + tables.len() as u64 * (layout.num_columns() * cli.llt_size + 16);
+        if words > cli.mem_size / 4 {
+            return Err(format!(
+                "{} phases with {} distinct lease tables cannot fit in specified {} byte memory",
+                image.len(),
+                tables.len(),
+                cli.mem_size
+            ));
+        }
+    }
+    if layout == LeaseLayout::Packed {
+        let num_entries = phase_lease_arr
+            .values()
+            .map(|leases| leases.len() as u64)
+            .sum();
+        if packed_words(image.len() as u64, num_entries) > cli.mem_size / 4 {
+            return Err(format!(
+                "{} phases with {} lease table entries cannot fit in specified {} byte memory",
+                image.len(),
+                num_entries,
+                cli.mem_size
+            ));
+        }
+    }
+    Ok(image)
+}
+
+/// The table index of every phase, and the distinct tables in order of first use.
+pub fn deduplicate_tables(image: &[PhaseImage]) -> (Vec<u64>, Vec<&PhaseImage>) {
+    let mut phase_map = Vec::new();
+    let mut tables: Vec<&PhaseImage> = Vec::new();
+    for phase_image in image.iter() {
+        match tables.iter().position(|table| *table == phase_image) {
+            Some(table) => phase_map.push(table as u64),
+            None => {
+                phase_map.push(tables.len() as u64);
+                tables.push(phase_image);
+            }
+        }
+    }
+    (phase_map, tables)
+}
+
+//commented words of the image, like a phase header
+type ImageWords = Vec<(u64, &'static str)>;
+
+// words of the deduplicated layout's phase map block
+fn phase_map_block(phase_map: &[u64], num_tables: usize) -> ImageWords {
+    let mut block = vec![
+        (phase_map.len() as u64, "num of phases"),
+        (num_tables as u64, "num of tables"),
+    ];
+    block.extend(phase_map.iter().map(|&table| (table, "table of phase")));
+    block.resize(
+        phase_map_words(phase_map.len() as u64) as usize,
+        (0, "unused"),
+    );
+    block
+}
+
+// a run of the image under one comment: commented words, then whole columns
+struct ImageBlock<'a> {
+    title: String,
+    words: ImageWords,
+    columns: Vec<(&'static str, &'a [u64])>,
+}
+
+impl ImageBlock<'_> {
+    fn values(&self) -> impl Iterator<Item = u64> + '_ {
+        let words = self.words.iter().map(|(value, _)| *value);
+        words.chain(
+            self.columns
+                .iter()
+                .flat_map(|(_, values)| values.iter().cloned()),
+        )
+    }
+}
+
+// a phase's header and columns, or only one of them
+fn table_block(
+    title: String,
+    phase_image: &PhaseImage,
+    header: bool,
+    columns: bool,
+) -> ImageBlock<'_> {
+    ImageBlock {
+        title,
+        words: match header {
+            true => phase_image.header.clone(),
+            false => Vec::new(),
+        },
+        columns: match columns {
+            true => phase_image
+                .columns
+                .iter()
+                .map(|(field, values)| (*field, values.as_slice()))
+                .collect(),
+            false => Vec::new(),
+        },
+    }
+}
+
+// the phase map block, if the layout has one, and the blocks of the tables in memory order
+fn image_blocks(
+    image: &[PhaseImage],
+    layout: LeaseLayout,
+) -> (Option<ImageWords>, Vec<ImageBlock<'_>>) {
+    match layout {
+        LeaseLayout::Deduplicated => {
+            let (phase_map, tables) = deduplicate_tables(image);
+            let blocks = tables
+                .iter()
+                .enumerate()
+                .map(|(i, table)| table_block(format!("table {}", i), table, true, true))
+                .collect();
+            (Some(phase_map_block(&phase_map, tables.len())), blocks)
+        }
+        //every header, then every table
+        LeaseLayout::Packed => {
+            let headers = image.iter().enumerate().map(|(i, phase_image)| {
+                table_block(format!("phase {}", i), phase_image, true, false)
+            });
+            let tables = image.iter().enumerate().map(|(i, phase_image)| {
+                table_block(format!("phase {} table", i), phase_image, false, true)
+            });
+            (None, headers.chain(tables).collect())
+        }
+        _ => (
+            None,
+            image
+                .iter()
+                .enumerate()
+                .map(|(i, phase_image)| {
+                    table_block(format!("phase {}", i), phase_image, true, true)
+                })
+                .collect(),
+        ),
     }
+}
+
+// every word of the image in memory order
+fn image_words(image: &[PhaseImage], layout: LeaseLayout) -> Vec<u64> {
+    let (phase_map, blocks) = image_blocks(image, layout);
+    let mut words: Vec<u64> = phase_map
+        .unwrap_or_default()
+        .iter()
+        .map(|(value, _)| *value)
+        .collect();
+    for block in blocks.iter() {
+        words.extend(block.values());
+    }
+    words
+}
+
+// function for generating c-files
+pub fn gen_lease_c_file(
+    lease_vector: Vec<(u64, u64, u64, u64, f64)>,
+    default_leases: &BTreeMap<u64, u64>,
+    cli: &Cli,
+    max_num_scopes: u64,
+    output_file: String,
+) -> Result<(), String> {
+    let image = build_lease_image(lease_vector, default_leases, cli, max_num_scopes)?;
+    write_lease_c_file(&image, cli, output_file);
+    Ok(())
+}
 
+pub fn write_lease_c_file(image: &[PhaseImage], cli: &Cli, output_file: String) {
     //write header
     let mut file = std::fs::File::create(output_file).expect("create failed");
     file.write_all("#include \"stdint.h\"\n\n".as_bytes())
@@ -833,96 +1193,50 @@ pub fn gen_lease_c_file(
             cli.mem_size / 4)
             .as_bytes())
         .expect("write failed");
+    let (phase_map, blocks) = image_blocks(image, cli.lease_layout);
+    if let Some(phase_map) = phase_map {
+        file.write_all("// phase map\n".as_bytes())
+            .expect("write failed");
+        for (value, comment) in phase_map.iter() {
+            file.write_all(format!("\t0x{:08x},\t// {}\n", value, comment).as_bytes())
+                .expect("write failed");
+        }
+    }
     file.write_all("// lease header\n".as_bytes())
         .expect("write failed");
-    let mut phase_index: u64 = 0; //len returns usize which can't directly substituted as u64
-    for i in 0..phase_lease_arr.len() {
-        let phase_leases = phase_lease_arr.get(&phase_index).unwrap();
-        phase_index += 1; //increment to next phase
-        file.write_all(format!("// phase {}\n", i).as_bytes())
+    //the last column word of the image ends without a delimiter
+    let num_values: usize = blocks
+        .iter()
+        .flat_map(|block| block.columns.iter())
+        .map(|(_, values)| values.len())
+        .sum();
+    let mut written = 0;
+    for block in blocks.iter() {
+        file.write_all(format!("// {}\n", block.title).as_bytes())
             .expect("write failed");
-
-        let mut dual_lease_ref = (0, 0, 1.0);
-        let mut lease_phase: Vec<(u64, u64)> = Vec::new();
-        let dual_lease_found = false;
-        for (lease_ref, lease_data) in phase_leases.iter() {
-            //convert hashmap of leases for phase to vector
-            lease_phase.push((*lease_ref, lease_data.0));
-            //get dual lease if it exists;
-            if lease_data.3 && !dual_lease_found {
-                dual_lease_ref = (*lease_ref, lease_data.1, lease_data.2);
-            }
-        }
-        lease_phase.sort_by_key(|a| a.0);
         //output config
-        for j in 0..16 {
-            if j == 0 {
-                file.write_all(
-                    format!("\t0x{:08x},\t// default lease\n", default_lease).as_bytes(),
-                )
-                .expect("write failed");
-            } else if j == 1 {
-                file.write_all(
-                    format!("\t0x{:08x},\t// long lease value\n", dual_lease_ref.1).as_bytes(),
-                )
+        for (value, comment) in block.words.iter() {
+            file.write_all(format!("\t0x{:08x},\t// {}\n", value, comment).as_bytes())
                 .expect("write failed");
-            } else if j == 2 {
-                file.write_all(
-                    format!(
-                        "\t0x{:08x},\t// short lease probability\n",
-                        discretize(dual_lease_ref.2, cli.discretize_width)
-                    )
-                    .as_bytes(),
-                )
-                .expect("write failed");
-            } else if j == 3 {
-                file.write_all(
-                    format!(
-                        "\t0x{:08x},\t// num of references in phase\n",
-                        phase_leases.len()
-                    )
-                    .as_bytes(),
-                )
-                .expect("write failed");
-            } else if j == 4 {
-                file.write_all(
-                    format!(
-                        "\t0x{:08x},\t// dual lease ref (word address)\n",
-                        dual_lease_ref.0 >> 2
-                    )
-                    .as_bytes(),
-                )
-                .expect("write failed");
-            } else {
-                file.write_all(format!("\t0x{:08x},\t // unused\n", 0).as_bytes())
-                    .expect("write failed");
-            }
         }
-        let field_list = ["reference address", "lease0 value"];
 
         // loop through lease fields
-        for k in 0..2 {
-            file.write_all(format!("\t//{}\n\t", field_list[k]).as_bytes())
+        for (field, values) in block.columns.iter() {
+            if values.is_empty() {
+                continue;
+            }
+            file.write_all(format!("\t//{}\n\t", field).as_bytes())
                 .expect("write failed");
 
-            for j in 0..cli.llt_size {
-                if j < phase_leases.len().try_into().unwrap() {
-                    if k == 0 {
-                        file.write_all(format!("0x{:08x}", lease_phase[j as usize].0).as_bytes())
-                            .expect("write failed");
-                    } else {
-                        file.write_all(format!("0x{:08x}", lease_phase[j as usize].1).as_bytes())
-                            .expect("write failed");
-                    }
-                } else {
-                    file.write_all(format!("0x{:08x}", 0).as_bytes())
-                        .expect("write failed");
-                }
+            for (j, value) in values.iter().enumerate() {
+                file.write_all(format!("0x{:08x}", value).as_bytes())
+                    .expect("write failed");
+                written += 1;
                 //print delimiter
-                if j + 1 == cli.llt_size && k == 1 && i + 1 == phase_lease_arr.len() {
+                if written == num_values {
                     file.write_all("\n".to_string().as_bytes())
                         .expect("write failed");
-                } else if j + 1 == cli.llt_size {
+                } else if j + 1 == values.len() {
                     file.write_all(",\n".to_string().as_bytes())
                         .expect("write failed");
                 } else if ((j + 1) % 10) == 0 {
@@ -935,22 +1249,341 @@ pub fn gen_lease_c_file(
             }
         }
     }
-    file.write_all(format!("}};").as_bytes())
-        .expect("write failed");
+    file.write_all("};".as_bytes()).expect("write failed");
+}
+
+// writes the lease image of the chosen layout as lease.c, lease.bin and lease.hex, or says why
+// the leases do not fit it. Leases of merged phases are written for every phase merged into them
+pub fn dump_lease_image(
+    lease_results: &LeaseResults,
+    cli: &Cli,
+    max_num_scopes: u64,
+    phase_mapping: Option<&BTreeMap<u64, u64>>,
+    output_dir: &str,
+) -> Result<(), String> {
+    let mut leases = lease_vector(lease_results, cli.discretize_width);
+    let mut default_leases = lease_results.phase_default_leases.clone();
+    //leases assigned on merged phases: every phase gets a copy of its merged phase's table,
+    //which the deduplicated layout stores once behind the phase map
+    if let Some(mapping) = phase_mapping {
+        leases = mapping
+            .iter()
+            .flat_map(|(&phase, &merged)| {
+                leases.iter().filter(move |lease| lease.0 == merged).map(
+                    move |&(_, address, short, long, percentage)| {
+                        (phase, address, short, long, percentage)
+                    },
+                )
+            })
+            .collect();
+        default_leases = mapping
+            .iter()
+            .filter_map(|(&phase, merged)| Some((phase, *default_leases.get(merged)?)))
+            .collect();
+    }
+    let image = build_lease_image(leases, &default_leases, cli, max_num_scopes)?;
+    write_lease_c_file(&image, cli, format!("{}/lease.c", output_dir));
+    write_lease_bin_file(
+        &image,
+        cli.lease_layout,
+        format!("{}/lease.bin", output_dir),
+    );
+    write_lease_hex_file(
+        &image,
+        cli.lease_layout,
+        format!("{}/lease.hex", output_dir),
+    );
+    Ok(())
+}
+
+// raw little-endian 32-bit words, for loading the lease image without a C toolchain
+pub fn write_lease_bin_file(image: &[PhaseImage], layout: LeaseLayout, output_file: String) {
+    let mut file = std::fs::File::create(output_file).expect("create failed");
+    for value in image_words(image, layout) {
+        file.write_all(&(value as u32).to_le_bytes())
+            .expect("write failed");
+    }
+}
+
+// one 32-bit hex word per line, for $readmemh
+pub fn write_lease_hex_file(image: &[PhaseImage], layout: LeaseLayout, output_file: String) {
+    let mut file = std::fs::File::create(output_file).expect("create failed");
+    for value in image_words(image, layout) {
+        file.write_all(format!("{:08x}\n", value).as_bytes())
+            .expect("write failed");
+    }
 }
 
 pub fn discretize(percentage: f64, discretization: u64) -> u64 {
     (percentage * ((2 << (discretization - 1)) as f64) - 1.0).round() as u64
 }
 
+/// Inverse of `discretize`: the short lease probability the hardware applies for a stored value.
+pub fn undiscretize(value: u64, discretization: u64) -> f64 {
+    (value + 1) as f64 / ((2 << (discretization - 1)) as f64)
+}
+
+/// Rounds a short lease probability to the nearest one the hardware can represent.
+pub fn hardware_percentage(percentage: f64, discretization: u64) -> f64 {
+    undiscretize(discretize(percentage, discretization), discretization)
+}
+
+/// Writes, per pruning strategy, the predicted sampled hits left after fitting the lease tables
+/// to the layout and how many predicted hits pruning lost.
+pub fn dump_prune_report(
+    lease_results: &LeaseResults,
+    ri_hists: &RIHists,
+    cli: &Cli,
+    output_dir: &str,
+) {
+    let output_file = format!("{}/prune_report.txt", output_dir);
+    let mut file = File::create(output_file).expect("create failed");
+    let unpruned_hits = lease_results.predicted_hits(cli.discretize_width);
+    for strategy in PruneStrategy::ALL {
+        let mut pruned = lease_results.clone();
+        pruned.fit_lease_tables(ri_hists, cli, strategy);
+        let hits = pruned.predicted_hits(cli.discretize_width);
+        file.write_all(
+            format!(
+                "{:?}, {}, {}\n",
+                strategy,
+                hits,
+                unpruned_hits.saturating_sub(hits)
+            )[..]
+                .as_bytes(),
+        )
+        .expect("write failed");
+    }
+}
+
+/// Writes the detected phases: id, samples, distinct references of the opening window, and
+/// the (first, last) sample times of each stretch of the trace in the phase.
+pub fn dump_phase_report(phases: &[DetectedPhase], output_dir: &str) {
+    let output_file = format!("{}/detected_phases.txt", output_dir);
+    let mut file = File::create(output_file).expect("create failed");
+    for phase in phases {
+        file.write_all(
+            format!(
+                "{}, {}, {}, {:?}\n",
+                phase.phase,
+                phase.samples,
+                phase.signature.len(),
+                phase.segments
+            )[..]
+                .as_bytes(),
+        )
+        .expect("write failed");
+    }
+}
+
+/// Writes the predicted sampled hits of the greedy and the exact assignment, before lease table
+/// pruning, and how far the greedy falls short.
+/// The optimum is labelled a lower bound when dual leases can compete for a budget.
+pub fn dump_optimality_gap(
+    greedy_results: &LeaseResults,
+    optimal_results: &LeaseResults,
+    cshel: bool,
+    cli: &Cli,
+    output_dir: &str,
+) {
+    let output_file = format!("{}/optimality_gap.txt", output_dir);
+    let mut file = File::create(output_file).expect("create failed");
+    let greedy_hits = greedy_results.predicted_hits(cli.discretize_width);
+    let optimal_hits = optimal_results.predicted_hits(cli.discretize_width);
+    let gap = optimal_hits.saturating_sub(greedy_hits);
+    //the optimal search picks dual leases greedily after the single leases, which is only exact
+    //with at most one dual lease per phase under SHEL
+    let bound = match cshel || cli.max_dual_leases() > 1 {
+        true => " (lower bound)",
+        false => "",
+    };
+    file.write_all(
+        format!(
+            "greedy hits: {}\noptimal hits{}: {}\ngap{}: {} ({})\n",
+            greedy_hits,
+            bound,
+            optimal_hits,
+            bound,
+            gap,
+            gap as f64 / optimal_hits.max(1) as f64
+        )[..]
+            .as_bytes(),
+    )
+    .expect("write failed");
+}
+
+/// Writes, per (phase, set), the cache budget the capacity schedule allows, the occupancy the
+/// assigned leases predict and their ratio.
+pub fn dump_capacity_report(
+    lease_results: &LeaseResults,
+    cshel: bool,
+    cli: &Cli,
+    context: &LeaseOperationContext,
+    output_file: &str,
+) {
+    let mut file = File::create(output_file).expect("create failed");
+    file.write_all("phase\tset\tbudget\tused\tutilization\n".as_bytes())
+        .expect("write failed");
+    let num_sets = context.set_mask as u64 + 1;
+    let budget = budget_per_cell(cli, context);
+    let used = lease_results.cost_per_cell(cshel, cli, context);
+    let cells = context
+        .samples_per_phase
+        .keys()
+        .flat_map(|&phase| (0..num_sets).map(move |set| (phase, set)));
+    for (cell, (phase, set)) in cells.enumerate() {
+        file.write_all(
+            format!(
+                "{}\t{}\t{}\t{:.0}\t{:.4}\n",
+                phase,
+                set,
+                budget[cell],
+                used[cell],
+                used[cell] / budget[cell].max(1) as f64
+            )
+            .as_bytes(),
+        )
+        .expect("write failed");
+    }
+}
+
+/// Writes which merged phase every phase went to, and the predicted misses merging costs: the
+/// sampled hits of the leases assigned on the original and on the merged phases, and the hits
+/// lost as extra misses, also relative to the unmerged hits.
+pub fn dump_phase_merge_report(
+    mapping: &BTreeMap<u64, u64>,
+    unconstrained_results: &LeaseResults,
+    merged_results: &LeaseResults,
+    cli: &Cli,
+    output_dir: &str,
+) {
+    let output_file = format!("{}/phase_merge_report.txt", output_dir);
+    let mut file = File::create(output_file).expect("create failed");
+    for (phase, merged_phase) in mapping.iter() {
+        file.write_all(format!("{} -> {}\n", phase, merged_phase)[..].as_bytes())
+            .expect("write failed");
+    }
+    let unconstrained_hits = unconstrained_results.predicted_hits(cli.discretize_width);
+    let merged_hits = merged_results.predicted_hits(cli.discretize_width);
+    let extra_misses = unconstrained_hits.saturating_sub(merged_hits);
+    file.write_all(
+        format!(
+            "unconstrained hits: {}\nmerged hits: {}\nextra misses: {} ({})\n",
+            unconstrained_hits,
+            merged_hits,
+            extra_misses,
+            extra_misses as f64 / unconstrained_hits.max(1) as f64
+        )[..]
+            .as_bytes(),
+    )
+    .expect("write failed");
+}
+
+/// Writes how much binning the RI histograms shrank them and what it cost: the predicted miss
+/// ratio of the leases assigned on the exact and on the binned histograms, both evaluated on the
+/// exact histograms, and the time each assignment took.
+pub fn dump_binning_report(
+    exact_results: &LeaseResults,
+    binned_results: &LeaseResults,
+    exact_hists: &RIHists,
+    binned_hists: &RIHists,
+    (exact_time, binned_time): (std::time::Duration, std::time::Duration),
+    cli: &Cli,
+    output_dir: &str,
+) {
+    let output_file = format!("{}/binning_report.txt", output_dir);
+    let mut file = File::create(output_file).expect("create failed");
+    let samples = exact_hists
+        .ri_hists
+        .values()
+        .flat_map(|ri_hist| ri_hist.values())
+        .map(|(count, _)| count)
+        .sum::<u64>()
+        .max(1) as f64;
+    let miss_ratio = |results: &LeaseResults| {
+        1.0 - results.predicted_hits(cli.discretize_width) as f64 / samples
+    };
+    let (exact_ratio, binned_ratio) = (miss_ratio(exact_results), miss_ratio(binned_results));
+    file.write_all(
+        format!(
+            "binning: {:?}, {} bins\nhistogram entries: {} exact, {} binned\nmax relative RI error: {}\n\
+             predicted miss ratio: {} exact, {} binned ({:+})\nassignment time: {:?} exact, {:?} binned\n",
+            cli.ri_binning,
+            cli.ri_bins,
+            crate::binning::num_entries(exact_hists),
+            crate::binning::num_entries(binned_hists),
+            crate::binning::max_relative_error(exact_hists, binned_hists),
+            exact_ratio,
+            binned_ratio,
+            binned_ratio - exact_ratio,
+            exact_time,
+            binned_time
+        )[..]
+            .as_bytes(),
+    )
+    .expect("write failed");
+}
+
+/// Writes every reference's lease distribution, one line per reference: phase, address, then a
+/// `lease:probability` pair per lease, with probabilities discretized like the short lease
+/// probability in `leases.txt`.
+pub fn dump_lease_distributions(
+    lease_results: &LeaseResults,
+    output_dir: &str,
+    discretize_width: u64,
+) {
+    let output_file = format!("{}/lease_distributions.txt", output_dir);
+    let mut file = File::create(output_file).expect("create failed");
+    for &ref_id in lease_results.leases.keys() {
+        let weights: Vec<String> = lease_results
+            .lease_weights(ref_id, None)
+            .iter()
+            .map(|(lease, weight)| {
+                format!("{:x}:{:x}", lease, discretize(*weight, discretize_width))
+            })
+            .collect();
+        file.write_all(
+            format!(
+                "{:x}, {:x}, {}\n",
+                ref_id.phase,
+                ref_id.reference,
+                weights.join(", ")
+            )[..]
+                .as_bytes(),
+        )
+        .expect("write failed");
+    }
+}
+
+/// Writes, per dual lease, the alpha the allocator wanted, the alpha it assigned after snapping to
+/// the hardware grid, and the average occupancy (in blocks) that snapping gave up.
+pub fn dump_alpha_quantization(lease_results: &LeaseResults, output_dir: &str) {
+    let output_file = format!("{}/alpha_quantization.txt", output_dir);
+    let mut file = File::create(output_file).expect("create failed");
+    let mut total_shift = 0.0;
+    for (ref_id, quantization) in lease_results.alpha_quantization.iter() {
+        file.write_all(
+            format!(
+                "{:x}, {:x}, {}, {}, {}\n",
+                ref_id.phase,
+                ref_id.reference,
+                quantization.continuous,
+                quantization.quantized,
+                quantization.occupancy_shift
+            )[..]
+                .as_bytes(),
+        )
+        .expect("write failed");
+        total_shift += quantization.occupancy_shift;
+    }
+    file.write_all(format!("total occupancy shift: {}\n", total_shift)[..].as_bytes())
+        .expect("write failed");
+}
+
 pub mod debug {
     pub fn print_ri_hists(rihists: &super::super::lease_gen::RIHists) {
         for (ref_id, ref_ri_hist) in &rihists.ri_hists {
-            println!(
-                "({},0x{:x}):",
-                (ref_id & 0xFF000000) >> 24,
-                ref_id & 0x00FFFFFF
-            );
+            println!("({},0x{:x}):", ref_id.phase, ref_id.reference);
 
             let mut keys: Vec<_> = ref_ri_hist.keys().collect();
             keys.sort();
@@ -975,12 +1608,12 @@ pub mod debug {
             // }
         }
     }
-    //<u64,HashMap<u64,HashMap<u64,u64>>>
+    //<u64,BTreeMap<u64,BTreeMap<u64,u64>>>
     pub fn print_binned_hists(binned_ris: &super::super::lease_gen::BinnedRIs) {
         for (bin, ref_ri_hist) in &binned_ris.bin_ri_distribution {
             println!("Bin:{}", bin);
             for (ref_id, ri_hist) in ref_ri_hist {
-                println!(" | ref 0x{:x}:", ref_id);
+                println!(" | ref 0x{:x}:", ref_id.reference);
                 for (ri, count) in ri_hist {
                     println!(" | | ri 0x{:x}: count {}", ri, count);
                 }
@@ -1,18 +1,71 @@
 use crate::cli::Cli;
+use crate::cost_index::CostIndex;
+use crate::helpers::{alpha_to_f64, fixed_alpha, is_meaningful_alpha};
 use core::{cmp::Ordering, panic};
-use std::collections::{BinaryHeap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap};
+use std::fmt;
+
+/// Identifies a reference within a phase, optionally narrowed to a single cache set.
+///
+/// Replaces the packed `u64` encoding (`phase << 24 | reference`, set in bits 32+) so that
+/// neither the phase count nor the reference id width is capped by the packing.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RefKey {
+    pub phase: u64,
+    pub reference: u64,
+    pub set: u64,
+}
+
+impl RefKey {
+    pub fn new(phase: u64, reference: u64, set: u64) -> Self {
+        Self {
+            phase,
+            reference,
+            set,
+        }
+    }
+
+    /// Decodes the legacy 32-bit `phase_id_ref` word (phase in the top byte, reference in the low 24 bits).
+    pub fn from_phase_id_ref(phase_id_ref: u32, set: u64) -> Self {
+        Self {
+            phase: ((phase_id_ref & 0xFF000000) >> 24) as u64,
+            reference: (phase_id_ref & 0x00FFFFFF) as u64,
+            set,
+        }
+    }
+
+    /// The key with the set stripped, as used for per-reference lease tables.
+    pub fn phase_ref(&self) -> Self {
+        Self { set: 0, ..*self }
+    }
+
+    pub fn with_set(&self, set: u64) -> Self {
+        Self { set, ..*self }
+    }
+}
+
+impl fmt::Display for RefKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({},{:x})", self.phase, self.reference)
+    }
+}
+
+/// `{phase_id: (head_cost, tail_cost)}`
+pub type PhaseCosts = BTreeMap<u64, (u64, u64)>;
+/// `{ri: (count, {phase_id: (head_cost, tail_cost)})}`
+pub type RIHist = BTreeMap<u64, (u64, PhaseCosts)>;
 
 #[derive(Debug, Clone)]
 pub struct BinFreqs {
-    pub bin_freqs: HashMap<u64, HashMap<u64, u64>>,
+    pub bin_freqs: BTreeMap<u64, BTreeMap<RefKey, u64>>,
 }
 #[derive(Debug, Clone)]
 pub struct BinnedRIs {
-    pub bin_ri_distribution: HashMap<u64, HashMap<u64, HashMap<u64, u64>>>,
+    pub bin_ri_distribution: BTreeMap<u64, BTreeMap<RefKey, BTreeMap<u64, u64>>>,
 }
 
 impl BinFreqs {
-    pub fn new(bin_freqs_input: HashMap<u64, HashMap<u64, u64>>) -> Self {
+    pub fn new(bin_freqs_input: BTreeMap<u64, BTreeMap<RefKey, u64>>) -> Self {
         Self {
             bin_freqs: bin_freqs_input,
         }
@@ -20,7 +73,7 @@ impl BinFreqs {
 }
 
 impl BinnedRIs {
-    pub fn new(bin_ri_input: HashMap<u64, HashMap<u64, HashMap<u64, u64>>>) -> Self {
+    pub fn new(bin_ri_input: BTreeMap<u64, BTreeMap<RefKey, BTreeMap<u64, u64>>>) -> Self {
         Self {
             bin_ri_distribution: bin_ri_input,
         }
@@ -28,31 +81,29 @@ impl BinnedRIs {
 }
 
 pub struct RIHists {
-    pub ri_hists: HashMap<u64, HashMap<u64, (u64, HashMap<u64, (u64, u64)>)>>,
+    pub ri_hists: BTreeMap<RefKey, RIHist>,
 }
 
 impl RIHists {
-    pub fn new(
-        ri_hists_input: HashMap<u64, HashMap<u64, (u64, HashMap<u64, (u64, u64)>)>>,
-    ) -> Self {
+    pub fn new(ri_hists_input: BTreeMap<RefKey, RIHist>) -> Self {
         Self {
             ri_hists: ri_hists_input,
         }
     }
 
-    pub fn get_ref_hist(&self, ref_id: u64) -> &HashMap<u64, (u64, HashMap<u64, (u64, u64)>)> {
+    pub fn get_ref_hist(&self, ref_id: RefKey) -> &RIHist {
         self.ri_hists.get(&ref_id).unwrap()
     }
 
-    pub fn get_ref_ri_count(&self, ref_id: u64, ri: u64) -> u64 {
+    pub fn get_ref_ri_count(&self, ref_id: RefKey, ri: u64) -> u64 {
         self.ri_hists.get(&ref_id).unwrap().get(&ri).unwrap().0
     }
 
-    pub fn get_ref_ri_cost(&self, ref_id: u64, ri: u64) -> &HashMap<u64, (u64, u64)> {
+    pub fn get_ref_ri_cost(&self, ref_id: RefKey, ri: u64) -> &PhaseCosts {
         &self.ri_hists.get(&ref_id).unwrap().get(&ri).unwrap().1
     }
 
-    pub fn get_ref_ri_phase_cost(&self, ref_id: u64, ri: u64, phase: u64) -> (u64, u64) {
+    pub fn get_ref_ri_phase_cost(&self, ref_id: RefKey, ri: u64, phase: u64) -> (u64, u64) {
         *self
             .ri_hists
             .get(&ref_id)
@@ -70,48 +121,164 @@ pub struct PPUC {
     pub ppuc: f64,
     pub lease: u64,
     pub old_lease: u64,
-    pub ref_id: u64,
+    pub ref_id: RefKey,
     pub new_hits: u64,
 }
 impl PartialOrd for PPUC {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        // Some(self.cmp(other))
-        self.ppuc.partial_cmp(&other.ppuc)
+        Some(self.cmp(other))
     }
 }
+// The heap pops the highest ppuc first. Ties go to the lowest (phase, reference, set),
+// then the shortest lease, so the pop order never depends on insertion order.
 impl Ord for PPUC {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.ppuc.partial_cmp(&self.ppuc).unwrap()
+        self.ppuc
+            .total_cmp(&other.ppuc)
+            .then_with(|| other.ref_id.cmp(&self.ref_id))
+            .then_with(|| other.lease.cmp(&self.lease))
+            .then_with(|| other.old_lease.cmp(&self.old_lease))
     }
 }
 impl PartialEq for PPUC {
     fn eq(&self, other: &Self) -> bool {
-        other.ppuc.eq(&self.ppuc)
+        self.cmp(other) == Ordering::Equal
     }
 }
 impl Eq for PPUC {}
 
+/// Leases the hardware's lease counters can hold: multiples of `1 << granularity` accesses, up
+/// to `max_lease`. The default grid holds every lease.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct LeaseGrid {
+    pub max_lease: u64,
+    pub granularity: u64,
+}
+
+impl Default for LeaseGrid {
+    fn default() -> Self {
+        Self {
+            max_lease: u64::MAX,
+            granularity: 0,
+        }
+    }
+}
+
+impl LeaseGrid {
+    /// Rounds a lease up to the counter granularity and clamps it to the largest lease that fits.
+    /// A lease of 0 (bypass) stays 0.
+    pub fn representable(&self, lease: u64) -> u64 {
+        let step = 1u64 << self.granularity;
+        let max_lease = self.max_lease / step * step;
+        lease.div_ceil(step).saturating_mul(step).min(max_lease)
+    }
+
+    pub fn is_representable(&self, lease: u64) -> bool {
+        self.representable(lease) == lease
+    }
+
+    /// Value a lease counter is loaded with for a lease.
+    pub fn counter_value(&self, lease: u64) -> u64 {
+        lease >> self.granularity
+    }
+}
+
+/// Room in the lease tables for entries, references with a lease other than the default: each
+/// phase's own table, or entries every phase draws on together when tables are packed into the
+/// lease memory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TableLimit {
+    PerPhase(u64),
+    Shared(u64),
+}
+
+impl TableLimit {
+    /// Whether `phase` can take no more entries, given the entries of every phase.
+    pub fn is_full(&self, entries: &BTreeMap<u64, u64>, phase: u64) -> bool {
+        match *self {
+            TableLimit::PerPhase(llt_size) => *entries.get(&phase).unwrap_or(&0) >= llt_size,
+            TableLimit::Shared(total) => entries.values().sum::<u64>() >= total,
+        }
+    }
+}
+
 pub struct LeaseOperationContext<'a> {
     pub ri_hists: &'a RIHists,
     pub sample_rate: u64,
-    pub samples_per_phase: &'a HashMap<u64, u64>,
+    pub samples_per_phase: &'a BTreeMap<u64, u64>,
     pub set_mask: u32,
     pub misses_from_first_access: usize,
     pub max_scopes: u64,
 }
 
+impl LeaseOperationContext<'_> {
+    /// Phases of the lease image: every phase up to the last one sampled.
+    pub fn num_phases(&self) -> u64 {
+        self.samples_per_phase
+            .keys()
+            .max()
+            .map_or(0, |phase| phase + 1)
+    }
+}
+
+/// The alpha the allocator wanted for a dual lease versus the one the hardware can represent.
+#[derive(Debug, Copy, Clone)]
+pub struct AlphaQuantization {
+    pub continuous: f64,
+    pub quantized: f64,
+    /// Average cache occupancy (in blocks) given up by rounding alpha down to the grid; with
+    /// PRL, that of the bin the rounding shifts the most.
+    pub occupancy_shift: f64,
+}
+
+/// How `prune_leases_to_fit_llt` ranks references when a phase has more leases than table slots.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PruneStrategy {
+    /// Keep the longest leases
+    #[default]
+    LeaseLength,
+    /// Keep the leases that add the most predicted hits
+    PredictedHits,
+    /// Keep the leases that add the most predicted hits per unit of cache cost
+    HitsPerCost,
+    /// Keep the most sampled references
+    SampleCount,
+    /// Keep the selection that maximizes predicted hits for the table size
+    Knapsack,
+}
+
+impl PruneStrategy {
+    pub const ALL: [PruneStrategy; 5] = [
+        PruneStrategy::LeaseLength,
+        PruneStrategy::PredictedHits,
+        PruneStrategy::HitsPerCost,
+        PruneStrategy::SampleCount,
+        PruneStrategy::Knapsack,
+    ];
+}
+
+#[derive(Clone)]
 pub struct LeaseResults {
-    pub leases: HashMap<u64, u64>,
-    pub dual_leases: HashMap<u64, (f64, u64)>,
-    pub lease_hits: HashMap<u64, HashMap<u64, u64>>,
+    pub leases: BTreeMap<RefKey, u64>,
+    pub dual_leases: BTreeMap<RefKey, (f64, u64)>,
+    pub lease_hits: BTreeMap<RefKey, BTreeMap<u64, u64>>,
     pub trace_length: u64,
+    pub alpha_quantization: BTreeMap<RefKey, AlphaQuantization>,
+    /// `{ref_id: [(lease, probability)]}` for references that mix more than two leases;
+    /// `leases` holds their shortest lease.
+    pub lease_distributions: BTreeMap<RefKey, Vec<(u64, f64)>>,
+    /// Lease of references without a table entry: 1, or 0 when they bypass the cache.
+    pub default_lease: u64,
+    /// `{phase: default lease}` chosen by `optimize_default_leases`; phases not in it use
+    /// `default_lease`.
+    pub phase_default_leases: BTreeMap<u64, u64>,
 }
 
 impl LeaseResults {
     pub fn new(
-        leases: HashMap<u64, u64>,
-        dual_leases: HashMap<u64, (f64, u64)>,
-        lease_hits: HashMap<u64, HashMap<u64, u64>>,
+        leases: BTreeMap<RefKey, u64>,
+        dual_leases: BTreeMap<RefKey, (f64, u64)>,
+        lease_hits: BTreeMap<RefKey, BTreeMap<u64, u64>>,
         trace_length: u64,
     ) -> Self {
         Self {
@@ -119,90 +286,147 @@ impl LeaseResults {
             dual_leases,
             lease_hits,
             trace_length,
+            alpha_quantization: BTreeMap::new(),
+            lease_distributions: BTreeMap::new(),
+            default_lease: 1,
+            phase_default_leases: BTreeMap::new(),
         }
     }
 
-    pub fn prune_leases_to_fit_llt(&mut self, ri_hists: &RIHists, llt_size: u64) {
-        let mut pruned_leases: HashMap<u64, u64> = HashMap::new();
-        let mut pruned_dual_leases: HashMap<u64, (f64, u64)> = HashMap::new();
-        let references_per_phase: HashMap<u64, u64> = get_num_leases_per_phase(&self.leases);
+    /// Prunes the lease tables to the layout: packed tables share the whole lease memory, by
+    /// marginal predicted hits, every other layout gives each phase `llt_size` entries.
+    pub fn fit_lease_tables(&mut self, ri_hists: &RIHists, cli: &Cli, strategy: PruneStrategy) {
+        match cli.lease_layout {
+            super::io::LeaseLayout::Packed => self.prune_leases_to_fit_memory(
+                ri_hists,
+                cli.mem_size,
+                strategy,
+                cli.max_dual_leases(),
+            ),
+            _ => self.prune_leases_to_fit_llt(
+                ri_hists,
+                cli.llt_size,
+                strategy,
+                cli.max_dual_leases(),
+            ),
+        }
+    }
 
-        // print self.leases
-        // if self.leases.is_empty() {
-        //     return;
-        // } else {
-        //     for (reference, lease) in self.leases.iter() {
-        //         if *lease != 1 {
-        //             println!("Reference: {:x} Lease: {:x}", reference, lease);
-        //         }
-        //     }
-        // }
+    pub fn prune_leases_to_fit_llt(
+        &mut self,
+        ri_hists: &RIHists,
+        llt_size: u64,
+        strategy: PruneStrategy,
+        max_dual_leases: u64,
+    ) {
+        self.prune_leases_to_table_sizes(ri_hists, |_| llt_size, strategy, max_dual_leases);
+    }
+
+    /// Prunes the lease tables of a packed layout, where phases share `mem_size` bytes instead of
+    /// each getting `llt_size` entries: a 16-word header per phase, then two words per entry.
+    /// The entries go to the references predicting the most hits over the default lease,
+    /// whatever their phase. The allocator already shares the entries out this way, so this
+    /// only trims what it could not.
+    pub fn prune_leases_to_fit_memory(
+        &mut self,
+        ri_hists: &RIHists,
+        mem_size: u64,
+        strategy: PruneStrategy,
+        max_dual_leases: u64,
+    ) {
+        let num_phases = self
+            .lease_hits
+            .keys()
+            .chain(self.leases.keys())
+            .map(|reference| reference.phase + 1)
+            .max()
+            .unwrap_or(0);
+        let total_entries = super::io::packed_max_entries(mem_size, num_phases);
+        let table_sizes = self.table_sizes_by_marginal_hits(total_entries);
+        self.prune_leases_to_table_sizes(
+            ri_hists,
+            |phase| *table_sizes.get(&phase).unwrap_or(&0),
+            strategy,
+            max_dual_leases,
+        );
+    }
+
+    /// Table length of every phase when `total_entries` entries are shared between phases, each
+    /// going to the reference it adds the most predicted hits to over the phase default lease.
+    pub fn table_sizes_by_marginal_hits(&self, total_entries: u64) -> BTreeMap<u64, u64> {
+        let mut gains: Vec<(f64, RefKey)> = self
+            .leases
+            .keys()
+            .map(|&reference| {
+                let gain = self.hits_over_default(reference, &self.lease_weights(reference, None));
+                (gain, reference)
+            })
+            .filter(|(gain, _)| *gain > 0.0)
+            .collect();
+        //stable sort: equal gains keep reference order
+        gains.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut table_sizes: BTreeMap<u64, u64> = BTreeMap::new();
+        for (_, reference) in gains.into_iter().take(total_entries as usize) {
+            *table_sizes.entry(reference.phase).or_insert(0) += 1;
+        }
+        table_sizes
+    }
+
+    fn prune_leases_to_table_sizes(
+        &mut self,
+        ri_hists: &RIHists,
+        table_size: impl Fn(u64) -> u64,
+        strategy: PruneStrategy,
+        max_dual_leases: u64,
+    ) {
+        let mut pruned_leases: BTreeMap<RefKey, u64> = BTreeMap::new();
+        let mut pruned_dual_leases: BTreeMap<RefKey, (f64, u64)> = BTreeMap::new();
+        let references_per_phase: BTreeMap<u64, u64> = get_num_leases_per_phase(&self.leases);
 
         for (phase_id, _lease_count) in references_per_phase.iter() {
+            if strategy == PruneStrategy::Knapsack {
+                for (reference, dual) in
+                    self.knapsack_entries(*phase_id, table_size(*phase_id), max_dual_leases)
+                {
+                    pruned_leases.insert(reference, *self.leases.get(&reference).unwrap());
+                    if dual {
+                        pruned_dual_leases
+                            .insert(reference, *self.dual_leases.get(&reference).unwrap());
+                    }
+                }
+                continue;
+            }
             //loop through phases
-            let mut importance_per_reference: HashMap<u64, u64> = HashMap::new();
+            let mut importance_per_reference: BTreeMap<RefKey, f64> = BTreeMap::new();
 
             //this is globally sorting leases by importance
             //need to be locally sorting them per phase
             for (reference, &_lease) in self.leases.iter() {
-                let reference_phase_id = (reference & 0xFF000000) >> 24;
                 //if this reference is not in the current phase, pass instead of inserting
-                if reference_phase_id != *phase_id {
+                if reference.phase != *phase_id {
                     continue;
                 }
-                // let ri_hist = ri_hists.get_ref_hist(*reference);
-                let mut count = 0;
-                //need to sum over this
-                // for (_ri, count_cost_tuple) in ri_hist {
-                //     count += count_cost_tuple.0;
-                //     // Summing ri counts for this reference
-                //     // if _ri > _lease {
-                //     //     break;
-                //     //     // counting only hits for ri <= lease
-                //     // }
-                // }
-
-                // reset count to be its lease length
-                count = _lease;
-                importance_per_reference.entry(*reference).or_insert(count);
+                let importance = self.importance(*reference, ri_hists, strategy);
+                importance_per_reference
+                    .entry(*reference)
+                    .or_insert(importance);
             }
 
             let mut importance_vec: Vec<_> = importance_per_reference.iter().collect();
-            importance_vec.sort_by(|a, b| a.1.cmp(b.1).reverse());
-
-            // print importance_vec
-            // for (reference, importance) in &importance_vec {
-            //     if **importance == 1 {
-            //         continue;
-            //     }
-            //     println!("Reference: {:x} Importance: {:x}", reference, importance);
-            // }
-
-            // for i in 0..llt_size {
-            //     if i == importance_vec.len() as u64 {
-            //         break;
-            //     }
-
-            //     //add the top llt_size most important leases to the pruned vector
-            //     let reference_id = importance_vec[i as usize].0;
-            //     pruned_leases
-            //         .entry(*reference_id)
-            //         .or_insert(*self.leases.get(reference_id).unwrap());
-
-            //     if self.dual_leases.contains_key(reference_id) {
-            //         pruned_dual_leases
-            //             .entry(*reference_id)
-            //             .or_insert(*self.dual_leases.get(reference_id).unwrap());
-            //     }
-            //     //println!("Inserted successfully");
-            // }
+            //stable sort: equal importance keeps reference order
+            importance_vec.sort_by(|a, b| b.1.total_cmp(a.1));
 
+            let llt_size = table_size(*phase_id);
             let mut count = 0;
+            let mut num_duals = 0;
             let mut idx = 0;
             while count < llt_size && idx < importance_vec.len() {
                 let reference_id = importance_vec[idx].0;
                 let lease_value = *self.leases.get(reference_id).unwrap();
-                if self.dual_leases.contains_key(reference_id) {
+                //past the phase's dual lease slots, a dual lease keeps only its short lease
+                if self.dual_leases.contains_key(reference_id) && num_duals < max_dual_leases {
+                    num_duals += 1;
                     // Always add dual leases
                     if !pruned_leases.contains_key(reference_id) {
                         pruned_leases.insert(*reference_id, lease_value);
@@ -214,7 +438,10 @@ impl LeaseResults {
                     }
                 } else {
                     // Only skip lease_value == 1 if llt_size < importance_vec.len()
-                    if lease_value == 1 && llt_size < importance_vec.len() as u64 {
+                    if lease_value == self.default_lease
+                        && !self.lease_distributions.contains_key(reference_id)
+                        && llt_size < importance_vec.len() as u64
+                    {
                         // skip
                     } else {
                         if !pruned_leases.contains_key(reference_id) {
@@ -229,28 +456,321 @@ impl LeaseResults {
             }
         }
         // (pruned_leases, pruned_dual_leases)
+        self.lease_distributions
+            .retain(|reference, _| pruned_leases.contains_key(reference));
         self.leases = pruned_leases;
         self.dual_leases = pruned_dual_leases;
     }
+
+    //predicted hits a reference gains with the given leases over its phase's default lease
+    fn hits_over_default(&self, reference: RefKey, weights: &[(u64, f64)]) -> f64 {
+        let default_weights = [(self.phase_default_lease(reference.phase), 1.0)];
+        self.ref_hits(reference, weights) - self.ref_hits(reference, &default_weights)
+    }
+
+    //the table entries of a phase that gain the most predicted hits over the default lease, as
+    //(reference, keeps its dual lease). An exact 0/1 knapsack: every entry takes one of the
+    //table_size slots and a dual lease also one of the max_dual_leases dual slots, while a dual
+    //lease without a dual slot can still take an entry with its short lease alone
+    fn knapsack_entries(
+        &self,
+        phase: u64,
+        table_size: u64,
+        max_dual_leases: u64,
+    ) -> Vec<(RefKey, bool)> {
+        //(reference, gain with its short lease only, gain with its dual lease if it has one)
+        let items: Vec<(RefKey, f64, Option<f64>)> = self
+            .leases
+            .keys()
+            .filter(|reference| reference.phase == phase)
+            .map(|&reference| {
+                let weights = self.lease_weights(reference, None);
+                match self.dual_leases.contains_key(&reference) {
+                    true => (
+                        reference,
+                        self.hits_over_default(reference, &[(weights[0].0, 1.0)]),
+                        Some(self.hits_over_default(reference, &weights)),
+                    ),
+                    false => (reference, self.hits_over_default(reference, &weights), None),
+                }
+            })
+            .filter(|&(_, short_gain, dual_gain)| short_gain > 0.0 || dual_gain > Some(0.0))
+            .collect();
+        let slots = (table_size as usize).min(items.len());
+        let dual_slots =
+            (max_dual_leases as usize).min(items.iter().filter(|item| item.2.is_some()).count());
+        let width = dual_slots + 1;
+
+        //best[entries * width + duals]: most hits gained with that many entries and dual leases
+        let mut best = vec![f64::NEG_INFINITY; (slots + 1) * width];
+        best[0] = 0.0;
+        //0 skips the item, 1 keeps its short lease, 2 keeps its dual lease
+        let mut choices = vec![0u8; items.len() * best.len()];
+        for (idx, &(_, short_gain, dual_gain)) in items.iter().enumerate() {
+            let choice = &mut choices[idx * best.len()..(idx + 1) * best.len()];
+            for entries in (1..=slots).rev() {
+                for duals in (0..width).rev() {
+                    let cell = entries * width + duals;
+                    let short = best[cell - width] + short_gain;
+                    if short > best[cell] {
+                        best[cell] = short;
+                        choice[cell] = 1;
+                    }
+                    if let Some(dual_gain) = dual_gain
+                        && duals > 0
+                    {
+                        let dual = best[cell - width - 1] + dual_gain;
+                        if dual > best[cell] {
+                            best[cell] = dual;
+                            choice[cell] = 2;
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut cell = (0..best.len())
+            .max_by(|&a, &b| best[a].total_cmp(&best[b]).then(b.cmp(&a)))
+            .unwrap();
+        let mut entries = Vec::new();
+        for (idx, &(reference, _, _)) in items.iter().enumerate().rev() {
+            match choices[idx * best.len() + cell] {
+                1 => {
+                    entries.push((reference, false));
+                    cell -= width;
+                }
+                2 => {
+                    entries.push((reference, true));
+                    cell -= width + 1;
+                }
+                _ => {}
+            }
+        }
+        entries
+    }
+
+    //how much a reference's table entry is worth keeping under the given strategy
+    fn importance(&self, reference: RefKey, ri_hists: &RIHists, strategy: PruneStrategy) -> f64 {
+        let lease = *self.leases.get(&reference).unwrap();
+        let weights = self.lease_weights(reference, None);
+        //a reference without a table entry predicts no hits, so the whole prediction is at stake
+        let hits = self.ref_hits(reference, &weights);
+        let ref_hists = ri_hists
+            .ri_hists
+            .range(reference.with_set(0)..=reference.with_set(u64::MAX))
+            .map(|(_, ri_hist)| ri_hist);
+        match strategy {
+            PruneStrategy::LeaseLength => lease as f64,
+            //without a table entry the reference falls back to its phase's default lease, so
+            //only the hits over that are at stake. The knapsack picks its entries in
+            //knapsack_entries, on their own they are worth the same
+            PruneStrategy::PredictedHits | PruneStrategy::Knapsack => {
+                self.hits_over_default(reference, &weights)
+            }
+            PruneStrategy::SampleCount => ref_hists
+                .flat_map(|ri_hist| ri_hist.values())
+                .map(|(count, _)| *count as f64)
+                .sum(),
+            PruneStrategy::HitsPerCost => {
+                let lease_cost = |lease: u64| -> f64 {
+                    ri_hists
+                        .ri_hists
+                        .range(reference.with_set(0)..=reference.with_set(u64::MAX))
+                        .flat_map(|(_, ri_hist)| ri_hist.iter())
+                        .map(|(ri, (count, _))| (count * ri.min(&lease)) as f64)
+                        .sum()
+                };
+                let cost: f64 = weights
+                    .iter()
+                    .map(|&(lease, weight)| weight * lease_cost(lease.max(self.default_lease)))
+                    .sum();
+                if cost > 0.0 { hits / cost } else { 0.0 }
+            }
+        }
+    }
+
+    /// The leases a reference uses and the probability of each: its distribution, its short and
+    /// long lease, or its single lease. With a `discretize_width`, the short lease probability is
+    /// the one the hardware applies.
+    pub fn lease_weights(
+        &self,
+        reference: RefKey,
+        discretize_width: Option<u64>,
+    ) -> Vec<(u64, f64)> {
+        let lease = *self.leases.get(&reference).unwrap();
+        let lease = lease.max(self.default_lease);
+        if let Some(distribution) = self.lease_distributions.get(&reference) {
+            return distribution.clone();
+        }
+        match self.dual_leases.get(&reference) {
+            Some(&(alpha, long_lease)) => {
+                let percentage = match discretize_width {
+                    Some(width) => super::io::hardware_percentage(1.0 - alpha, width),
+                    None => 1.0 - alpha,
+                };
+                vec![(lease, percentage), (long_lease, 1.0 - percentage)]
+            }
+            None => vec![(lease, 1.0)],
+        }
+    }
+
+    //sampled hits of a reference with each lease taken with the given probability
+    fn ref_hits(&self, reference: RefKey, weights: &[(u64, f64)]) -> f64 {
+        let Some(hits) = self.lease_hits.get(&reference) else {
+            return 0.0;
+        };
+        weights
+            .iter()
+            .map(|(lease, weight)| (hits_at(hits, *lease) as f64 * weight).round())
+            .sum()
+    }
+
+    /// Lease a reference of `phase` gets when it has no lease table entry.
+    pub fn phase_default_lease(&self, phase: u64) -> u64 {
+        *self
+            .phase_default_leases
+            .get(&phase)
+            .unwrap_or(&self.default_lease)
+    }
+
+    /// Sampled hits predicted for the current leases, using the short lease probability the
+    /// hardware applies after discretization. References without a table entry get their
+    /// phase's default lease.
+    pub fn predicted_hits(&self, discretize_width: u64) -> u64 {
+        let mut num_hits = 0.0;
+        for &reference in self.lease_hits.keys() {
+            let weights = match self.leases.contains_key(&reference) {
+                true => self.lease_weights(reference, Some(discretize_width)),
+                false => vec![(self.phase_default_lease(reference.phase), 1.0)],
+            };
+            num_hits += self.ref_hits(reference, &weights);
+        }
+        num_hits as u64
+    }
+
+    /// Picks, per phase, the default lease that predicts the most hits for the references
+    /// without a lease table entry while every (phase, set) budget still holds the leases in
+    /// the table. Phases are taken in order, so under C-SHEL an earlier phase's default lease
+    /// uses budget a later one can no longer spend.
+    pub fn optimize_default_leases(
+        &mut self,
+        cshel: bool,
+        cli: &Cli,
+        context: &LeaseOperationContext,
+    ) {
+        let budget = budget_per_cell(cli, context);
+        let cost_index = CostIndex::new(cshel, context);
+        let cost = |reference: RefKey, lease: u64| {
+            lease_cost_per_cell(&cost_index, context, reference, 0, lease)
+        };
+        let mut used = vec![0.0; budget.len()];
+        for &reference in self.leases.keys() {
+            for (lease, weight) in self.lease_weights(reference, Some(cli.discretize_width)) {
+                for (used, cost) in used.iter_mut().zip(cost(reference, lease)) {
+                    *used += weight * cost as f64;
+                }
+            }
+        }
+
+        let mut outside: BTreeMap<u64, Vec<RefKey>> = BTreeMap::new();
+        for &reference in self.lease_hits.keys() {
+            if !self.leases.contains_key(&reference) {
+                outside.entry(reference.phase).or_default().push(reference);
+            }
+        }
+        for (phase, references) in outside {
+            let outside_cost = |lease: u64| -> Vec<f64> {
+                let mut total = vec![0.0; budget.len()];
+                for &reference in references.iter() {
+                    for (total, cost) in total.iter_mut().zip(cost(reference, lease)) {
+                        *total += cost as f64;
+                    }
+                }
+                total
+            };
+            //the default lease is always affordable, whatever it overruns
+            let default_cost = outside_cost(self.default_lease);
+            let mut best = (
+                self.default_lease,
+                self.outside_hits(&references, self.default_lease),
+            );
+            let mut best_cost = default_cost.clone();
+            let candidates: BTreeSet<u64> = references
+                .iter()
+                .flat_map(|reference| self.lease_hits[reference].keys().cloned())
+                .collect();
+            for lease in candidates {
+                let hits = self.outside_hits(&references, lease);
+                if hits <= best.1 {
+                    continue;
+                }
+                let lease_cost = outside_cost(lease);
+                let fits = lease_cost.iter().enumerate().all(|(cell, cost)| {
+                    used[cell] + cost <= (budget[cell] as f64).max(used[cell] + default_cost[cell])
+                });
+                if fits {
+                    best = (lease, hits);
+                    best_cost = lease_cost;
+                }
+            }
+            for (used, cost) in used.iter_mut().zip(best_cost) {
+                *used += cost;
+            }
+            self.phase_default_leases.insert(phase, best.0);
+        }
+    }
+
+    /// Cache occupancy of the assigned leases in every cell of `budget_per_cell`, references
+    /// without a table entry at their phase's default lease and dual leases at their hardware
+    /// probability.
+    pub fn cost_per_cell(
+        &self,
+        cshel: bool,
+        cli: &Cli,
+        context: &LeaseOperationContext,
+    ) -> Vec<f64> {
+        let num_cells = context.samples_per_phase.len() * (context.set_mask as usize + 1);
+        let cost_index = CostIndex::new(cshel, context);
+        let mut used = vec![0.0; num_cells];
+        for &reference in self.lease_hits.keys() {
+            let weights = match self.leases.contains_key(&reference) {
+                true => self.lease_weights(reference, Some(cli.discretize_width)),
+                false => vec![(self.phase_default_lease(reference.phase), 1.0)],
+            };
+            for (lease, weight) in weights {
+                let cost = lease_cost_per_cell(&cost_index, context, reference, 0, lease);
+                for (used, cost) in used.iter_mut().zip(cost) {
+                    *used += weight * cost as f64;
+                }
+            }
+        }
+        used
+    }
+
+    //sampled hits of references without a table entry if they all get the same lease
+    fn outside_hits(&self, references: &[RefKey], lease: u64) -> u64 {
+        references
+            .iter()
+            .map(|reference| hits_at(&self.lease_hits[reference], lease))
+            .sum()
+    }
 }
 
 pub fn process_sample_cost(
-    ri_hists: &mut HashMap<u64, HashMap<u64, (u64, HashMap<u64, (u64, u64)>)>>,
-    phase_id_ref: u64,
+    ri_hists: &mut BTreeMap<RefKey, RIHist>,
+    ref_id: RefKey,
     ri: u64,
     use_time: u64,
     next_phase_tuple: (u64, u64),
     is_head_cost: bool,
 ) {
-    let phase_id = (phase_id_ref & 0xFF000000) >> 24;
-    let ref_hist = ri_hists.entry(phase_id_ref).or_default();
+    let phase_id = ref_id.phase;
+    let ref_hist = ri_hists.entry(ref_id).or_default();
     if is_head_cost {
-        let ri_tuple = ref_hist.entry(ri).or_insert_with(|| (0, HashMap::new()));
+        let ri_tuple = ref_hist.entry(ri).or_insert_with(|| (0, BTreeMap::new()));
         ri_tuple.0 += 1;
 
-        let this_phase_cost = std::cmp::min(next_phase_tuple.0 - use_time, ri);
-        let next_phase_cost =
-            std::cmp::max(use_time as i64 + ri as i64 - next_phase_tuple.0 as i64, 0) as u64;
+        let (this_phase_cost, next_phase_cost) = split_reuse_cost(use_time, ri, next_phase_tuple);
         ri_tuple.1.entry(phase_id).or_insert_with(|| (0, 0)).0 += this_phase_cost;
         if next_phase_cost > 0 {
             ri_tuple
@@ -267,12 +787,9 @@ pub fn process_sample_cost(
             }
             let count_phase_cost_tuple = ref_hist
                 .entry(ri_other)
-                .or_insert_with(|| (0, HashMap::new()));
-            let this_phase_tail_cost = std::cmp::min(next_phase_tuple.0 - use_time, ri_other);
-            let next_phase_tail_cost = std::cmp::max(
-                0,
-                use_time as i64 + ri_other as i64 - next_phase_tuple.0 as i64,
-            ) as u64;
+                .or_insert_with(|| (0, BTreeMap::new()));
+            let (this_phase_tail_cost, next_phase_tail_cost) =
+                split_reuse_cost(use_time, ri_other, next_phase_tuple);
 
             count_phase_cost_tuple
                 .1
@@ -290,10 +807,53 @@ pub fn process_sample_cost(
     }
 }
 
+/// Cache occupancy of a reuse of `length` from `use_time`, split between the phase it starts in
+/// and the phase of `next_phase_tuple`, the first transition after it.
+pub fn split_reuse_cost(use_time: u64, length: u64, next_phase_tuple: (u64, u64)) -> (u64, u64) {
+    let this_phase_cost = std::cmp::min(next_phase_tuple.0 - use_time, length);
+    let next_phase_cost = std::cmp::max(
+        use_time as i64 + length as i64 - next_phase_tuple.0 as i64,
+        0,
+    ) as u64;
+    (this_phase_cost, next_phase_cost)
+}
+
+/// Cache budget of every (phase, set) cell, phase-major in `samples_per_phase` order.
+pub fn budget_per_cell(cli: &Cli, context: &LeaseOperationContext) -> Vec<u64> {
+    let num_sets = context.set_mask as u64 + 1;
+    let mut budget = Vec::new();
+    for (&phase, &num) in context.samples_per_phase.iter() {
+        for set in 0..num_sets {
+            budget.push(cli.set_budget(phase, set, num_sets, num, context.sample_rate));
+        }
+    }
+    budget
+}
+
+/// Cost of moving a reference from `old_lease` to `new_lease` in every cell of `budget_per_cell`.
+/// Only the cells the reference touches are looked up in the index, the rest cost nothing.
+pub fn lease_cost_per_cell(
+    cost_index: &CostIndex,
+    context: &LeaseOperationContext,
+    ref_id: RefKey,
+    old_lease: u64,
+    new_lease: u64,
+) -> Vec<u64> {
+    let num_sets = context.set_mask as usize + 1;
+    let mut cost = vec![0; context.samples_per_phase.len() * num_sets];
+    for (phase, set) in cost_index.touched_cells(ref_id) {
+        if let Some(position) = context.samples_per_phase.keys().position(|&p| p == phase) {
+            cost[position * num_sets + set as usize] =
+                cost_index.cost(phase, ref_id.with_set(set), old_lease, new_lease);
+        }
+    }
+    cost
+}
+
 pub fn cshel_phase_ref_cost(
     sample_rate: u64,
     phase: u64,
-    ref_id: u64,
+    ref_id: RefKey,
     old_lease: u64,
     new_lease: u64,
     ri_hists: &RIHists,
@@ -305,6 +865,9 @@ pub fn cshel_phase_ref_cost(
         return 0;
     }
     let ri_hist = ri_hists.ri_hists.get(&ref_id).unwrap();
+    //tail costs are sampled at RIs only: a lease between two RIs (one of another set's RIs) pays
+    //the tail of the longest RI it covers, like `hits_at`
+    let (mut old_tail, mut new_tail) = (0, 0);
 
     for (&ri, (_, phase_cost_hashmap)) in ri_hist.iter() {
         let (phase_head_cost, phase_tail_cost) = match phase_cost_hashmap.get(&phase) {
@@ -313,24 +876,20 @@ pub fn cshel_phase_ref_cost(
         };
         if ri <= old_lease {
             old_cost += phase_head_cost;
-        }
-        if ri == old_lease {
-            old_cost += phase_tail_cost;
+            old_tail = phase_tail_cost;
         }
         if ri <= new_lease {
             new_cost += phase_head_cost;
-        }
-        if ri == new_lease {
-            new_cost += phase_tail_cost;
+            new_tail = phase_tail_cost;
         }
     }
-    (new_cost - old_cost) * sample_rate
+    (new_cost + new_tail - old_cost - old_tail) * sample_rate
 }
 
 pub fn shel_phase_ref_cost(
     sample_rate: u64,
     phase: u64,
-    ref_id: u64,
+    ref_id: RefKey,
     old_lease: u64,
     new_lease: u64,
     ri_hists: &RIHists,
@@ -339,12 +898,11 @@ pub fn shel_phase_ref_cost(
     if !ri_hists.ri_hists.contains_key(&ref_id) {
         return 0;
     }
-    let ref_ri_hist: &HashMap<u64, (u64, HashMap<u64, (u64, u64)>)> =
-        ri_hists.ri_hists.get(&ref_id).unwrap();
+    let ref_ri_hist: &RIHist = ri_hists.ri_hists.get(&ref_id).unwrap();
     let ri_hist: Vec<(u64, u64)> = ref_ri_hist.iter().map(|(k, v)| (*k, v.0)).collect();
     let mut old_cost = 0;
     let mut new_cost = 0;
-    if phase != (ref_id & 0xFF000000) >> 24 {
+    if phase != ref_id.phase {
         return 0;
     }
     for (ri, count) in ri_hist.iter() {
@@ -362,53 +920,191 @@ pub fn shel_phase_ref_cost(
     (new_cost - old_cost) * sample_rate
 }
 
-pub fn get_ppuc(
-    ref_id: u64,
+/// Hits each candidate lease would get, per reference summed over all sets.
+/// Hits a reference gets with any lease, not only a sampled RI: those of the longest RI that fits.
+pub fn hits_at(lease_hits: &BTreeMap<u64, u64>, lease: u64) -> u64 {
+    lease_hits
+        .range(..=lease)
+        .next_back()
+        .map(|(_, hits)| *hits)
+        .unwrap_or(0)
+}
+
+pub fn get_lease_hits(ri_hists: &RIHists) -> BTreeMap<RefKey, BTreeMap<u64, u64>> {
+    get_lease_hits_on_grid(ri_hists, LeaseGrid::default())
+}
+
+/// `get_lease_hits` with candidate leases snapped onto what the lease counters can hold.
+pub fn get_lease_hits_on_grid(
+    ri_hists: &RIHists,
+    grid: LeaseGrid,
+) -> BTreeMap<RefKey, BTreeMap<u64, u64>> {
+    let mut lease_hits: BTreeMap<RefKey, BTreeMap<u64, u64>> = BTreeMap::new();
+    for (&ref_id, ri_hist) in ri_hists.ri_hists.iter() {
+        for ppuc in get_ppuc_on_grid(ref_id, 0, ri_hist, grid) {
+            *lease_hits
+                .entry(ref_id.phase_ref())
+                .or_default()
+                .entry(ppuc.lease)
+                .or_insert(0) += ppuc.new_hits;
+        }
+    }
+    lease_hits
+}
+
+pub fn get_ppuc(ref_id: RefKey, base_lease: u64, ref_ri_hist: &RIHist) -> Vec<PPUC> {
+    get_ppuc_on_grid(ref_id, base_lease, ref_ri_hist, LeaseGrid::default())
+}
+
+/// `get_ppuc` over the leases the counters can hold: every RI rounded up to the lease
+/// granularity and clamped to the maximum lease, with hits and cost taken at that lease.
+pub fn get_ppuc_on_grid(
+    ref_id: RefKey,
     base_lease: u64,
-    ref_ri_hist: &HashMap<u64, (u64, HashMap<u64, (u64, u64)>)>,
+    ref_ri_hist: &RIHist,
+    grid: LeaseGrid,
 ) -> Vec<PPUC> {
+    let (base, candidates) = lease_table(base_lease, ref_ri_hist, grid);
+    candidates
+        .iter()
+        .map(|&(lease, hits, cost)| PPUC {
+            ppuc: ((hits - base.1) as f64 / (cost - base.2) as f64),
+            lease,
+            old_lease: base_lease,
+            ref_id,
+            new_hits: hits - base.1,
+        })
+        .collect()
+}
+
+//(lease, hits, cost) of the base lease and of every candidate lease above it, shortest first
+type LeasePoint = (u64, u64, u64);
+
+fn lease_table(
+    base_lease: u64,
+    ref_ri_hist: &RIHist,
+    grid: LeaseGrid,
+) -> (LeasePoint, Vec<LeasePoint>) {
     let ri_hist: Vec<(u64, u64)> = ref_ri_hist.iter().map(|(k, v)| (*k, v.0)).collect();
     let total_count = ri_hist.iter().fold(0, |acc, (_k, v)| acc + v);
     let mut hits = 0;
     let mut head_cost = 0;
 
-    let mut lease_hit_table = HashMap::new();
-    let mut lease_cost_table = HashMap::new();
-
     //prevent kernel panic for a base lease that doesn't correspond to sampled ri for a reference
-    lease_hit_table.insert(base_lease, 0);
-    lease_cost_table.insert(base_lease, 0);
+    let mut base = (base_lease, 0, 0);
+    let mut candidates = Vec::new();
 
     let mut ri_hist_clone = ri_hist.clone();
-    ri_hist_clone.sort_by(|a, b| a.0.cmp(&b.0));
+    ri_hist_clone.sort_by_key(|a| a.0);
+    let leases: BTreeSet<u64> = ri_hist_clone
+        .iter()
+        .map(|(ri, _)| grid.representable(*ri))
+        .collect();
+
+    //with the exact grid every candidate is an RI and this is one pass over the histogram
+    let mut idx = 0;
+    for &lease in leases.iter() {
+        while idx < ri_hist_clone.len() && ri_hist_clone[idx].0 <= lease {
+            let (ri, count) = ri_hist_clone[idx];
+            hits += count;
+            head_cost += count * ri;
+            idx += 1;
+        }
+        let tail_cost = (total_count - hits) * lease;
 
-    for (ri, count) in ri_hist_clone.iter() {
-        hits += *count;
-        head_cost += *count * *ri;
-        let tail_cost = (total_count - hits) * *ri;
+        if lease == base_lease {
+            base = (lease, hits, head_cost + tail_cost);
+        } else if lease > base_lease {
+            candidates.push((lease, hits, head_cost + tail_cost));
+        }
+    }
+    (base, candidates)
+}
 
-        lease_hit_table.insert(*ri, hits);
-        lease_cost_table.insert(*ri, head_cost + tail_cost);
+/// The candidate leases of one (reference, set) that a PPUC greedy can reach from the base
+/// lease: the upper convex hull of hits against cost. From any hull point the highest PPUC is
+/// the next one, so walking the hull takes the same leases as taking the highest of `get_ppuc`
+/// each time, without rescanning the histogram. Collinear points are dropped in favour of the
+/// longest lease, as the highest PPUC does on ties.
+pub struct PPUCHull {
+    ref_id: RefKey,
+    points: Vec<LeasePoint>,
+}
+
+impl PPUCHull {
+    pub fn new(ref_id: RefKey, base_lease: u64, ref_ri_hist: &RIHist, grid: LeaseGrid) -> Self {
+        let (base, candidates) = lease_table(base_lease, ref_ri_hist, grid);
+        //whether b is on or above the line through o and a
+        let not_below = |o: &LeasePoint, a: &LeasePoint, b: &LeasePoint| {
+            let cross = (a.2 as i128 - o.2 as i128) * (b.1 as i128 - o.1 as i128)
+                - (a.1 as i128 - o.1 as i128) * (b.2 as i128 - o.2 as i128);
+            cross >= 0
+        };
+        let mut points = vec![base];
+        for point in candidates {
+            while points.len() >= 2
+                && not_below(&points[points.len() - 2], &points[points.len() - 1], &point)
+            {
+                points.pop();
+            }
+            points.push(point);
+        }
+        Self { ref_id, points }
     }
 
-    ri_hist_clone
-        .iter()
-        .map(|(k, _v)| k)
-        .filter(|k| **k > base_lease)
-        .map(|k| PPUC {
-            ppuc: ((*lease_hit_table.get(k).unwrap() - *lease_hit_table.get(&base_lease).unwrap())
-                as f64
-                / (*lease_cost_table.get(k).unwrap() - *lease_cost_table.get(&base_lease).unwrap())
-                    as f64),
-            lease: *k,
-            old_lease: base_lease,
-            ref_id,
-            new_hits: *lease_hit_table.get(k).unwrap() - *lease_hit_table.get(&base_lease).unwrap(),
+    /// The PPUC from a lease on the hull to the next hull point, `None` at the end of the hull
+    /// or off it.
+    pub fn next_ppuc(&self, lease: u64) -> Option<PPUC> {
+        let idx = self
+            .points
+            .binary_search_by_key(&lease, |point| point.0)
+            .ok()?;
+        let (from, to) = (self.points[idx], *self.points.get(idx + 1)?);
+        Some(PPUC {
+            ppuc: ((to.1 - from.1) as f64 / (to.2 - from.2) as f64),
+            lease: to.0,
+            old_lease: from.0,
+            ref_id: self.ref_id,
+            new_hits: to.1 - from.1,
         })
+    }
+
+    /// Number of hull points, including the base lease.
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+}
+
+/// The PPUC hull of every (reference, set) histogram, from the base lease.
+pub fn ppuc_hulls(
+    ri_hists: &RIHists,
+    base_lease: u64,
+    grid: LeaseGrid,
+) -> BTreeMap<RefKey, PPUCHull> {
+    ri_hists
+        .ri_hists
+        .iter()
+        .map(|(&ref_id, ri_hist)| (ref_id, PPUCHull::new(ref_id, base_lease, ri_hist, grid)))
         .collect()
 }
 
-pub fn get_avg_lease(distribution: &BinnedRIs, addr: &u64, bin: u64, lease: u64) -> u64 {
+/// Queues the highest PPUC of a (reference, set) from its current lease.
+pub fn push_highest_ppuc(
+    ppuc_tree: &mut BinaryHeap<PPUC>,
+    hulls: &BTreeMap<RefKey, PPUCHull>,
+    ref_id: RefKey,
+    lease: u64,
+) {
+    if let Some(highest_ppuc) = hulls[&ref_id].next_ppuc(lease) {
+        ppuc_tree.push(highest_ppuc);
+    }
+}
+
+pub fn get_avg_lease(distribution: &BinnedRIs, addr: &RefKey, bin: u64, lease: u64) -> u64 {
     let mut total = 0;
     for (ri, freq) in distribution
         .bin_ri_distribution
@@ -434,27 +1130,29 @@ pub fn prl(
     binned_freqs: &BinFreqs,
 ) -> Option<LeaseResults> {
     let mut new_lease: PPUC;
-    let mut dual_leases: HashMap<u64, (f64, u64)> = HashMap::new(); //{ref_id, (alpha, long_lease)}
+    let mut dual_leases: BTreeMap<RefKey, (f64, u64)> = BTreeMap::new(); //{ref_id, (alpha, long_lease)}
     let mut trace_length: u64 = 0;
     let mut bin_endpoints: Vec<u64> = Vec::new();
     let mut num_unsuitable: u64;
     let mut ppuc_tree = BinaryHeap::new();
-    let mut impact_dict: HashMap<u64, HashMap<u64, f64>> = HashMap::new();
-    let mut bin_ranks: HashMap<u64, f64> = HashMap::new();
+    let mut impact_dict: BTreeMap<u64, BTreeMap<u64, f64>> = BTreeMap::new();
+    let mut bin_ranks: BTreeMap<u64, f64> = BTreeMap::new();
     let mut sorted_bins: Vec<(u64, f64)> = Vec::new();
     let mut acceptable_ratio: f64;
     let mut neg_impact;
-    let mut lease_hits = HashMap::new();
+    let mut lease_hits = BTreeMap::new();
     let mut num_full_bins;
-    let mut bin_saturation: HashMap<u64, HashMap<u64, f64>> = HashMap::new();
-    let mut leases: HashMap<u64, u64> = HashMap::new();
+    let mut bin_saturation: BTreeMap<u64, BTreeMap<u64, f64>> = BTreeMap::new();
+    let mut leases: BTreeMap<RefKey, u64> = BTreeMap::new();
+    let mut alpha_quantization: BTreeMap<RefKey, AlphaQuantization> = BTreeMap::new();
 
     let num_sets = context.set_mask as u64 + 1;
-    let bin_target: u64 = bin_width * cli.cache_size / num_sets;
-    //threshold for meaningful dual lease
-    let min_alpha = 1.0
-        - (((2 << (cli.discretize_width - 1)) as f64) - 1.5f64)
-            / (((2 << (cli.discretize_width - 1)) as f64) - 1.0f64);
+    //sets may hold fewer blocks than an even share of the cache
+    let bin_target: Vec<u64> = (0..num_sets)
+        .map(|set| cli.set_capacity_share(set, num_sets, bin_width))
+        .collect();
+    let width = cli.discretize_width;
+    let one = 1u64 << width;
 
     if cli.verbose {
         println!("---------Dump Binned RI Hists------------");
@@ -470,7 +1168,7 @@ pub fn prl(
         bin_endpoints.push(*key);
     }
     //each bin will have all addresses although freq may be 0
-    let mut addrs: Vec<u64> = Vec::new();
+    let mut addrs: Vec<RefKey> = Vec::new();
     for key in binned_freqs.bin_freqs.get(&0).unwrap().keys() {
         addrs.push(*key);
     }
@@ -486,7 +1184,7 @@ pub fn prl(
     }
     //make all references have lease of 1
     for addr in addrs {
-        leases.insert(addr & 0x00FFFFFF, 1);
+        leases.insert(addr.phase_ref(), 1);
         // update saturation to take into account each reference having a lease of 1
         for (bin, _sat) in bin_saturation.clone() {
             for set in 0..num_sets {
@@ -518,7 +1216,7 @@ pub fn prl(
     }
 
     for (&ref_id, ri_hist) in context.ri_hists.ri_hists.iter() {
-        let ppuc_vec = get_ppuc(ref_id, 0, ri_hist);
+        let ppuc_vec = get_ppuc_on_grid(ref_id, 0, ri_hist, cli.lease_grid());
         for ppuc in ppuc_vec.iter() {
             ppuc_tree.push(*ppuc);
         }
@@ -528,13 +1226,13 @@ pub fn prl(
         let lease = ppuc_tree.pop().unwrap();
         lease_hits
             .entry(lease.ref_id)
-            .or_insert(HashMap::new())
+            .or_insert(BTreeMap::new())
             .entry(lease.lease)
             .or_insert(lease.new_hits);
     }
     //reinitallize ppuc tree, assuming a base lease of 1
     for (&ref_id, ri_hist) in context.ri_hists.ri_hists.iter() {
-        let ppuc_vec = get_ppuc(ref_id, 1, ri_hist);
+        let ppuc_vec = get_ppuc_on_grid(ref_id, 1, ri_hist, cli.lease_grid());
         for ppuc in ppuc_vec.iter() {
             ppuc_tree.push(*ppuc);
         }
@@ -549,6 +1247,10 @@ pub fn prl(
                     dual_leases,
                     lease_hits,
                     trace_length,
+                    alpha_quantization,
+                    lease_distributions: BTreeMap::new(),
+                    default_lease: 1,
+                    phase_default_leases: BTreeMap::new(),
                 });
             }
         };
@@ -560,11 +1262,11 @@ pub fn prl(
         // }
 
         //continue to pop until we have a ppuc with the right base_lease
-        if new_lease.old_lease != *leases.get(&(new_lease.ref_id & 0xFFFFFFFF)).unwrap() {
+        if new_lease.old_lease != *leases.get(&new_lease.ref_id.phase_ref()).unwrap() {
             continue;
         }
         //won't assign a reference to a reference that has recieved a dual lease
-        if dual_leases.contains_key(&(new_lease.ref_id & 0xFFFFFFFF)) {
+        if dual_leases.contains_key(&new_lease.ref_id.phase_ref()) {
             continue;
         }
         neg_impact = false;
@@ -573,7 +1275,7 @@ pub fn prl(
         for (bin, bin_sat_set) in &bin_saturation {
             for set in bin_sat_set.keys() {
                 let mut impact: f64 = 0.0;
-                let set_addr = (addr & 0xFFFFFFFF) | (set << 32);
+                let set_addr = addr.with_set(*set);
                 if binned_ris
                     .bin_ri_distribution
                     .get(bin)
@@ -584,7 +1286,7 @@ pub fn prl(
                         binned_ris,
                         &set_addr,
                         *bin,
-                        *leases.get(&(addr & 0xFFFFFFFF)).unwrap(),
+                        *leases.get(&addr.phase_ref()).unwrap(),
                     );
                     let avg_lease = get_avg_lease(binned_ris, &set_addr, *bin, new_lease.lease);
                     impact =
@@ -595,7 +1297,8 @@ pub fn prl(
                 } else {
                     impact_dict.get_mut(bin).unwrap().insert(*set, 0f64);
                 }
-                if (bin_saturation.get(bin).unwrap().get(set).unwrap() + impact) > bin_target as f64
+                if (bin_saturation.get(bin).unwrap().get(set).unwrap() + impact)
+                    > bin_target[*set as usize] as f64
                 {
                     num_unsuitable += 1;
                 }
@@ -615,7 +1318,7 @@ pub fn prl(
             }
         }
         if cli.verbose {
-            println!("addr:{:x} ri:{:x}", addr & 0xFFFFFFFF, new_lease.lease);
+            println!("addr:{} ri:{:x}", addr, new_lease.lease);
         }
         //skip lease, if it makes it worse
         if neg_impact {
@@ -625,12 +1328,13 @@ pub fn prl(
             continue;
         }
         if num_unsuitable < 1 {
-            leases.insert(addr & 0xFFFFFFFF, new_lease.lease);
+            leases.insert(addr.phase_ref(), new_lease.lease);
             //push new ppucs
-            let ppuc_vec = get_ppuc(
+            let ppuc_vec = get_ppuc_on_grid(
                 new_lease.ref_id,
                 new_lease.lease,
                 context.ri_hists.ri_hists.get(&new_lease.ref_id).unwrap(),
+                cli.lease_grid(),
             );
 
             for ppuc in ppuc_vec.iter() {
@@ -639,7 +1343,7 @@ pub fn prl(
             let mut print_string: String = String::new();
             for (bin, sat_set) in &bin_saturation.clone() {
                 for (set, sat) in sat_set {
-                    let set_addr = (addr & 0xFFFFFFFF) | (set << 32);
+                    let set_addr = addr.with_set(*set);
                     if binned_ris
                         .bin_ri_distribution
                         .get(bin)
@@ -660,7 +1364,7 @@ pub fn prl(
             }
             if cli.verbose {
                 println!(
-                    "assigning lease: {:x} to reference {:x}",
+                    "assigning lease: {:x} to reference {}",
                     new_lease.lease, addr
                 );
                 println!("Average cache occupancy per bin: [{}]", print_string);
@@ -668,8 +1372,11 @@ pub fn prl(
         } else {
             num_full_bins = 0;
             let mut alpha = 1.0;
+            //the same alpha on the hardware grid, rounded down as SHEL does so no bin goes over
+            let mut fixed_ratio = one;
             for (bin, sat_set) in &bin_saturation {
                 for (set, sat) in sat_set {
+                    let bin_target = bin_target[*set as usize];
                     if sat >= &(bin_target as f64) {
                         num_full_bins += 1
                     }
@@ -684,6 +1391,14 @@ pub fn prl(
                         if set_alpha < alpha {
                             alpha = set_alpha;
                         }
+                        let impact = *impact_dict.get(bin).unwrap().get(set).unwrap();
+                        if impact > 0.0 {
+                            fixed_ratio = fixed_ratio.min(fixed_alpha(
+                                ((bin_target as f64) - sat).max(0.0) as u64,
+                                impact.ceil() as u64,
+                                width,
+                            ));
+                        }
                     }
                 }
                 bin_ranks.insert(*bin, alpha);
@@ -696,19 +1411,51 @@ pub fn prl(
                 }
             }
             sorted_bins.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-            acceptable_ratio = if num_full_bins == 0 {
-                sorted_bins[0].1
+            let (continuous_ratio, fixed_ratio) = if num_full_bins == 0 {
+                (sorted_bins[0].1, fixed_ratio)
             } else {
-                0.0
+                (0.0, 0)
             };
-
-            if acceptable_ratio > min_alpha {
-                dual_leases.insert(addr, (acceptable_ratio, new_lease.lease));
+            //snap down to the probability grid so saturation tracks what the hardware will do
+            acceptable_ratio = alpha_to_f64(fixed_ratio, width);
+
+            if is_meaningful_alpha(fixed_ratio, width) && fixed_ratio < one {
+                dual_leases.insert(addr.phase_ref(), (acceptable_ratio, new_lease.lease));
+                //occupancy the rounding gives up in each bin, added up as the saturation is;
+                //the fullest bin limits the lease, so report the largest
+                let occupancy_shift = bin_saturation
+                    .iter()
+                    .map(|(bin, sat_set)| {
+                        sat_set
+                            .keys()
+                            .filter(|set| {
+                                binned_ris
+                                    .bin_ri_distribution
+                                    .get(bin)
+                                    .unwrap()
+                                    .contains_key(&addr.with_set(**set))
+                            })
+                            .map(|set| {
+                                impact_dict.get(bin).unwrap().get(set).unwrap()
+                                    * (continuous_ratio - acceptable_ratio)
+                                    / bin_width as f64
+                            })
+                            .sum::<f64>()
+                    })
+                    .fold(0.0, f64::max);
+                alpha_quantization.insert(
+                    addr.phase_ref(),
+                    AlphaQuantization {
+                        continuous: continuous_ratio,
+                        quantized: acceptable_ratio,
+                        occupancy_shift,
+                    },
+                );
                 let mut print_string: String = String::new();
 
                 for (bin, sat_set) in &bin_saturation.clone() {
                     for (set, sat) in sat_set {
-                        let set_addr = (addr & 0xFFFFFFFF) | (set << 32);
+                        let set_addr = addr.with_set(*set);
                         if binned_ris
                             .bin_ri_distribution
                             .get(bin)
@@ -742,10 +1489,10 @@ pub fn prl(
     }
 }
 
-pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64> {
-    let mut references_per_phase: HashMap<u64, u64> = HashMap::new();
+pub fn get_num_leases_per_phase(leases: &BTreeMap<RefKey, u64>) -> BTreeMap<u64, u64> {
+    let mut references_per_phase: BTreeMap<u64, u64> = BTreeMap::new();
     for (phase_id_x_reference, _lease) in leases.iter() {
-        let phase_id = (phase_id_x_reference & 0xFF000000) >> 24;
+        let phase_id = phase_id_x_reference.phase;
         references_per_phase
             .entry(phase_id)
             .and_modify(|e| *e += 1)
@@ -755,18 +1502,18 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 }
 
 // pub fn prune_leases_to_fit_llt(
-//     leases: HashMap<u64, u64>,
-//     dual_leases: HashMap<u64, (f64, u64)>,
+//     leases: BTreeMap<u64, u64>,
+//     dual_leases: BTreeMap<u64, (f64, u64)>,
 //     ri_hists: &RIHists,
 //     llt_size: u64,
-// ) -> (HashMap<u64, u64>, HashMap<u64, (f64, u64)>) {
-//     let mut pruned_leases: HashMap<u64, u64> = HashMap::new();
-//     let mut pruned_dual_leases: HashMap<u64, (f64, u64)> = HashMap::new();
-//     let references_per_phase: HashMap<u64, u64> = get_num_leases_per_phase(&leases);
+// ) -> (BTreeMap<u64, u64>, BTreeMap<u64, (f64, u64)>) {
+//     let mut pruned_leases: BTreeMap<u64, u64> = BTreeMap::new();
+//     let mut pruned_dual_leases: BTreeMap<u64, (f64, u64)> = BTreeMap::new();
+//     let references_per_phase: BTreeMap<u64, u64> = get_num_leases_per_phase(&leases);
 //
 //     for (phase_id, _lease_count) in references_per_phase.iter() {
 //         //loop through phases
-//         let mut importance_per_reference: HashMap<u64, u64> = HashMap::new();
+//         let mut importance_per_reference: BTreeMap<u64, u64> = BTreeMap::new();
 //
 //         //this is globally sorting leases by importance
 //         //need to be locally sorting them per phase
@@ -812,21 +1559,21 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 
 // //Output:
 // //leases: Hashmap<u64,u64>
-// //dual_leases: HashMap<u64, (f64, u64)>
-// //lease_hits: HashMap<u64, HashMap<u64,u64>>
+// //dual_leases: BTreeMap<u64, (f64, u64)>
+// //lease_hits: BTreeMap<u64, BTreeMap<u64,u64>>
 // //trace_length: u64
 // pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Option<LeaseResults> {
 //     let mut new_lease: PPUC;
-//     let mut cost_per_phase: HashMap<u64, HashMap<u64, u64>> = HashMap::new();
-//     let mut budget_per_phase: HashMap<u64, u64> = HashMap::new();
-//     let mut leases = HashMap::new(); //{ri, lease}
-//     let mut dual_leases: HashMap<u64, (f64, u64)> = HashMap::new(); //{ref_id, (alpha, long_lease)}
+//     let mut cost_per_phase: BTreeMap<u64, BTreeMap<u64, u64>> = BTreeMap::new();
+//     let mut budget_per_phase: BTreeMap<u64, u64> = BTreeMap::new();
+//     let mut leases = BTreeMap::new(); //{ri, lease}
+//     let mut dual_leases: BTreeMap<u64, (f64, u64)> = BTreeMap::new(); //{ref_id, (alpha, long_lease)}
 //     let mut trace_length: u64 = 0;
-//     let mut lease_hits = HashMap::new();
+//     let mut lease_hits = BTreeMap::new();
 //     let mut dual_lease_phases: Vec<u64> = Vec::new();
 //     //{phase,(cost with alpha, cost if alpha was 1, ref ID)}
-//     let mut past_lease_values: HashMap<u64, (u64, u64)> = HashMap::new();
-//     let mut last_lease_cost: HashMap<u64, HashMap<u64, (u64, u64, u64)>> = HashMap::new();
+//     let mut past_lease_values: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+//     let mut last_lease_cost: BTreeMap<u64, BTreeMap<u64, (u64, u64, u64)>> = BTreeMap::new();
 
 //     let num_sets = context.set_mask as u64 + 1;
 //     let phase_ids: Vec<&u64> = context.samples_per_phase.keys().collect();
@@ -859,7 +1606,7 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 //         //sum hits for reference over all sets
 //         *lease_hits
 //             .entry(lease.ref_id)
-//             .or_insert(HashMap::new())
+//             .or_insert(BTreeMap::new())
 //             .entry(lease.lease)
 //             .or_insert(0) += lease.new_hits;
 //     }
@@ -986,7 +1733,7 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 //         let old_lease = *leases.get(&ref_id).unwrap();
 //         //check for capacity
 //         let mut acceptable_lease = true;
-//         let mut new_phase_ref_cost: HashMap<u64, HashMap<u64, u64>> = HashMap::new();
+//         let mut new_phase_ref_cost: BTreeMap<u64, BTreeMap<u64, u64>> = BTreeMap::new();
 //         for (&phase, current_cost) in cost_per_phase.iter() {
 //             //get cost of assigning a lease of 1 for each set
 //             for set in 0..num_sets {
@@ -1046,7 +1793,7 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 //             );
 
 //             if last_lease_cost.get_mut(&phase).is_none() {
-//                 last_lease_cost.insert(phase, HashMap::new());
+//                 last_lease_cost.insert(phase, BTreeMap::new());
 //             }
 //             for set in 0..num_sets {
 //                 last_lease_cost.get_mut(&phase).unwrap().insert(
@@ -1148,8 +1895,8 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 //                 //if there's no alpha that would assign a meaningful dual lease
 //                 //that wouldn't put other phases over budget
 //                 if alpha <= min_alpha {
-//                     let mut new_costs = HashMap::new();
-//                     let mut new_alpha = HashMap::new();
+//                     let mut new_costs = BTreeMap::new();
+//                     let mut new_alpha = BTreeMap::new();
 //                     let mut adjust_lease = true;
 //                     let mut phase_alpha = 1.0;
 //                     for phase in &phase_ids {
@@ -1169,7 +1916,7 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 //                                     last_lease_cost.get(phase).unwrap().get(&set).unwrap().0
 //                                 };
 //                                 if new_costs.get(&phase).is_none() {
-//                                     new_costs.insert(phase, HashMap::new());
+//                                     new_costs.insert(phase, BTreeMap::new());
 //                                 }
 //                                 let new_cost =
 //                                     cost_per_phase.get(phase).unwrap().get(&set).unwrap()
@@ -1222,7 +1969,7 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 //                             //new costs is equal to old cost
 //                             else {
 //                                 if new_costs.get(&phase).is_none() {
-//                                     new_costs.insert(phase, HashMap::new());
+//                                     new_costs.insert(phase, BTreeMap::new());
 //                                 }
 //                                 new_costs.get_mut(&phase).unwrap().insert(
 //                                     set,
@@ -1378,7 +2125,7 @@ pub fn get_num_leases_per_phase(leases: &HashMap<u64, u64>) -> HashMap<u64, u64>
 //                 }
 
 //                 dual_lease_phases.push(phase);
-//                 //update dual lease HashMap
+//                 //update dual lease BTreeMap
 //                 dual_leases.insert(new_lease.ref_id & 0xFFFFFFFF, (alpha, new_lease.lease));
 
 //                 if cli.verbose {
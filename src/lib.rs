@@ -5,17 +5,29 @@ use crate::io::{build_ri_hists, build_ri_hists_from_iter};
 use crate::lease_gen::{LeaseOperationContext, LeaseResults};
 use crate::utils::*;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::error::Error;
 
+pub mod binning;
+pub mod capacity;
 pub mod cli;
+pub mod cost_index;
+pub mod distribution;
+pub mod flat_hist;
 mod helpers;
 pub mod io;
 pub mod lease_gen;
+pub mod optimal;
+pub mod phase_detect;
+pub mod phase_merge;
+pub mod refine;
 pub mod shel_cshel;
+mod spill;
 mod tests;
 pub mod utils;
 
-pub fn run_this(cli: Cli) -> f64 {
+pub fn run_this(cli: Cli) -> Result<f64, String> {
+    cli.validate().map_err(|err| err.to_string())?;
     let max_scopes = calculate_max_scopes(cli.mem_size, cli.llt_size);
     let num_ways = calculate_num_ways(cli.set_associativity, cli.cache_size);
     let set_mask = calculate_set_mask(cli.cache_size, num_ways);
@@ -29,8 +41,22 @@ pub fn run_this(cli: Cli) -> f64 {
         .unwrap();
     let empirical_rate = cli.empirical_sample_rate.to_lowercase();
 
+    let phases = cli.phase_detection().map(|detection| {
+        let (phases, detected) = io::detect_trace_phases(&cli.input, cli.trace_format(), detection);
+        io::dump_phase_report(&detected, &cli.output);
+        phases
+    });
+
     let (ri_hists, samples_per_phase, misses_from_first_access, empirical_sample_rate) =
-        build_ri_hists(&cli.input, cli.cshel, set_mask);
+        build_ri_hists(
+            &cli.input,
+            cli.cshel,
+            set_mask,
+            cli.trace_format(),
+            cli.threads,
+            cli.memory_limit_bytes(),
+            phases.as_ref(),
+        );
 
     let sample_rate = if empirical_rate == "no" {
         cli.sampling_rate
@@ -47,16 +73,30 @@ pub fn run_this(cli: Cli) -> f64 {
         misses_from_first_access,
         max_scopes,
     };
+    check_capacity_schedule(&cli, &context);
+    let merged = merge_phases_to_fit(&cli, &context);
+    let (context, phase_mapping) = match &merged {
+        Some((ri_hists, samples_per_phase, mapping)) => (
+            LeaseOperationContext {
+                ri_hists,
+                samples_per_phase,
+                ..context
+            },
+            Some(mapping),
+        ),
+        None => (context, None),
+    };
 
     // println!("prl{}", cli.prl);
     if cli.prl > 0 {
-        run_prl(&cli, &context, &cap);
+        run_prl(&cli, &context, &cap, phase_mapping)?;
     }
 
-    run_shel_cshel(&cli, &context, &cap)
+    run_shel_cshel(&cli, &context, &cap, phase_mapping)
 }
 
-pub fn gen_lease_from_trace(cli: Cli, trace: &Vec<(u32, i32, u32)>) -> f64 {
+pub fn gen_lease_from_trace(cli: Cli, trace: &[(u32, i32, u32)]) -> Result<f64, String> {
+    cli.validate().map_err(|err| err.to_string())?;
     let max_scopes = calculate_max_scopes(cli.mem_size, cli.llt_size);
     let num_ways = calculate_num_ways(cli.set_associativity, cli.cache_size);
     let set_mask = calculate_set_mask(cli.cache_size, num_ways);
@@ -76,8 +116,21 @@ pub fn gen_lease_from_trace(cli: Cli, trace: &Vec<(u32, i32, u32)>) -> f64 {
         .unwrap();
     let empirical_rate = cli.empirical_sample_rate.to_lowercase();
 
+    let phases = cli.phase_detection().map(|detection| {
+        let (phases, detected) = io::detect_trace_phases_from_iter(trace, detection);
+        io::dump_phase_report(&detected, &cli.output);
+        phases
+    });
+
     let (ri_hists, samples_per_phase, misses_from_first_access, empirical_sample_rate) =
-        build_ri_hists_from_iter(trace, cli.cshel, set_mask);
+        build_ri_hists_from_iter(
+            trace,
+            cli.cshel,
+            set_mask,
+            cli.threads,
+            cli.memory_limit_bytes(),
+            phases.as_ref(),
+        );
 
     let sample_rate = if empirical_rate == "no" {
         cli.sampling_rate
@@ -94,18 +147,36 @@ pub fn gen_lease_from_trace(cli: Cli, trace: &Vec<(u32, i32, u32)>) -> f64 {
         misses_from_first_access,
         max_scopes,
     };
+    check_capacity_schedule(&cli, &context);
+    let merged = merge_phases_to_fit(&cli, &context);
+    let (context, phase_mapping) = match &merged {
+        Some((ri_hists, samples_per_phase, mapping)) => (
+            LeaseOperationContext {
+                ri_hists,
+                samples_per_phase,
+                ..context
+            },
+            Some(mapping),
+        ),
+        None => (context, None),
+    };
 
     // println!("prl{}", cli.prl);
     if cli.prl > 0 {
-        run_prl(&cli, &context, &cap);
+        run_prl(&cli, &context, &cap, phase_mapping)?;
     }
 
-    run_shel_cshel(&cli, &context, &cap)
+    run_shel_cshel(&cli, &context, &cap, phase_mapping)
 }
 
-pub fn run_prl(cli: &Cli, context: &LeaseOperationContext, cap: &regex::Captures) -> f64 {
+pub fn run_prl(
+    cli: &Cli,
+    context: &LeaseOperationContext,
+    cap: &regex::Captures,
+    phase_mapping: Option<&BTreeMap<u64, u64>>,
+) -> Result<f64, String> {
     let (binned_ri_distributions, binned_freqs, bin_width) =
-        crate::io::get_prl_hists(&cli.input, cli.prl, context.set_mask);
+        crate::io::get_prl_hists(&cli.input, cli.prl, context.set_mask, cli.trace_format());
 
     if &cap[1] == "shel" {
         panic!("Error! You can only use prl on sampling files with a single phase!");
@@ -121,7 +192,8 @@ pub fn run_prl(cli: &Cli, context: &LeaseOperationContext, cap: &regex::Captures
         &binned_freqs,
     )
     .unwrap();
-    lease_results.prune_leases_to_fit_llt(context.ri_hists, cli.llt_size);
+    io::dump_prune_report(&lease_results, context.ri_hists, cli, &cli.output);
+    lease_results.fit_lease_tables(context.ri_hists, cli, cli.prune_strategy);
 
     // generate_output_files(
     //     lease_results,
@@ -131,15 +203,33 @@ pub fn run_prl(cli: &Cli, context: &LeaseOperationContext, cap: &regex::Captures
     //     "prl",
     //     &cap[2],
     // ).unwrap();
-    get_misses(lease_results, context, cli)
+    get_misses(lease_results, context, cli, phase_mapping)
 }
 
-pub fn run_shel_cshel(cli: &Cli, context: &LeaseOperationContext, cap: &regex::Captures) -> f64 {
+pub fn run_shel_cshel(
+    cli: &Cli,
+    context: &LeaseOperationContext,
+    cap: &regex::Captures,
+    phase_mapping: Option<&BTreeMap<u64, u64>>,
+) -> Result<f64, String> {
     // print!("Run {}: ", &cap[1]);
     let output_file_name = format!("{}/{}_{}_{}", cli.output, &cap[2], &cap[1], "leases");
 
-    let mut lease_results = shel_cshel::shel_cshel(false, cli, context).unwrap();
-    lease_results.prune_leases_to_fit_llt(context.ri_hists, cli.llt_size);
+    let mut lease_results = binned_leases(false, cli, context);
+    if cli.optimal {
+        let optimal_results = optimal::optimal_leases(false, cli, context).unwrap();
+        io::dump_optimality_gap(&lease_results, &optimal_results, false, cli, &cli.output);
+    }
+    refine_leases(false, cli, context, &mut lease_results);
+    io::dump_prune_report(&lease_results, context.ri_hists, cli, &cli.output);
+    lease_results.fit_lease_tables(context.ri_hists, cli, cli.prune_strategy);
+    if cli.optimize_default_lease {
+        lease_results.optimize_default_leases(false, cli, context);
+    }
+    if cli.capacity_schedule.is_some() {
+        let report = format!("{}/capacity_report_shel.txt", cli.output);
+        io::dump_capacity_report(&lease_results, false, cli, context, &report);
+    }
 
     // generate_output_files(
     //     lease_results,
@@ -154,14 +244,22 @@ pub fn run_shel_cshel(cli: &Cli, context: &LeaseOperationContext, cap: &regex::C
     //     println!("Running C-SHEL.");
     //     run_cshel(cli, cap, context);
     // }
-    get_misses(lease_results, context, cli)
+    get_misses(lease_results, context, cli, phase_mapping)
 }
 
 pub fn run_cshel(cli: &Cli, cap: &regex::Captures, context: &LeaseOperationContext) {
     println!("Running C-SHEL.");
-    let mut lease_results = crate::shel_cshel::shel_cshel(true, cli, context).unwrap();
+    let mut lease_results = binned_leases(true, cli, context);
+    refine_leases(true, cli, context, &mut lease_results);
 
-    lease_results.prune_leases_to_fit_llt(context.ri_hists, cli.llt_size);
+    lease_results.fit_lease_tables(context.ri_hists, cli, cli.prune_strategy);
+    if cli.optimize_default_lease {
+        lease_results.optimize_default_leases(true, cli, context);
+    }
+    if cli.capacity_schedule.is_some() {
+        let report = format!("{}/capacity_report_c-shel.txt", cli.output);
+        io::dump_capacity_report(&lease_results, true, cli, context, &report);
+    }
 
     let output_file_name = format!("{}/{}_{}_{}", cli.output, &cap[2], "c-shel", "leases");
     // generate_output_files(
@@ -174,18 +272,142 @@ pub fn run_cshel(cli: &Cli, cap: &regex::Captures, context: &LeaseOperationConte
     // ).unwrap();
 }
 
-pub fn get_misses(lease_results: LeaseResults, context: &LeaseOperationContext, cli: &Cli) -> f64 {
+//reports the capacity schedule entries that name a phase or set the hists do not have
+fn check_capacity_schedule(cli: &Cli, context: &LeaseOperationContext) {
+    if let Some(schedule) = &cli.capacity_schedule {
+        let num_sets = context.set_mask as u64 + 1;
+        for mismatch in schedule.mismatches(context.samples_per_phase, num_sets) {
+            println!("Capacity schedule: {}, ignoring it", mismatch);
+        }
+    }
+}
+
+//merged histograms, merged samples per phase and the merged phase of every phase
+type MergedPhases = (lease_gen::RIHists, BTreeMap<u64, u64>, BTreeMap<u64, u64>);
+
+//with --merge-phases, when there are more phases than distinct lease tables fit next to their
+//phase map, merges the most similar ones and reports the hits lost against the assignment on
+//the unmerged phases
+fn merge_phases_to_fit(cli: &Cli, context: &LeaseOperationContext) -> Option<MergedPhases> {
+    if !cli.merge_phases {
+        return None;
+    }
+    let num_phases = context
+        .samples_per_phase
+        .keys()
+        .max()
+        .map_or(0, |phase| phase + 1);
+    let max_tables = io::deduplicated_max_tables(cli.mem_size, cli.llt_size, num_phases);
+    let mapping = phase_merge::merge_mapping(context.ri_hists, max_tables)?;
+    let (ri_hists, samples_per_phase) =
+        phase_merge::merge_phases(context.ri_hists, context.samples_per_phase, &mapping);
+    let merged_context = LeaseOperationContext {
+        ri_hists: &ri_hists,
+        samples_per_phase: &samples_per_phase,
+        ..*context
+    };
+    let unconstrained_results = allocate_leases(false, cli, context);
+    let merged_results = allocate_leases(false, cli, &merged_context);
+    io::dump_phase_merge_report(
+        &mapping,
+        &unconstrained_results,
+        &merged_results,
+        cli,
+        &cli.output,
+    );
+    Some((ri_hists, samples_per_phase, mapping))
+}
+
+fn allocate_leases(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> LeaseResults {
+    match cli.lease_distributions {
+        true => distribution::distribution_leases(cshel, cli, context).unwrap(),
+        false => shel_cshel::shel_cshel(cshel, cli, context).unwrap(),
+    }
+}
+
+//allocates on the binned histograms when binning is on; hits are always predicted from the
+//exact histograms. Only the binning report allocates on the exact ones as well
+fn binned_leases(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> LeaseResults {
+    if cli.ri_binning == binning::RIBinning::Exact {
+        return allocate_leases(cshel, cli, context);
+    }
+    let binned_hists = binning::bin_ri_hists(context.ri_hists, cli.ri_binning, cli.ri_bins);
+    let binned_context = LeaseOperationContext {
+        ri_hists: &binned_hists,
+        sample_rate: context.sample_rate,
+        samples_per_phase: context.samples_per_phase,
+        set_mask: context.set_mask,
+        misses_from_first_access: context.misses_from_first_access,
+        max_scopes: context.max_scopes,
+    };
+
+    let start = std::time::Instant::now();
+    let mut lease_results = allocate_leases(cshel, cli, &binned_context);
+    let binned_time = start.elapsed();
+    lease_results.lease_hits =
+        lease_gen::get_lease_hits_on_grid(context.ri_hists, cli.lease_grid());
+    if !cli.binning_report {
+        return lease_results;
+    }
+    let start = std::time::Instant::now();
+    let exact_results = allocate_leases(cshel, cli, context);
+    let exact_time = start.elapsed();
+
+    io::dump_binning_report(
+        &exact_results,
+        &lease_results,
+        context.ri_hists,
+        &binned_hists,
+        (exact_time, binned_time),
+        cli,
+        &cli.output,
+    );
+    lease_results
+}
+
+fn refine_leases(
+    cshel: bool,
+    cli: &Cli,
+    context: &LeaseOperationContext,
+    lease_results: &mut LeaseResults,
+) {
+    if cli.refine_iterations > 0 {
+        let limits = refine::RefineLimits {
+            max_iterations: cli.refine_iterations,
+            time_limit: std::time::Duration::from_millis(cli.refine_millis),
+        };
+        refine::refine_leases(cshel, cli, context, lease_results, limits);
+    }
+}
+
+//writes the lease image and leases.txt and predicts the miss ratio, or says why the leases do
+//not fit the lease image
+pub fn get_misses(
+    lease_results: LeaseResults,
+    context: &LeaseOperationContext,
+    cli: &Cli,
+    phase_mapping: Option<&BTreeMap<u64, u64>>,
+) -> Result<f64, String> {
+    io::dump_alpha_quantization(&lease_results, &cli.output);
+    io::dump_lease_image(
+        &lease_results,
+        cli,
+        context.max_scopes,
+        phase_mapping,
+        &cli.output,
+    )?;
     let (length, misses) = io::dump_leases(
         lease_results,
         &cli.output,
         context.sample_rate,
         context.misses_from_first_access,
+        cli.discretize_width,
     );
 
     let miss_rate: f64 = misses as f64 / length as f64;
     // println!("length: {}, [CARL (UnboundCache) misses: {}, misses ratio: {}]", length, misses, miss_rate);
 
-    miss_rate
+    Ok(miss_rate)
 
     // let (length, hits) = io::dump_leases(
     //     lease_results,
@@ -207,7 +429,7 @@ pub fn calculate_next_cache_size(cache_size: usize) -> usize {
         cache_size + 2
     } else {
         let mut target = (cache_size * 11 + 5) / 10; // Equivalent to rounding cache_size * 1.1
-        if target % 2 != 0 {
+        if !target.is_multiple_of(2) {
             target += 1; // Ensure target is even
         }
         let next_power_of_two = (cache_size + 1).next_power_of_two();
@@ -232,6 +454,7 @@ pub fn generate_output_files_(
         output_file_name,
         context.sample_rate,
         context.misses_from_first_access,
+        cli.discretize_width,
     );
 
     // let output_lease_file_name = format!("{}/{}_{}_{}", cli.output, cap_index, method, "lease.c");
@@ -23,7 +23,13 @@ fn grinding() {
         cli.output = clam_out_dir.to_string();
         cli.cache_size = cache_size as u64;
         cli.verbose = false;
-        let miss = run_this(cli);
+        let miss = match run_this(cli) {
+            Ok(miss) => miss,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        };
         wtr.write_record(&[cache_size.to_string(), miss.to_string()])
             .unwrap();
 
@@ -39,7 +45,7 @@ fn grinding() {
 fn main() {
     grinding();
 
-    // let cli = Cli::parse();
+    // let cli = Cli::parse_validated();
     //
     // let max_scopes = calculate_max_scopes(cli.mem_size, cli.llt_size);
     // let num_ways = calculate_num_ways(cli.set_associativity, cli.cache_size);
@@ -101,7 +107,7 @@ mod tests {
         // };
         let mut cli = Cli::default();
         cli.input = "tests/clam/access_trace.csv".to_string();
-        run_this(cli);
+        run_this(cli).unwrap();
         // run_clam(cli).unwrap();
     }
 }
@@ -0,0 +1,320 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    cli::Cli,
+    cost_index::CostIndex,
+    helpers::{alpha_to_f64, fixed_alpha, is_meaningful_alpha, scale_by_alpha},
+    lease_gen::*,
+};
+
+//a lease a reference could be given: its sampled hits and the cost it adds over the base lease
+//in every (phase, set) budget cell
+struct Candidate {
+    lease: u64,
+    hits: u64,
+    cost: Vec<u64>,
+}
+
+struct RefChoices {
+    ref_id: RefKey,
+    //all candidate leases, shortest first; candidates[0] is the base lease
+    candidates: Vec<Candidate>,
+    //candidates worth assigning as a single lease (no shorter lease gets as many hits), most hits first
+    plain: Vec<usize>,
+    max_hits: u64,
+}
+
+//a dual lease: (reference index, long lease candidate index, alpha)
+type Dual = (usize, usize, u64);
+
+struct Solver {
+    refs: Vec<RefChoices>,
+    phases: Vec<u64>,
+    //budget left in each cell after every reference has the base lease
+    base_lease: u64,
+    available: Vec<u64>,
+    table_limit: TableLimit,
+    width: u64,
+    //upper bound on the hits references i.. can still add
+    suffix_bound: Vec<u64>,
+    //upper bound on the hits all dual leases can add
+    dual_bound: u64,
+    max_duals: u64,
+    used: Vec<u64>,
+    entries: BTreeMap<u64, u64>,
+    choice: Vec<usize>,
+    hits: u64,
+    best: Option<(u64, Vec<usize>, Vec<Dual>)>,
+}
+
+/// Exact lease assignment by branch-and-bound over every reference's candidate leases, with up
+/// to `Cli::max_dual_leases` dual leases per phase, under the same per-phase, per-set budgets
+/// and lease table size as `shel_cshel`. The search is exponential in the number of references, so this is only meant
+/// for small benchmarks and tests.
+///
+/// Dual leases are chosen one at a time, each taking the largest alpha that still fits. With one
+/// dual lease per phase under SHEL, phases have separate budgets and this is exact; when dual
+/// leases can compete for a budget (C-SHEL, or `Cli::max_dual_leases` above one) the
+/// result is a lower bound.
+pub fn optimal_leases(
+    cshel: bool,
+    cli: &Cli,
+    context: &LeaseOperationContext,
+) -> Option<LeaseResults> {
+    let phases: Vec<u64> = context.samples_per_phase.keys().cloned().collect();
+    let lease_hits = get_lease_hits_on_grid(context.ri_hists, cli.lease_grid());
+    let budget = budget_per_cell(cli, context);
+    let trace_length = context.samples_per_phase.values().sum::<u64>() * context.sample_rate;
+    let base_lease = cli.base_lease();
+    let cost_index = CostIndex::new(cshel, context);
+    let lease_cost = |ref_id: RefKey, old_lease: u64, new_lease: u64| {
+        lease_cost_per_cell(&cost_index, context, ref_id, old_lease, new_lease)
+    };
+
+    let mut baseline = vec![0; budget.len()];
+    let mut refs: Vec<RefChoices> = Vec::new();
+    for (&ref_id, hits) in lease_hits.iter() {
+        for (cell, cost) in lease_cost(ref_id, 0, base_lease).into_iter().enumerate() {
+            baseline[cell] += cost;
+        }
+        let mut candidates = vec![Candidate {
+            lease: base_lease,
+            hits: *hits.get(&base_lease).unwrap_or(&0),
+            cost: vec![0; budget.len()],
+        }];
+        for (&lease, &lease_hits) in hits.range(base_lease + 1..) {
+            candidates.push(Candidate {
+                lease,
+                hits: lease_hits,
+                cost: lease_cost(ref_id, base_lease, lease),
+            });
+        }
+
+        let mut plain: Vec<usize> = Vec::new();
+        let mut most_hits = None;
+        for (idx, candidate) in candidates.iter().enumerate() {
+            if most_hits.is_none_or(|most_hits| candidate.hits > most_hits) {
+                plain.push(idx);
+                most_hits = Some(candidate.hits);
+            }
+        }
+        plain.sort_by_key(|&idx| std::cmp::Reverse(candidates[idx].hits));
+        let max_hits = candidates.iter().map(|c| c.hits).max().unwrap();
+        refs.push(RefChoices {
+            ref_id,
+            candidates,
+            plain,
+            max_hits,
+        });
+    }
+    //search the references with the most to gain first, phase by phase
+    refs.sort_by_key(|r| {
+        (
+            r.ref_id.phase,
+            std::cmp::Reverse(r.max_hits - r.candidates[0].hits),
+        )
+    });
+
+    let mut suffix_bound = vec![0; refs.len() + 1];
+    for idx in (0..refs.len()).rev() {
+        suffix_bound[idx] = suffix_bound[idx + 1] + refs[idx].max_hits;
+    }
+    //each dual lease adds at most the spread of one reference's hits
+    let mut dual_gains_per_phase: BTreeMap<u64, Vec<u64>> = BTreeMap::new();
+    for r in refs.iter() {
+        let min_hits = r.candidates.iter().map(|c| c.hits).min().unwrap();
+        dual_gains_per_phase
+            .entry(r.ref_id.phase)
+            .or_default()
+            .push(r.max_hits - min_hits);
+    }
+    let dual_bound = dual_gains_per_phase
+        .values_mut()
+        .map(|gains| {
+            gains.sort_unstable_by(|a, b| b.cmp(a));
+            gains
+                .iter()
+                .take(cli.max_dual_leases() as usize)
+                .sum::<u64>()
+        })
+        .sum();
+
+    let mut solver = Solver {
+        available: budget
+            .iter()
+            .zip(baseline.iter())
+            .map(|(budget, baseline)| budget.saturating_sub(*baseline))
+            .collect(),
+        used: vec![0; budget.len()],
+        choice: vec![0; refs.len()],
+        refs,
+        phases,
+        base_lease,
+        table_limit: cli.table_limit(context.num_phases()),
+        width: cli.discretize_width,
+        suffix_bound,
+        dual_bound,
+        max_duals: cli.max_dual_leases(),
+        entries: BTreeMap::new(),
+        hits: 0,
+        best: None,
+    };
+    solver.search(0);
+
+    let (_hits, choice, duals) = solver.best?;
+    let mut leases = BTreeMap::new();
+    let mut dual_leases = BTreeMap::new();
+    for (r, &idx) in solver.refs.iter().zip(choice.iter()) {
+        leases.insert(r.ref_id, r.candidates[idx].lease);
+    }
+    for (r, long, alpha) in duals {
+        let r = &solver.refs[r];
+        dual_leases.insert(
+            r.ref_id,
+            (alpha_to_f64(alpha, solver.width), r.candidates[long].lease),
+        );
+    }
+    let mut lease_results = LeaseResults::new(leases, dual_leases, lease_hits, trace_length);
+    lease_results.default_lease = base_lease;
+    Some(lease_results)
+}
+
+impl Solver {
+    fn search(&mut self, idx: usize) {
+        if let Some((best_hits, _, _)) = self.best
+            && self.hits + self.suffix_bound[idx] + self.dual_bound <= best_hits
+        {
+            return;
+        }
+        if idx == self.refs.len() {
+            let (dual_hits, duals) = self.best_duals();
+            if self
+                .best
+                .as_ref()
+                .is_none_or(|(best_hits, _, _)| self.hits + dual_hits > *best_hits)
+            {
+                self.best = Some((self.hits + dual_hits, self.choice.clone(), duals));
+            }
+            return;
+        }
+
+        let phase = self.refs[idx].ref_id.phase;
+        for plain_idx in 0..self.refs[idx].plain.len() {
+            let candidate_idx = self.refs[idx].plain[plain_idx];
+            let candidate = &self.refs[idx].candidates[candidate_idx];
+            let needs_entry = candidate.lease != self.base_lease;
+            if needs_entry && self.table_limit.is_full(&self.entries, phase) {
+                continue;
+            }
+            let fits = candidate
+                .cost
+                .iter()
+                .enumerate()
+                .all(|(cell, cost)| self.used[cell] + cost <= self.available[cell]);
+            if !fits {
+                continue;
+            }
+
+            let hits = candidate.hits;
+            for (used, cost) in self.used.iter_mut().zip(candidate.cost.iter()) {
+                *used += cost;
+            }
+            if needs_entry {
+                *self.entries.entry(phase).or_insert(0) += 1;
+            }
+            self.hits += hits;
+            self.choice[idx] = candidate_idx;
+
+            self.search(idx + 1);
+
+            self.hits -= hits;
+            if needs_entry {
+                *self.entries.get_mut(&phase).unwrap() -= 1;
+            }
+            let candidate = &self.refs[idx].candidates[candidate_idx];
+            for (used, cost) in self.used.iter_mut().zip(candidate.cost.iter()) {
+                *used -= cost;
+            }
+        }
+    }
+
+    //for the current single leases, the dual leases per phase that add the most hits
+    fn best_duals(&self) -> (u64, Vec<Dual>) {
+        let mut used = self.used.clone();
+        let mut entries = self.entries.clone();
+        let mut total = 0;
+        let mut duals: Vec<Dual> = Vec::new();
+        for &phase in self.phases.iter() {
+            for _dual in 0..self.max_duals {
+                let Some((gain, r_idx, long_idx, alpha, extra)) =
+                    self.best_dual(phase, &used, &entries, &duals)
+                else {
+                    break;
+                };
+                for (used, extra) in used.iter_mut().zip(extra.iter()) {
+                    *used += scale_by_alpha(*extra, alpha, self.width);
+                }
+                if self.refs[r_idx].candidates[self.choice[r_idx]].lease == self.base_lease {
+                    *entries.entry(phase).or_insert(0) += 1;
+                }
+                total += gain;
+                duals.push((r_idx, long_idx, alpha));
+            }
+        }
+        (total, duals)
+    }
+
+    //the dual lease that adds the most hits in a phase: (hits added, reference, long lease,
+    //alpha, extra cost per cell)
+    fn best_dual(
+        &self,
+        phase: u64,
+        used: &[u64],
+        entries: &BTreeMap<u64, u64>,
+        duals: &[Dual],
+    ) -> Option<(u64, usize, usize, u64, Vec<u64>)> {
+        let one = 1 << self.width;
+        let mut best: Option<(u64, usize, usize, u64, Vec<u64>)> = None;
+        for (r_idx, r) in self.refs.iter().enumerate() {
+            if r.ref_id.phase != phase || duals.iter().any(|dual| dual.0 == r_idx) {
+                continue;
+            }
+            let short = &r.candidates[self.choice[r_idx]];
+            if short.lease == self.base_lease && self.table_limit.is_full(entries, phase) {
+                continue;
+            }
+            for (long_idx, long) in r.candidates.iter().enumerate() {
+                if long.lease <= short.lease || long.hits <= short.hits {
+                    continue;
+                }
+                let extra: Vec<u64> = long
+                    .cost
+                    .iter()
+                    .zip(short.cost.iter())
+                    .map(|(long, short)| long.saturating_sub(*short))
+                    .collect();
+                let alpha = extra
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, extra)| **extra > 0)
+                    .map(|(cell, extra)| {
+                        fixed_alpha(self.available[cell] - used[cell], *extra, self.width)
+                    })
+                    .min()
+                    .unwrap_or(one);
+                //an alpha of one is a single lease, which the search already covers
+                if alpha >= one || !is_meaningful_alpha(alpha, self.width) {
+                    continue;
+                }
+                let percentage = 1.0 - alpha_to_f64(alpha, self.width);
+                let dual_hits = (short.hits as f64 * percentage).round()
+                    + (long.hits as f64 * (1.0 - percentage)).round();
+                let gain = (dual_hits as u64).saturating_sub(short.hits);
+                if gain > 0 && best.as_ref().is_none_or(|best| gain > best.0) {
+                    best = Some((gain, r_idx, long_idx, alpha, extra));
+                }
+            }
+        }
+        best
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::BTreeSet;
+
+use crate::io::Sample;
+
+/// Window, sensitivity and phase cap of the phase segmentation.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PhaseDetection {
+    /// Samples per window; boundaries fall on window starts
+    pub window: usize,
+    /// A window starts a new phase when the Jaccard similarity of its references to those of the
+    /// current phase falls below this. Higher finds more phases.
+    pub sensitivity: f64,
+    /// Most phases to open, as many as the trace format's phase ids hold; past it windows go
+    /// to the most similar phase
+    pub max_phases: usize,
+}
+
+/// A phase found in the sample stream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DetectedPhase {
+    pub phase: u64,
+    /// (first, last) sample time of every stretch of the trace in this phase
+    pub segments: Vec<(u64, u64)>,
+    pub samples: u64,
+    /// references of the window that opened the phase, which later windows are matched against
+    pub signature: BTreeSet<u64>,
+}
+
+/// Phase of every sample time, as the times phases start at.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PhaseSegments {
+    pub starts: Vec<(u64, u64)>,
+}
+
+impl PhaseSegments {
+    pub fn phase_at(&self, time: u64) -> u64 {
+        match self.starts.partition_point(|&(start, _)| start <= time) {
+            0 => 0,
+            idx => self.starts[idx - 1].1,
+        }
+    }
+}
+
+fn similarity(a: &BTreeSet<u64>, b: &BTreeSet<u64>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// Infers phases from the working set of references, ignoring the phase ids in the samples.
+/// The stream is cut into windows; a window stays in the current phase while its references
+/// look like the phase's, and otherwise goes back to the most similar earlier phase or opens a
+/// new one. Only the current window and one signature per phase are kept.
+pub fn detect_phases(
+    samples: impl Iterator<Item = Sample>,
+    detection: PhaseDetection,
+) -> (PhaseSegments, Vec<DetectedPhase>) {
+    let mut segments = PhaseSegments::default();
+    let mut phases: Vec<DetectedPhase> = Vec::new();
+    let mut current: Option<usize> = None;
+    let mut window = BTreeSet::new();
+    let (mut window_start, mut window_end, mut window_samples) = (0, 0, 0);
+
+    let mut close_window = |window: &BTreeSet<u64>, start: u64, end: u64, num_samples: u64| {
+        let stays = current
+            .map(|phase| similarity(window, &phases[phase].signature) >= detection.sensitivity)
+            .unwrap_or(false);
+        let phase = if stays {
+            current.unwrap()
+        } else {
+            let closest = phases
+                .iter()
+                .map(|phase| similarity(window, &phase.signature))
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1));
+            match closest {
+                Some((phase, similarity))
+                    if similarity >= detection.sensitivity
+                        || phases.len() >= detection.max_phases =>
+                {
+                    phase
+                }
+                _ => {
+                    phases.push(DetectedPhase {
+                        phase: phases.len() as u64,
+                        segments: Vec::new(),
+                        samples: 0,
+                        signature: window.clone(),
+                    });
+                    phases.len() - 1
+                }
+            }
+        };
+        let detected = &mut phases[phase];
+        if current == Some(phase) {
+            detected.segments.last_mut().unwrap().1 = end;
+        } else {
+            detected.segments.push((start, end));
+            segments.starts.push((start, phase as u64));
+        }
+        detected.samples += num_samples;
+        current = Some(phase);
+    };
+
+    for sample in samples {
+        if window_samples == 0 {
+            window_start = sample.time;
+        }
+        window.insert(sample.reference);
+        window_end = sample.time;
+        window_samples += 1;
+        if window_samples as usize == detection.window.max(1) {
+            close_window(&window, window_start, window_end, window_samples);
+            window.clear();
+            window_samples = 0;
+        }
+    }
+    if window_samples > 0 {
+        close_window(&window, window_start, window_end, window_samples);
+    }
+    (segments, phases)
+}
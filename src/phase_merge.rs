@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::lease_gen::{RIHist, RIHists, RefKey};
+
+/// What phase merging compares: the references a phase samples and the shape of its RI
+/// distribution, as counts per power-of-two RI bin.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PhaseProfile {
+    pub references: BTreeSet<u64>,
+    pub ri_bins: BTreeMap<u32, u64>,
+}
+
+impl PhaseProfile {
+    fn absorb(&mut self, other: &PhaseProfile) {
+        self.references.extend(other.references.iter().cloned());
+        for (&bin, &count) in other.ri_bins.iter() {
+            *self.ri_bins.entry(bin).or_insert(0) += count;
+        }
+    }
+
+    /// Average of the Jaccard distance of the reference sets and the total variation distance of
+    /// the RI distributions, both in [0, 1].
+    pub fn distance(&self, other: &PhaseProfile) -> f64 {
+        let union = self.references.union(&other.references).count();
+        let reference_distance = match union {
+            0 => 0.0,
+            _ => {
+                1.0 - self.references.intersection(&other.references).count() as f64 / union as f64
+            }
+        };
+        let total = |bins: &BTreeMap<u32, u64>| bins.values().sum::<u64>().max(1) as f64;
+        let (self_total, other_total) = (total(&self.ri_bins), total(&other.ri_bins));
+        let bins: BTreeSet<u32> = self
+            .ri_bins
+            .keys()
+            .chain(other.ri_bins.keys())
+            .cloned()
+            .collect();
+        let ri_distance = bins
+            .iter()
+            .map(|bin| {
+                let p = *self.ri_bins.get(bin).unwrap_or(&0) as f64 / self_total;
+                let q = *other.ri_bins.get(bin).unwrap_or(&0) as f64 / other_total;
+                (p - q).abs()
+            })
+            .sum::<f64>()
+            / 2.0;
+        (reference_distance + ri_distance) / 2.0
+    }
+}
+
+pub fn phase_profiles(ri_hists: &RIHists) -> BTreeMap<u64, PhaseProfile> {
+    let mut profiles: BTreeMap<u64, PhaseProfile> = BTreeMap::new();
+    for (ref_id, ri_hist) in ri_hists.ri_hists.iter() {
+        let profile = profiles.entry(ref_id.phase).or_default();
+        profile.references.insert(ref_id.reference);
+        for (&ri, (count, _)) in ri_hist.iter() {
+            *profile
+                .ri_bins
+                .entry(u64::BITS - ri.leading_zeros())
+                .or_insert(0) += count;
+        }
+    }
+    profiles
+}
+
+/// Old phase id to merged phase id, merging the two most similar phases until at most
+/// `max_phases` are left. Merged phases are numbered from 0 in the order of their lowest
+/// original phase. `None` when the phases already fit.
+pub fn merge_mapping(ri_hists: &RIHists, max_phases: u64) -> Option<BTreeMap<u64, u64>> {
+    let mut clusters: Vec<(Vec<u64>, PhaseProfile)> = phase_profiles(ri_hists)
+        .into_iter()
+        .map(|(phase, profile)| (vec![phase], profile))
+        .collect();
+    if clusters.len() as u64 <= max_phases {
+        return None;
+    }
+    while clusters.len() as u64 > max_phases.max(1) {
+        let mut closest = (0, 1, f64::INFINITY);
+        for a in 0..clusters.len() {
+            for b in a + 1..clusters.len() {
+                let distance = clusters[a].1.distance(&clusters[b].1);
+                if distance < closest.2 {
+                    closest = (a, b, distance);
+                }
+            }
+        }
+        let (members, profile) = clusters.remove(closest.1);
+        clusters[closest.0].0.extend(members);
+        clusters[closest.0].1.absorb(&profile);
+    }
+    let mut mapping = BTreeMap::new();
+    for (merged, (members, _)) in clusters.iter().enumerate() {
+        for &phase in members {
+            mapping.insert(phase, merged as u64);
+        }
+    }
+    Some(mapping)
+}
+
+/// The histograms and sample counts with every phase replaced by its merged phase. A
+/// reference sampled in several merged phases gets their RI counts and phase costs summed.
+pub fn merge_phases(
+    ri_hists: &RIHists,
+    samples_per_phase: &BTreeMap<u64, u64>,
+    mapping: &BTreeMap<u64, u64>,
+) -> (RIHists, BTreeMap<u64, u64>) {
+    let merged_phase = |phase: u64| *mapping.get(&phase).unwrap_or(&phase);
+    let mut merged: BTreeMap<RefKey, RIHist> = BTreeMap::new();
+    for (ref_id, ri_hist) in ri_hists.ri_hists.iter() {
+        let merged_hist = merged
+            .entry(RefKey::new(
+                merged_phase(ref_id.phase),
+                ref_id.reference,
+                ref_id.set,
+            ))
+            .or_default();
+        for (&ri, (count, phase_costs)) in ri_hist.iter() {
+            let ri_tuple = merged_hist
+                .entry(ri)
+                .or_insert_with(|| (0, BTreeMap::new()));
+            ri_tuple.0 += count;
+            for (&phase, &(head, tail)) in phase_costs.iter() {
+                let cost = ri_tuple.1.entry(merged_phase(phase)).or_insert((0, 0));
+                cost.0 += head;
+                cost.1 += tail;
+            }
+        }
+    }
+    let mut merged_samples = BTreeMap::new();
+    for (&phase, &samples) in samples_per_phase.iter() {
+        *merged_samples.entry(merged_phase(phase)).or_insert(0) += samples;
+    }
+    (RIHists::new(merged), merged_samples)
+}
@@ -0,0 +1,243 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::{cli::Cli, cost_index::CostIndex, helpers::scale_by_alpha, lease_gen::*};
+
+/// Stops the local search after this many sweeps over the phases or this much time.
+pub struct RefineLimits {
+    pub max_iterations: u64,
+    pub time_limit: Duration,
+}
+
+struct Refiner<'a, 'b> {
+    cost_index: CostIndex,
+    context: &'a LeaseOperationContext<'b>,
+    lease_hits: &'a BTreeMap<RefKey, BTreeMap<u64, u64>>,
+    budget: Vec<u64>,
+    used: Vec<u64>,
+    //cost in every cell of a reference holding a lease, from no lease at all
+    costs: BTreeMap<(RefKey, u64), Vec<u64>>,
+}
+
+impl Refiner<'_, '_> {
+    fn cost(&mut self, ref_id: RefKey, lease: u64) -> &Vec<u64> {
+        let (cost_index, context) = (&self.cost_index, self.context);
+        self.costs
+            .entry((ref_id, lease))
+            .or_insert_with(|| lease_cost_per_cell(cost_index, context, ref_id, 0, lease))
+    }
+
+    fn hits(&self, ref_id: RefKey, lease: u64) -> u64 {
+        self.lease_hits
+            .get(&ref_id)
+            .and_then(|hits| hits.get(&lease))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    //cell usage after replacing each (reference, old lease) with its new lease, if every
+    //cell that grows stays within budget
+    fn try_move(&mut self, changes: &[(RefKey, u64, u64)]) -> Option<Vec<u64>> {
+        let mut used = self.used.clone();
+        for &(ref_id, old_lease, new_lease) in changes {
+            let old_cost = self.cost(ref_id, old_lease).clone();
+            let new_cost = self.cost(ref_id, new_lease).clone();
+            for (cell, used) in used.iter_mut().enumerate() {
+                *used = *used + new_cost[cell] - old_cost[cell];
+            }
+        }
+        let fits = used
+            .iter()
+            .zip(self.used.iter())
+            .zip(self.budget.iter())
+            .all(|((new, old), budget)| new <= old || new <= budget);
+        fits.then_some(used)
+    }
+}
+
+/// Local search over the single leases of an assignment: reassigning a reference's lease,
+/// swapping the leases of two references, and shortening one lease to lengthen another, all
+/// within a phase. Moves are taken when they raise the predicted hits and keep every growing
+/// (phase, set) cell within its budget under the same cost model as the allocator. Dual leases
+/// and lease distributions are left as they are. Returns the number of moves taken.
+pub fn refine_leases(
+    cshel: bool,
+    cli: &Cli,
+    context: &LeaseOperationContext,
+    lease_results: &mut LeaseResults,
+    limits: RefineLimits,
+) -> u64 {
+    let start = Instant::now();
+    let width = cli.discretize_width;
+    let base_lease = lease_results.default_lease;
+    let lease_hits = lease_results.lease_hits.clone();
+    let mut refiner = Refiner {
+        cost_index: CostIndex::new(cshel, context),
+        context,
+        lease_hits: &lease_hits,
+        budget: budget_per_cell(cli, context),
+        used: Vec::new(),
+        costs: BTreeMap::new(),
+    };
+
+    let mut used = vec![0; refiner.budget.len()];
+    let mut entries: BTreeMap<u64, u64> = BTreeMap::new();
+    for (&ref_id, &lease) in lease_results.leases.iter() {
+        for (cell, cost) in refiner.cost(ref_id, lease).iter().enumerate() {
+            used[cell] += cost;
+        }
+        if let Some(distribution) = lease_results.lease_distributions.get(&ref_id) {
+            for &(long_lease, weight) in distribution.iter().skip(1) {
+                let weight = (weight * (1u64 << width) as f64).round() as u64;
+                let long_cost =
+                    lease_cost_per_cell(&refiner.cost_index, context, ref_id, lease, long_lease);
+                for (cell, cost) in long_cost.into_iter().enumerate() {
+                    used[cell] += scale_by_alpha(cost, weight, width);
+                }
+            }
+        } else if let Some(&(alpha, long_lease)) = lease_results.dual_leases.get(&ref_id) {
+            let alpha = (alpha * (1u64 << width) as f64).round() as u64;
+            let long_cost =
+                lease_cost_per_cell(&refiner.cost_index, context, ref_id, lease, long_lease);
+            for (cell, cost) in long_cost.into_iter().enumerate() {
+                used[cell] += scale_by_alpha(cost, alpha, width);
+            }
+        }
+        if lease != base_lease
+            || lease_results.dual_leases.contains_key(&ref_id)
+            || lease_results.lease_distributions.contains_key(&ref_id)
+        {
+            *entries.entry(ref_id.phase).or_insert(0) += 1;
+        }
+    }
+    refiner.used = used;
+
+    //references free to move, per phase, with their candidate leases
+    let mut movable: BTreeMap<u64, Vec<(RefKey, Vec<u64>)>> = BTreeMap::new();
+    for &ref_id in lease_results.leases.keys() {
+        if lease_results.dual_leases.contains_key(&ref_id)
+            || lease_results.lease_distributions.contains_key(&ref_id)
+        {
+            continue;
+        }
+        let mut candidates = vec![base_lease];
+        if let Some(hits) = lease_hits.get(&ref_id) {
+            candidates.extend(hits.keys().filter(|&&lease| lease > base_lease));
+        }
+        movable
+            .entry(ref_id.phase)
+            .or_default()
+            .push((ref_id, candidates));
+    }
+
+    let table_limit = cli.table_limit(context.num_phases());
+    let out_of_time = |start: &Instant| start.elapsed() >= limits.time_limit;
+    let mut moves = 0;
+    for _iteration in 0..limits.max_iterations {
+        let mut improved = false;
+        for (phase, refs) in movable.iter() {
+            let llt_full = |entries: &BTreeMap<u64, u64>| table_limit.is_full(entries, *phase);
+            //reassign one reference
+            for (ref_id, candidates) in refs.iter() {
+                for &new_lease in candidates.iter() {
+                    if out_of_time(&start) {
+                        return moves;
+                    }
+                    let old_lease = *lease_results.leases.get(ref_id).unwrap();
+                    if refiner.hits(*ref_id, new_lease) <= refiner.hits(*ref_id, old_lease) {
+                        continue;
+                    }
+                    if old_lease == base_lease && llt_full(&entries) {
+                        continue;
+                    }
+                    if let Some(used) = refiner.try_move(&[(*ref_id, old_lease, new_lease)]) {
+                        refiner.used = used;
+                        lease_results.leases.insert(*ref_id, new_lease);
+                        let phase_entries = entries.entry(*phase).or_insert(0);
+                        if old_lease == base_lease {
+                            *phase_entries += 1;
+                        } else if new_lease == base_lease {
+                            *phase_entries -= 1;
+                        }
+                        moves += 1;
+                        improved = true;
+                    }
+                }
+            }
+            //swap the leases of two references; the table entries stay the same
+            for (a_idx, (a, _)) in refs.iter().enumerate() {
+                for (b, _) in refs.iter().skip(a_idx + 1) {
+                    if out_of_time(&start) {
+                        return moves;
+                    }
+                    let a_lease = *lease_results.leases.get(a).unwrap();
+                    let b_lease = *lease_results.leases.get(b).unwrap();
+                    let old_hits = refiner.hits(*a, a_lease) + refiner.hits(*b, b_lease);
+                    let new_hits = refiner.hits(*a, b_lease) + refiner.hits(*b, a_lease);
+                    if a_lease == b_lease || new_hits <= old_hits {
+                        continue;
+                    }
+                    if let Some(used) =
+                        refiner.try_move(&[(*a, a_lease, b_lease), (*b, b_lease, a_lease)])
+                    {
+                        refiner.used = used;
+                        lease_results.leases.insert(*a, b_lease);
+                        lease_results.leases.insert(*b, a_lease);
+                        moves += 1;
+                        improved = true;
+                    }
+                }
+            }
+            //shorten one lease by a step to make room for lengthening another
+            for (a, a_candidates) in refs.iter() {
+                for (b, b_candidates) in refs.iter() {
+                    if a == b {
+                        continue;
+                    }
+                    let a_lease = *lease_results.leases.get(a).unwrap();
+                    let Some(&a_shorter) = a_candidates.iter().rev().find(|&&l| l < a_lease) else {
+                        continue;
+                    };
+                    for &b_new in b_candidates.iter() {
+                        if out_of_time(&start) {
+                            return moves;
+                        }
+                        let b_lease = *lease_results.leases.get(b).unwrap();
+                        if b_new <= b_lease {
+                            continue;
+                        }
+                        let old_hits = refiner.hits(*a, a_lease) + refiner.hits(*b, b_lease);
+                        let new_hits = refiner.hits(*a, a_shorter) + refiner.hits(*b, b_new);
+                        if new_hits <= old_hits {
+                            continue;
+                        }
+                        let entry_change =
+                            (b_lease == base_lease) as i64 - (a_shorter == base_lease) as i64;
+                        if entry_change > 0 && llt_full(&entries) {
+                            continue;
+                        }
+                        if let Some(used) =
+                            refiner.try_move(&[(*a, a_lease, a_shorter), (*b, b_lease, b_new)])
+                        {
+                            refiner.used = used;
+                            lease_results.leases.insert(*a, a_shorter);
+                            lease_results.leases.insert(*b, b_new);
+                            let phase_entries = entries.entry(*phase).or_insert(0);
+                            *phase_entries = (*phase_entries as i64 + entry_change) as u64;
+                            moves += 1;
+                            improved = true;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    if cli.verbose {
+        println!("Local search took {} moves in {:?}", moves, start.elapsed());
+    }
+    moves
+}
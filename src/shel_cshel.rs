@@ -1,27 +1,40 @@
 use std::{
-    collections::{BinaryHeap, HashMap},
+    collections::{BTreeMap, BTreeSet, BinaryHeap},
     default,
 };
 
-use crate::{cli::*, lease_gen::*};
+use crate::{
+    cli::*,
+    cost_index::CostIndex,
+    helpers::{alpha_to_f64, fixed_alpha, is_meaningful_alpha, scale_by_alpha},
+    lease_gen::*,
+};
 
 //Output:
 //leases: Hashmap<u64,u64>
-//dual_leases: HashMap<u64, (f64, u64)>
-//lease_hits: HashMap<u64, HashMap<u64,u64>>
+//dual_leases: BTreeMap<RefKey, (f64, u64)>
+//lease_hits: BTreeMap<RefKey, BTreeMap<u64,u64>>
 //trace_length: u64
 pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Option<LeaseResults> {
     let mut new_lease: PPUC;
-    let mut cost_per_phase: HashMap<u64, HashMap<u64, u64>> = HashMap::new();
-    let mut budget_per_phase: HashMap<u64, u64> = HashMap::new();
-    let mut leases = HashMap::new(); //{ri, lease}
-    let mut dual_leases: HashMap<u64, (f64, u64)> = HashMap::new(); //{ref_id, (alpha, long_lease)}
+    let mut cost_per_phase: BTreeMap<u64, BTreeMap<u64, u64>> = BTreeMap::new();
+    let mut budget_per_phase: BTreeMap<u64, BTreeMap<u64, u64>> = BTreeMap::new();
+    let mut leases = BTreeMap::new(); //{ri, lease}
+    let mut dual_leases: BTreeMap<RefKey, (f64, u64)> = BTreeMap::new(); //{ref_id, (alpha, long_lease)}
     let mut trace_length: u64 = 0;
-    let mut lease_hits = HashMap::new();
-    let mut dual_lease_phases: Vec<u64> = Vec::new();
+    //number of dual leases assigned in each phase, up to max_duals
+    let mut duals_per_phase: BTreeMap<u64, u64> = BTreeMap::new();
+    let max_duals = cli.max_dual_leases();
     //{phase,(cost with alpha, cost if alpha was 1, ref ID)}
-    let mut past_lease_values: HashMap<u64, (u64, u64)> = HashMap::new();
-    let mut last_lease_cost: HashMap<u64, HashMap<u64, (u64, u64, u64)>> = HashMap::new();
+    let mut past_lease_values: BTreeMap<RefKey, (u64, u64)> = BTreeMap::new();
+    let mut last_lease_cost: BTreeMap<u64, BTreeMap<u64, (u64, u64, RefKey)>> = BTreeMap::new();
+    let mut alpha_quantization: BTreeMap<RefKey, AlphaQuantization> = BTreeMap::new();
+    //references that lost their lease table entry once; they are not evicted again
+    let mut evicted: BTreeSet<RefKey> = BTreeSet::new();
+    //lease table entries per phase, kept up to date as leases are assigned
+    let mut table = TableEntries::default();
+    //packed tables share the lease memory, so a table is full when all of them are
+    let table_limit = cli.table_limit(context.num_phases());
 
     let num_sets = context.set_mask as u64 + 1; // default set_mask value: 0
     let phase_ids: Vec<&u64> = context.samples_per_phase.keys().collect();
@@ -34,39 +47,34 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
         println!("{:?}", &context.samples_per_phase);
     }
 
-    //threshold for meaningful dual lease
-    let min_alpha: f64 = 1.0
-        - (((2 << (cli.discretize_width - 1)) as f64) - 1.5f64)
-            / (((2 << (cli.discretize_width - 1)) as f64) - 1.0f64);
-    //initialize ppucs
+    //alphas are fixed-point fractions of `one`, on the same grid the hardware probability uses
+    let width = cli.discretize_width;
+    let one: u64 = 1 << width;
+    let grid = cli.lease_grid();
+    let lease_hits = get_lease_hits_on_grid(context.ri_hists, grid);
     let mut ppuc_tree = BinaryHeap::new();
-
-    for (&ref_id, ri_hist) in context.ri_hists.ri_hists.iter() {
-        let ppuc_vec = get_ppuc(ref_id, 0, ri_hist);
-        for ppuc in ppuc_vec.iter() {
-            ppuc_tree.push(*ppuc);
-        }
-    }
-
-    while let Some(lease) = ppuc_tree.pop() {
-        // Process the `lease` here
-        *lease_hits
-            .entry(lease.ref_id)
-            .or_insert(HashMap::new())
-            .entry(lease.lease)
-            .or_insert(0) += lease.new_hits;
-    }
-
-    // reinitalize ppuc tree, assuming a base lease of 1
-    for (&ref_id, ri_hist) in context.ri_hists.ri_hists.iter() {
-        push_highest_ppuc(&mut ppuc_tree, ref_id, 1, ri_hist);
+    //lease of 1, or 0 when references without a lease bypass the cache
+    let base_lease = cli.base_lease();
+    //the leases each reference can step through, computed once
+    let hulls = ppuc_hulls(context.ri_hists, base_lease, grid);
+    //lease change costs in O(log n) instead of a scan of the histogram
+    let cost_index = CostIndex::new(cshel, context);
+
+    // reinitalize ppuc tree from the base lease
+    for &ref_id in context.ri_hists.ri_hists.keys() {
+        push_highest_ppuc(&mut ppuc_tree, &hulls, ref_id, base_lease);
     }
 
     //initialize cost + budget
     for (&phase, &num) in context.samples_per_phase.iter() {
-        budget_per_phase
-            .entry(phase)
-            .or_insert(num * cli.cache_size / num_sets * context.sample_rate);
+        //the capacity schedule may leave a phase or set less than its share of the cache
+        let set_budgets = budget_per_phase.entry(phase).or_default();
+        for set in 0..num_sets {
+            set_budgets.insert(
+                set,
+                cli.set_budget(phase, set, num_sets, num, context.sample_rate),
+            );
+        }
         trace_length += num * context.sample_rate;
     }
 
@@ -80,32 +88,14 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
             budget_per_phase
         );
     }
-    //initialize leases to a default value of 1
+    //initialize leases to the base lease
     for (&ref_id, _) in context.ri_hists.ri_hists.iter() {
-        leases.insert(ref_id & 0xFFFFFFFF, 1);
-        let phase = (ref_id & 0xFF000000) >> 24;
-        let phase_id_ref = ref_id & 0xFFFFFFFF;
-        // get cost of assigning a lease of 1 for each set
+        leases.insert(ref_id.phase_ref(), base_lease);
+        let phase = ref_id.phase;
+        // get cost of assigning the base lease for each set
         for set in 0..num_sets {
-            let set_phase_id_ref = phase_id_ref | (set << 32);
-            let new_cost = match cshel {
-                true => cshel_phase_ref_cost(
-                    context.sample_rate,
-                    phase,
-                    set_phase_id_ref,
-                    0,
-                    1,
-                    context.ri_hists,
-                ),
-                false => shel_phase_ref_cost(
-                    context.sample_rate,
-                    phase,
-                    set_phase_id_ref,
-                    0,
-                    1,
-                    context.ri_hists,
-                ),
-            };
+            let set_phase_id_ref = ref_id.with_set(set);
+            let new_cost = cost_index.cost(phase, set_phase_id_ref, 0, base_lease);
             *cost_per_phase
                 .entry(phase)
                 .or_default()
@@ -133,17 +123,21 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
                     dual_leases,
                     lease_hits,
                     trace_length,
+                    alpha_quantization,
+                    lease_distributions: BTreeMap::new(),
+                    default_lease: base_lease,
+                    phase_default_leases: BTreeMap::new(),
                 });
             }
         };
-        let phase = (new_lease.ref_id & 0xFFFFFFFF) >> 24;
-        let ref_id = new_lease.ref_id & 0xFFFFFFFF;
+        let phase = new_lease.ref_id.phase;
+        let ref_id = new_lease.ref_id.phase_ref();
 
         //continue to pop until we have a ppuc with the right base_lease
-        if let Some(&old_lease) = leases.get(&ref_id) {
-            if new_lease.old_lease != old_lease {
-                continue;
-            }
+        if let Some(&old_lease) = leases.get(&ref_id)
+            && new_lease.old_lease != old_lease
+        {
+            continue;
         }
         // else {
         //     // Handle the case where ref_id is not in leases
@@ -151,32 +145,41 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
         //     continue;
         // }
 
-        let mut set_full = false;
+        let mut full_sets = 0;
         for set in 0..num_sets {
             if cost_per_phase.get(&phase).unwrap().get(&set).unwrap()
-                == budget_per_phase.get(&phase).unwrap()
+                >= budget_per_phase.get(&phase).unwrap().get(&set).unwrap()
             {
-                set_full = true;
-                break;
+                full_sets += 1;
             }
         }
 
-        //if any set in phase is full, skip
-        if set_full {
+        //if any set in phase is full, skip. With more than one dual lease per phase,
+        //a dual lease that fills one set leaves room for more in the other sets
+        if (max_duals <= 1 && full_sets > 0) || full_sets == num_sets {
             continue;
         }
-        //if we've already assigned dual leases to all phases, end
-        if dual_lease_phases.len() == cost_per_phase.len() {
+        //if we've already assigned all dual leases to all phases, end
+        if cost_per_phase
+            .keys()
+            .all(|phase| *duals_per_phase.get(phase).unwrap_or(&0) >= max_duals)
+        {
             //TERMINATION CONDITION 2
             return Some(LeaseResults {
                 leases,
                 dual_leases,
                 lease_hits,
                 trace_length,
+                alpha_quantization,
+                lease_distributions: BTreeMap::new(),
+                default_lease: base_lease,
+                phase_default_leases: BTreeMap::new(),
             });
         }
-        //if we've already assigned a dual lease for the phase
-        if dual_lease_phases.contains(&phase) {
+        //if we've already assigned all dual leases for the phase, or this reference has one
+        if *duals_per_phase.get(&phase).unwrap_or(&0) >= max_duals
+            || dual_leases.contains_key(&ref_id)
+        {
             continue;
         }
 
@@ -184,44 +187,88 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
         let default_lease = leases.values().cloned().min().unwrap_or(0);
 
         let old_lease = *leases.get(&ref_id).unwrap();
+
+        //a reference at the default lease needs a lease table entry before it can be assigned.
+        //if the phase's table is full, pick the entry with the lowest marginal utility and
+        //give its cost back to the budget, but only if the new lease is worth more; with packed
+        //tables the entry may come from any phase. The eviction is undone if the new lease
+        //cannot be assigned after all
+        let mut eviction: Option<Eviction> = None;
+        if old_lease == base_lease && table_limit.is_full(&table.per_phase, phase) {
+            //dual leases and the last lease of each phase may still be rewritten by C-SHEL, keep them;
+            //a reference is evicted at most once, so evictions cannot cycle
+            let last_refs: BTreeSet<RefKey> = last_lease_cost
+                .values()
+                .flat_map(|set_costs| set_costs.values().map(|last| last.2))
+                .collect();
+            let victim_phase = match table_limit {
+                TableLimit::PerPhase(_) => Some(phase),
+                TableLimit::Shared(_) => None,
+            };
+            let victim = table.lowest_utility(victim_phase, |key| {
+                last_refs.contains(key) || evicted.contains(key)
+            });
+            let new_utility =
+                phase_ref_utility(&cost_index, ref_id, new_lease.lease, base_lease, num_sets);
+            match victim {
+                Some((victim, utility)) if utility < new_utility => {
+                    let victim_lease = *leases.get(&victim).unwrap();
+                    let mut freed = Vec::new();
+                    for (cost_phase, set) in cost_index.touched_cells(victim) {
+                        let Some(set_cost) = cost_per_phase
+                            .get_mut(&cost_phase)
+                            .and_then(|set_costs| set_costs.get_mut(&set))
+                        else {
+                            continue;
+                        };
+                        let freed_cost = (*set_cost).min(cost_index.cost(
+                            cost_phase,
+                            victim.with_set(set),
+                            base_lease,
+                            victim_lease,
+                        ));
+                        *set_cost -= freed_cost;
+                        freed.push((cost_phase, set, freed_cost));
+                    }
+                    leases.insert(victim, base_lease);
+                    eviction = Some(Eviction {
+                        victim,
+                        victim_lease,
+                        freed,
+                    });
+                }
+                _ => continue,
+            }
+        }
         //check for capacity
         let mut acceptable_lease = true;
-        let mut new_phase_ref_cost: HashMap<u64, HashMap<u64, u64>> = HashMap::new();
-        for (&phase, current_cost) in cost_per_phase.iter() {
-            //get cost of assigning a lease of 1 for each set
-            for set in 0..num_sets {
-                let set_phase_id_ref = ref_id | (set << 32);
-                let additional_cost = match cshel {
-                    true => cshel_phase_ref_cost(
-                        context.sample_rate,
-                        phase,
-                        set_phase_id_ref,
-                        old_lease,
-                        new_lease.lease,
-                        context.ri_hists,
-                    ),
-                    false => shel_phase_ref_cost(
-                        context.sample_rate,
-                        phase,
-                        set_phase_id_ref,
-                        old_lease,
-                        new_lease.lease,
-                        context.ri_hists,
-                    ),
-                };
+        //only the cells the reference's reuses touch can change, every other one costs nothing
+        let mut new_phase_ref_cost: BTreeMap<u64, BTreeMap<u64, u64>> = BTreeMap::new();
+        for (phase, set) in cost_index.touched_cells(ref_id) {
+            let Some(current_cost) = cost_per_phase.get(&phase) else {
+                continue;
+            };
+            let set_phase_id_ref = ref_id.with_set(set);
+            let additional_cost =
+                cost_index.cost(phase, set_phase_id_ref, old_lease, new_lease.lease);
 
-                new_phase_ref_cost
-                    .entry(phase)
-                    .or_default()
-                    .entry(set)
-                    .or_insert(additional_cost);
-                if (additional_cost + current_cost.get(&set).unwrap())
-                    > *budget_per_phase.get(&phase).unwrap()
-                {
-                    acceptable_lease = false;
-                }
+            new_phase_ref_cost
+                .entry(phase)
+                .or_default()
+                .insert(set, additional_cost);
+            if (additional_cost + current_cost.get(&set).unwrap())
+                > *budget_per_phase.get(&phase).unwrap().get(&set).unwrap()
+            {
+                acceptable_lease = false;
             }
         }
+        let ref_cost = |phase: u64, set: u64| {
+            new_phase_ref_cost
+                .get(&phase)
+                .and_then(|set_costs| set_costs.get(&set))
+                .cloned()
+                .unwrap_or(0)
+        };
         if cli.verbose & cli.debug {
             println!("\nDebug: budgets per phase {:?}", &budget_per_phase);
             println!("Debug: Current cost budgets {:?}", &cost_per_phase);
@@ -229,149 +276,72 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
         }
         if acceptable_lease {
             //update cache use
-            for (phase, phase_set_costs) in cost_per_phase.iter_mut() {
-                for (set, set_costs) in phase_set_costs.iter_mut() {
-                    *set_costs += new_phase_ref_cost.get(phase).unwrap().get(set).unwrap();
+            for (phase, phase_set_costs) in new_phase_ref_cost.iter() {
+                for (set, set_cost) in phase_set_costs.iter() {
+                    *cost_per_phase.get_mut(phase).unwrap().get_mut(set).unwrap() += set_cost;
                 }
             }
-            let phase = (new_lease.ref_id & 0xFF000000) >> 24;
+            let phase = new_lease.ref_id.phase;
             //store lease value we assign to the reference and
             //the value of the previously assigned lease for that reference
             past_lease_values.insert(
-                new_lease.ref_id & 0xFFFFFFFF,
+                ref_id,
                 (
                     new_lease.lease,
-                    *leases.get(&(&new_lease.ref_id & 0xFFFFFFFF)).unwrap(),
+                    *leases.get(&ref_id).unwrap(),
                     // .unwrap_or(&default_lease),
                 ),
             );
 
-            if last_lease_cost.get_mut(&phase).is_none() {
-                last_lease_cost.insert(phase, HashMap::new());
-            }
             for set in 0..num_sets {
-                last_lease_cost.get_mut(&phase).unwrap().insert(
-                    set,
-                    (
-                        *new_phase_ref_cost.get(&phase).unwrap().get(&set).unwrap(),
-                        *new_phase_ref_cost.get(&phase).unwrap().get(&set).unwrap(),
-                        ref_id & 0xFFFFFFFF,
-                    ),
-                );
+                last_lease_cost
+                    .entry(phase)
+                    .or_default()
+                    .insert(set, (ref_cost(phase, set), ref_cost(phase, set), ref_id));
             }
             //update leases
-            leases.insert(new_lease.ref_id & 0xFFFFFFFF, new_lease.lease);
-            //push new ppucs
-            push_highest_ppuc(
-                &mut ppuc_tree,
+            leases.insert(ref_id, new_lease.lease);
+            if old_lease == base_lease {
+                table.add(phase);
+            }
+            table.set_single(
                 ref_id,
-                new_lease.lease,
-                context.ri_hists.ri_hists.get(&new_lease.ref_id).unwrap(),
+                phase_ref_utility(&cost_index, ref_id, new_lease.lease, base_lease, num_sets),
             );
+            //push new ppucs
+            push_highest_ppuc(&mut ppuc_tree, &hulls, new_lease.ref_id, new_lease.lease);
 
             if cli.verbose {
                 print!(
-                    "Assigned lease {:x} to reference ({},{:x}). ",
-                    new_lease.lease,
-                    (new_lease.ref_id & 0xFF000000) >> 24,
-                    new_lease.ref_id & 0x00FFFFFF
+                    "Assigned lease {:x} to reference {}. ",
+                    new_lease.lease, new_lease.ref_id
                 );
             }
         } else {
-            // // println!("lease length: {}", leases.len());
-            // // if the leases.len() is greater than cli.llt_size, we need to evict the reference that has the lowest ppuc amount the references in leases
-            // let mut  pruned = false;
-            // while leases.len() >= cli.llt_size.try_into().unwrap() {
-            //     pruned = true;
-            //     if cli.verbose {
-            //         println!(
-            //             "Evicting reference {:x} with lease {}",
-            //             *leases.keys().min().unwrap(),
-            //             *leases.values().min().unwrap()
-            //         );
-            //     }
-
-            //     // Calculate the ppuc for each reference with a given base of lease value of 1 and remove the reference with the lowest ppuc
-            //     let mut uc_tree: Vec<(u64, f64)> = Vec::new();
-            //     for (ref_id, lease) in leases.iter_mut() {
-            //         let uc = marginal_utility_cost(
-            //             *lease,
-            //             default_lease,
-            //             context.ri_hists.ri_hists.get(ref_id).unwrap(),
-            //         );
-            //         uc_tree.push((*ref_id, uc));
-            //     }
-
-            //     // Remove the reference with the lowest ppuc
-            //     let min_ref = uc_tree
-            //         .iter()
-            //         .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
-            //         .unwrap()
-            //         .0;
-            //     //update cache use
-            //     let mut loop_count = 0;
-            //     for (phase, phase_set_costs) in cost_per_phase.iter_mut() {
-            //         for (set, set_costs) in phase_set_costs.iter_mut() {
-            //             loop_count += 1;
-            //             print!("handling ref {:x} ", min_ref);
-            //             *set_costs -= calculate_cost(
-            //                 leases.get(&min_ref).unwrap(),
-            //                 default_lease,
-            //                 context.ri_hists.ri_hists.get(&(min_ref | (set << 32))).unwrap(),
-            //             );
-            //         }
-            //     }
-            //     println!("Processed {} loops", loop_count);
-            //     // lease_hits.remove(&min_ref);
-            //     leases.remove(&min_ref);
-            //     // Also remove the dual lease if it exists
-            //     dual_leases.remove(&min_ref);
-
-            //     push_highest_ppuc(
-            //         &mut ppuc_tree,
-            //         min_ref,
-            //         1,
-            //         context.ri_hists.ri_hists.get(&min_ref).unwrap(),
-            //     );
-            // }
-            // if pruned {
-            //     continue;
-            // }
             //unacceptable lease, must assign a dual lease
-            let mut alpha = 1.0;
-            let mut current_phase_alpha = 1.0;
-            for (&phase, phase_set_current_cost) in cost_per_phase.iter() {
-                let set_budget = *budget_per_phase.get(&phase).unwrap();
-                for (&set, &current_set_cost) in phase_set_current_cost.iter() {
-                    let &set_phase_ref_cost =
-                        new_phase_ref_cost.get(&phase).unwrap().get(&set).unwrap();
+            let mut alpha = one;
+            let mut current_phase_alpha = one;
+            //the alphas the budgets allow before snapping to the grid, for the quantization report
+            let mut continuous_alpha: f64 = 1.0;
+            let mut continuous_phase_alpha: f64 = 1.0;
+            for (&phase, phase_set_ref_cost) in new_phase_ref_cost.iter() {
+                for (&set, &set_phase_ref_cost) in phase_set_ref_cost.iter() {
+                    let set_budget = *budget_per_phase.get(&phase).unwrap().get(&set).unwrap();
+                    let current_set_cost = *cost_per_phase.get(&phase).unwrap().get(&set).unwrap();
                     if set_phase_ref_cost > 0 {
-                        // TODO: Fix this
-                        // if set_budget < current_set_cost {
-                        //     println!(
-                        //         "
-                        //     ERROR: current cost exceeds budget
-                        //     *budget_per_phase.get(&phase)=.unwrap():  {}
-                        //     set:                                     {}
-                        //     current_set_cost:                        {}
-                        //     ",
-                        //         set_budget, set, current_set_cost
-                        //     );
-                        //     panic!();
-                        // }
-
-                        let remaining_budget = set_budget - current_set_cost;
+                        //the initial lease of 1 for every reference may already exceed a small budget
+                        let remaining_budget = set_budget.saturating_sub(current_set_cost);
+                        let set_alpha = fixed_alpha(remaining_budget, set_phase_ref_cost, width);
+                        let set_continuous_alpha =
+                            (remaining_budget as f64 / set_phase_ref_cost as f64).min(1.0);
                         //get the best alpha for any set  (ignoring other phases) that we want for the current reference
-                        if phase == (new_lease.ref_id & 0xFF000000) >> 24 {
-                            current_phase_alpha = super::helpers::float_min(
-                                current_phase_alpha,
-                                remaining_budget as f64 / set_phase_ref_cost as f64,
-                            );
+                        if phase == new_lease.ref_id.phase {
+                            current_phase_alpha = current_phase_alpha.min(set_alpha);
+                            continuous_phase_alpha =
+                                continuous_phase_alpha.min(set_continuous_alpha);
                         }
-                        alpha = super::helpers::float_min(
-                            alpha,
-                            remaining_budget as f64 / set_phase_ref_cost as f64,
-                        );
+                        alpha = alpha.min(set_alpha);
+                        continuous_alpha = continuous_alpha.min(set_continuous_alpha);
                     }
                 }
             }
@@ -386,20 +356,12 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
             //     continue;
             // }
 
-            if alpha > min_alpha {
-                //update cache use
-                for (phase, phase_set_costs) in cost_per_phase.iter_mut() {
-                    let mut set_budget = *budget_per_phase.get(phase).unwrap();
-                    for (set, set_costs) in phase_set_costs.iter_mut() {
-                        *set_costs += (*new_phase_ref_cost.get(phase).unwrap().get(set).unwrap()
-                            as f64
-                            * alpha)
-                            .round() as u64;
-                        //fix floating point precision error leading
-                        //to "overallocation" or underallocation
-                        if set_costs > &mut set_budget {
-                            *set_costs = set_budget;
-                        }
+            if is_meaningful_alpha(alpha, width) {
+                //update cache use; scale_by_alpha rounds down, so no set can exceed its budget
+                for (phase, phase_set_costs) in new_phase_ref_cost.iter() {
+                    for (set, set_cost) in phase_set_costs.iter() {
+                        *cost_per_phase.get_mut(phase).unwrap().get_mut(set).unwrap() +=
+                            scale_by_alpha(*set_cost, alpha, width);
                     }
                 }
             }
@@ -407,17 +369,16 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
             if cshel {
                 //if there's no alpha that would assign a meaningful dual lease
                 //that wouldn't put other phases over budget
-                if alpha <= min_alpha {
-                    let mut new_costs = HashMap::new();
-                    let mut new_alpha = HashMap::new();
+                if !is_meaningful_alpha(alpha, width) {
+                    let mut new_costs = BTreeMap::new();
+                    let mut new_alpha = BTreeMap::new();
                     let mut adjust_lease = true;
-                    let mut phase_alpha = 1.0;
+                    let mut phase_alpha = one;
                     for phase in &phase_ids {
                         for set in 0..num_sets {
-                            let set_phase_ref_cost =
-                                new_phase_ref_cost.get(phase).unwrap().get(&set).unwrap();
+                            let set_phase_ref_cost = ref_cost(**phase, set);
                             //if the phase would be effected by the lease assignment
-                            if set_phase_ref_cost > &0 {
+                            if set_phase_ref_cost > 0 {
                                 //get phases that would be over budgeted by assigning the current lease.
                                 //then subtract the cost of their prior dual lease (which may be, due to the default, a non-dual lease)
                                 //and then add the spillover cost from the new leases
@@ -428,44 +389,59 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
                                 } else {
                                     last_lease_cost.get(phase).unwrap().get(&set).unwrap().0
                                 };
-                                if new_costs.get(&phase).is_none() {
-                                    new_costs.insert(phase, HashMap::new());
-                                }
-                                let new_cost =
-                                    cost_per_phase.get(phase).unwrap().get(&set).unwrap()
-                                        - past_cost_actual
-                                        + (*set_phase_ref_cost as f64 * current_phase_alpha).round()
-                                            as u64;
-                                new_costs.get_mut(&phase).unwrap().insert(set, new_cost);
+                                let new_cost = cost_per_phase
+                                    .get(phase)
+                                    .unwrap()
+                                    .get(&set)
+                                    .unwrap()
+                                    .saturating_sub(past_cost_actual)
+                                    + scale_by_alpha(
+                                        set_phase_ref_cost,
+                                        current_phase_alpha,
+                                        width,
+                                    );
+                                new_costs
+                                    .entry(phase)
+                                    .or_insert_with(BTreeMap::new)
+                                    .insert(set, new_cost);
                                 //if no lease adjustment can be made to keep the phase from being over budget
                                 if new_costs.get(&phase).unwrap().get(&set).unwrap()
-                                    > budget_per_phase.get(phase).unwrap()
+                                    > budget_per_phase.get(phase).unwrap().get(&set).unwrap()
                                 {
                                     adjust_lease = false;
                                     break;
                                 }
-                                let remaining_budget = *budget_per_phase.get(phase).unwrap()
-                                    - new_costs.get(&phase).unwrap().get(&set).unwrap();
+                                let remaining_budget =
+                                    *budget_per_phase.get(phase).unwrap().get(&set).unwrap()
+                                        - new_costs.get(&phase).unwrap().get(&set).unwrap();
                                 //if cost of last lease was zero i.e., no prior lease for phase, then alpha will be 1 and will not be adjusted
                                 let past_cost_max = if past_cost_actual != 0 {
                                     last_lease_cost.get(phase).unwrap().get(&set).unwrap().1
                                 } else {
                                     0
                                 };
+                                //turning the phase's last single lease into a dual lease needs a free dual slot
+                                if past_cost_max != 0
+                                    && **phase != new_lease.ref_id.phase
+                                    && !dual_leases.contains_key(
+                                        &last_lease_cost.get(phase).unwrap().get(&set).unwrap().2,
+                                    )
+                                    && *duals_per_phase.get(*phase).unwrap_or(&0) >= max_duals
+                                {
+                                    adjust_lease = false;
+                                    break;
+                                }
                                 if past_cost_max != 0 {
                                     //if previous long lease didn't fill phase, could be greater than one
-                                    let set_phase_alpha = super::helpers::float_min(
-                                        1.0,
-                                        remaining_budget as f64 / past_cost_max as f64,
-                                    );
-                                    if set_phase_alpha <= min_alpha {
+                                    let set_phase_alpha =
+                                        fixed_alpha(remaining_budget, past_cost_max, width);
+                                    if !is_meaningful_alpha(set_phase_alpha, width) {
                                         let old_phase_ref = last_lease_cost
                                             .get(phase)
                                             .unwrap()
                                             .get(&set)
                                             .unwrap()
                                             .2;
-                                        dual_leases.get(&old_phase_ref).unwrap().1;
                                         // println!("Assigning adjusted dual lease {:x} with percentage {} to reference ({},{:x}) would not be meaningful.",
                                         //          new_lease.lease, set_phase_alpha, phase, old_phase_ref);
                                         adjust_lease = false;
@@ -481,10 +457,7 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
                             }
                             //new costs is equal to old cost
                             else {
-                                if new_costs.get(&phase).is_none() {
-                                    new_costs.insert(phase, HashMap::new());
-                                }
-                                new_costs.get_mut(&phase).unwrap().insert(
+                                new_costs.entry(phase).or_insert_with(BTreeMap::new).insert(
                                     set,
                                     *cost_per_phase.get(phase).unwrap().get(&set).unwrap(),
                                 );
@@ -500,28 +473,36 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
                                         last_lease_cost.get(phase).unwrap().get(&set).unwrap().1;
                                     let old_phase_ref =
                                         last_lease_cost.get(phase).unwrap().get(&set).unwrap().2;
-                                    let new_phase_cost = (old_phase_cost_max as f64
-                                        * new_alpha.get(phase).unwrap())
-                                        as u64;
+                                    let new_phase_cost = scale_by_alpha(
+                                        old_phase_cost_max,
+                                        *new_alpha.get(phase).unwrap(),
+                                        width,
+                                    );
 
-                                    //if phase had a dual lease
-                                    if dual_lease_phases.contains(phase) {
+                                    //if the phase's last lease was a dual lease
+                                    if dual_leases.contains_key(&old_phase_ref) {
                                         dual_leases.insert(
                                             old_phase_ref,
                                             (
-                                                *new_alpha.get(&phase).unwrap(),
+                                                alpha_to_f64(
+                                                    *new_alpha.get(&phase).unwrap(),
+                                                    width,
+                                                ),
                                                 dual_leases.get(&old_phase_ref).unwrap().1,
                                             ),
                                         );
                                     }
                                     //if we are not currently assigning a dual lease to this phase
                                     //generate dual lease from the past two lease values of the last reference assigned in this phase
-                                    else if **phase != new_lease.ref_id >> 24 {
+                                    else if **phase != new_lease.ref_id.phase {
                                         //set prior single lease as long lease value with new alpha
                                         dual_leases.insert(
                                             old_phase_ref,
                                             (
-                                                *new_alpha.get(&phase).unwrap(),
+                                                alpha_to_f64(
+                                                    *new_alpha.get(&phase).unwrap(),
+                                                    width,
+                                                ),
                                                 past_lease_values.get(&old_phase_ref).unwrap().0,
                                             ),
                                         );
@@ -530,7 +511,8 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
                                             old_phase_ref,
                                             past_lease_values.get(&old_phase_ref).unwrap().1,
                                         );
-                                        dual_lease_phases.push(**phase);
+                                        *duals_per_phase.entry(**phase).or_insert(0) += 1;
+                                        table.unset(old_phase_ref);
                                     }
 
                                     last_lease_cost.get_mut(phase).unwrap().insert(
@@ -551,106 +533,148 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
                                         set,
                                         *new_costs.get(phase).unwrap().get(&set).unwrap(),
                                     );
-                                    //fix floating point precision error leading to "overallocation"
-                                    if cost_per_phase.get(phase).unwrap().get(&set).unwrap()
-                                        > budget_per_phase.get(phase).unwrap()
-                                    {
-                                        cost_per_phase
-                                            .get_mut(phase)
-                                            .unwrap()
-                                            .insert(set, *budget_per_phase.get(phase).unwrap());
-                                    }
                                 }
                             }
                         }
                         alpha = current_phase_alpha;
+                        continuous_alpha = continuous_phase_alpha;
                     } else {
                         //if we can't assign a dual lease without overflowing a phase
                         //without adjustment of past dual leases, with adjustment of past dual leases,
                         //or in the the unlikely case a phase is full with no dual lease
 
                         println!(
-                            "Unable to assign lease {:x} with percentage {} to reference ({},{:x})",
+                            "Unable to assign lease {:x} with percentage {} to reference {}",
                             new_lease.lease,
-                            current_phase_alpha,
-                            (new_lease.ref_id & 0xFF000000) >> 24,
-                            new_lease.ref_id & 0x00FFFFFF
+                            alpha_to_f64(current_phase_alpha, width),
+                            new_lease.ref_id
                         );
+                        if let Some(eviction) = eviction {
+                            eviction.undo(&mut cost_per_phase, &mut leases);
+                        }
                         continue;
                     }
                 }
+            } else if !is_meaningful_alpha(alpha, width) {
+                //SHEL can't adjust past leases, so a dual lease this short would never be used;
+                //give the victim its lease back and leave the reference at its current lease
+                if cli.verbose {
+                    println!(
+                        "Assigning lease {:x} with percentage {} to reference {} would not be meaningful.",
+                        new_lease.lease,
+                        alpha_to_f64(alpha, width),
+                        new_lease.ref_id
+                    );
+                }
+                if let Some(eviction) = eviction {
+                    eviction.undo(&mut cost_per_phase, &mut leases);
+                }
+                continue;
             }
 
-            let phase = (new_lease.ref_id & 0xFF000000) >> 24;
+            let phase = new_lease.ref_id.phase;
 
             //detect if set full
             let mut set_full = false;
             for set in 0..num_sets {
                 if cost_per_phase.get(&phase).unwrap().get(&set).unwrap()
-                    == budget_per_phase.get(&phase).unwrap()
+                    >= budget_per_phase.get(&phase).unwrap().get(&set).unwrap()
                 {
                     set_full = true;
                     break;
                 }
             }
-            //if last lease was a dual lease with alpha of 1 that didn't fill the budget, then it is actually a short lease and adjustments can be made to ensure
-            //there is only 1 dual lease per phase.
-            if alpha == 1.0 && !set_full {
+            //if last lease was a dual lease with alpha of 1, then it is actually a short lease and adjustments can be made to ensure
+            //there is only 1 dual lease per phase. The hardware can't store a short lease probability of 0,
+            //so this holds even if the lease filled the budget; the full set closes the phase on the next pop.
+            if alpha == one {
                 //update leases
-                leases.insert(new_lease.ref_id & 0xFFFFFFFF, new_lease.lease);
-
-                //push new ppucs
-                push_highest_ppuc(
-                    &mut ppuc_tree,
+                leases.insert(ref_id, new_lease.lease);
+                if old_lease == base_lease {
+                    table.add(phase);
+                }
+                table.set_single(
                     ref_id,
-                    new_lease.lease,
-                    context.ri_hists.ri_hists.get(&new_lease.ref_id).unwrap(),
+                    phase_ref_utility(&cost_index, ref_id, new_lease.lease, base_lease, num_sets),
                 );
 
+                //push new ppucs
+                push_highest_ppuc(&mut ppuc_tree, &hulls, new_lease.ref_id, new_lease.lease);
+
                 if cli.verbose {
                     println!(
-                        "Assigned lease {:x} to reference ({},{:x}).",
-                        new_lease.lease,
-                        (new_lease.ref_id & 0xFF000000) >> 24,
-                        new_lease.ref_id & 0x00FFFFFF
+                        "Assigned lease {:x} to reference {}.",
+                        new_lease.lease, new_lease.ref_id
                     );
                 }
             } else {
                 //add dual lease
                 //store cost of dual lease and store cost of lease with no dual lease and the reference for that lease
                 for set in 0..num_sets {
-                    if last_lease_cost.get_mut(&phase).is_none() {
-                        last_lease_cost.entry(phase).or_default();
-                    }
-
-                    last_lease_cost.get_mut(&phase).unwrap().insert(
+                    last_lease_cost.entry(phase).or_default().insert(
                         set,
                         (
-                            (*new_phase_ref_cost.get(&phase).unwrap().get(&set).unwrap() as f64
-                                * alpha)
-                                .round() as u64,
-                            *new_phase_ref_cost.get(&phase).unwrap().get(&set).unwrap(),
-                            ref_id & 0xFFFFFFFF,
+                            scale_by_alpha(ref_cost(phase, set), alpha, width),
+                            ref_cost(phase, set),
+                            ref_id,
                         ),
                     );
                 }
 
-                dual_lease_phases.push(phase);
-                //update dual lease HashMap
-                dual_leases.insert(new_lease.ref_id & 0xFFFFFFFF, (alpha, new_lease.lease));
+                *duals_per_phase.entry(phase).or_insert(0) += 1;
+                if old_lease == base_lease {
+                    table.add(phase);
+                }
+                table.unset(ref_id);
+                //update dual lease BTreeMap
+                dual_leases.insert(ref_id, (alpha_to_f64(alpha, width), new_lease.lease));
+
+                let quantized = alpha_to_f64(alpha, width);
+                let shift_cost: u64 = new_phase_ref_cost
+                    .values()
+                    .flat_map(|set_costs| set_costs.values())
+                    .sum();
+                alpha_quantization.insert(
+                    ref_id,
+                    AlphaQuantization {
+                        continuous: continuous_alpha,
+                        quantized,
+                        occupancy_shift: shift_cost as f64 * (continuous_alpha - quantized)
+                            / trace_length as f64,
+                    },
+                );
 
                 if cli.verbose {
                     println!(
-                        "Assigned dual lease ({:x},{}) to reference ({},{:x}).",
+                        "Assigned dual lease ({:x},{}) to reference {}.",
                         new_lease.lease,
-                        alpha,
-                        (new_lease.ref_id & 0xFF000000) >> 24,
-                        new_lease.ref_id & 0x00FFFFFF
+                        alpha_to_f64(alpha, width),
+                        new_lease.ref_id
                     );
                 }
             }
         } //unacceptable lease
 
+        //the new lease took the victim's table entry; the victim starts over from the base lease
+        if let Some(eviction) = eviction {
+            table.remove(eviction.victim.phase);
+            table.unset(eviction.victim);
+            past_lease_values.remove(&eviction.victim);
+            evicted.insert(eviction.victim);
+            for set in 0..num_sets {
+                let victim_set = eviction.victim.with_set(set);
+                if context.ri_hists.ri_hists.contains_key(&victim_set) {
+                    push_highest_ppuc(&mut ppuc_tree, &hulls, victim_set, base_lease);
+                }
+            }
+            if cli.verbose {
+                println!(
+                    "Evicted lease {:x} of reference {} for reference {}.",
+                    eviction.victim_lease, eviction.victim, new_lease.ref_id
+                );
+            }
+        }
+
         if cli.verbose & cli.debug {
             for (phase, num) in context.samples_per_phase.iter() {
                 for set in 0..num_sets {
@@ -675,23 +699,13 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
         if cli.verbose {
             let mut hits_from_old_lease = 0;
 
-            if lease_hits
-                .get(&new_lease.ref_id)
-                .unwrap()
-                .get(&old_lease)
-                .is_some()
-            {
-                hits_from_old_lease = *lease_hits
-                    .get(&new_lease.ref_id)
-                    .unwrap()
-                    .get(&old_lease)
-                    .unwrap();
+            if let Some(&hits) = lease_hits.get(&ref_id).unwrap().get(&old_lease) {
+                hits_from_old_lease = hits;
             }
-            let mut hits_from_new_lease =
-                *lease_hits.get(&new_lease.ref_id)?.get(&new_lease.lease)?;
+            let mut hits_from_new_lease = *lease_hits.get(&ref_id)?.get(&new_lease.lease)?;
             let long_lease_percentage: f64;
-            if dual_leases.contains_key(&new_lease.ref_id) {
-                long_lease_percentage = dual_leases.get(&new_lease.ref_id).unwrap().0;
+            if dual_leases.contains_key(&ref_id) {
+                long_lease_percentage = dual_leases.get(&ref_id).unwrap().0;
                 let hits_without_dual = hits_from_new_lease;
 
                 hits_from_new_lease = hits_without_dual
@@ -706,61 +720,109 @@ pub fn shel_cshel(cshel: bool, cli: &Cli, context: &LeaseOperationContext) -> Op
     }
 }
 
-fn push_highest_ppuc(
-    ppuc_tree: &mut BinaryHeap<PPUC>,
-    ref_id: u64,
-    base_lease: u64,
-    ri_hist: &HashMap<u64, (u64, HashMap<u64, (u64, u64)>)>,
-) {
-    let ppuc_vec = get_ppuc(ref_id, base_lease, ri_hist);
-    if let Some(&highest_ppuc) = ppuc_vec
-        .iter()
-        .max_by(|a, b| a.ppuc.partial_cmp(&b.ppuc).unwrap())
-    {
-        ppuc_tree.push(highest_ppuc);
-    }
-}
-
-fn marginal_utility_cost(
+//marginal utility of a reference's lease over the base lease, summed over all sets
+fn phase_ref_utility(
+    cost_index: &CostIndex,
+    ref_id: RefKey,
     lease: u64,
     base_lease: u64,
-    ri_hist: &HashMap<u64, (u64, HashMap<u64, (u64, u64)>)>,
+    num_sets: u64,
 ) -> f64 {
-    // If the base lease is zero, return 0 to avoid division by zero
-    if base_lease == 0 {
+    let (hits, cost) = (0..num_sets)
+        .map(|set| cost_index.marginal_hits_cost(ref_id.with_set(set), base_lease, lease))
+        .fold((0, 0), |acc, (hits, cost)| (acc.0 + hits, acc.1 + cost));
+    if cost == 0 {
         return 0.0;
     }
+    hits as f64 / cost as f64
+}
 
-    let mut hits = 0;
-    let mut cost = 0;
-    let mut bhits = 0;
-    let mut bcost = 0;
+//lease table entries of each phase: references with a lease other than the base lease, or a
+//dual lease. Single leases are also kept ordered by marginal utility for eviction
+#[derive(Default)]
+struct TableEntries {
+    per_phase: BTreeMap<u64, u64>,
+    //(phase, utility bits, reference); utilities are non-negative, so their bits sort like them
+    by_utility: BTreeSet<(u64, u64, RefKey)>,
+    utility: BTreeMap<RefKey, u64>,
+}
 
-    // Iterate through the reuse interval histogram (RI histogram)
-    for (ri, (count, _)) in ri_hist.iter() {
-        if *ri <= lease {
-            hits += *count;
-            cost += *count * *ri;
-        } else {
-            cost += *count * lease;
-        }
+impl TableEntries {
+    fn count(&self, phase: u64) -> u64 {
+        *self.per_phase.get(&phase).unwrap_or(&0)
+    }
 
-        if *ri <= base_lease {
-            bhits += *count;
-            bcost += *count * *ri;
-        } else {
-            bcost += *count * base_lease;
+    fn add(&mut self, phase: u64) {
+        *self.per_phase.entry(phase).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, phase: u64) {
+        *self.per_phase.get_mut(&phase).unwrap() -= 1;
+    }
+
+    //a reference holds a single lease with the given utility
+    fn set_single(&mut self, ref_id: RefKey, utility: f64) {
+        self.unset(ref_id);
+        let bits = utility.to_bits();
+        self.utility.insert(ref_id, bits);
+        self.by_utility.insert((ref_id.phase, bits, ref_id));
+    }
+
+    //a reference no longer holds a single lease it could be evicted from
+    fn unset(&mut self, ref_id: RefKey) {
+        if let Some(bits) = self.utility.remove(&ref_id) {
+            self.by_utility.remove(&(ref_id.phase, bits, ref_id));
         }
     }
 
-    (hits - bhits) as f64 / (cost - bcost) as f64
+    //the single lease of a phase, or of any phase, with the lowest utility
+    fn lowest_utility(
+        &self,
+        phase: Option<u64>,
+        skip: impl Fn(&RefKey) -> bool,
+    ) -> Option<(RefKey, f64)> {
+        let lowest = match phase {
+            Some(phase) => self
+                .by_utility
+                .range((phase, 0, RefKey::default())..)
+                .take_while(|(entry_phase, _, _)| *entry_phase == phase)
+                .find(|(_, _, key)| !skip(key)),
+            None => self
+                .by_utility
+                .iter()
+                .filter(|(_, _, key)| !skip(key))
+                .min_by_key(|(_, bits, _)| *bits),
+        };
+        lowest.map(|&(_, bits, key)| (key, f64::from_bits(bits)))
+    }
 }
 
-fn calculate_cost(
-    lease: &u64,
-    base_lease: u64,
-    ri_hist: &HashMap<u64, (u64, HashMap<u64, (u64, u64)>)>,
-) -> u64 {
+//an eviction waiting on the new lease it makes room for
+struct Eviction {
+    victim: RefKey,
+    victim_lease: u64,
+    //(phase, set, cost) given back to the budgets
+    freed: Vec<(u64, u64, u64)>,
+}
+
+impl Eviction {
+    fn undo(
+        self,
+        cost_per_phase: &mut BTreeMap<u64, BTreeMap<u64, u64>>,
+        leases: &mut BTreeMap<RefKey, u64>,
+    ) {
+        for (phase, set, cost) in self.freed {
+            *cost_per_phase
+                .get_mut(&phase)
+                .unwrap()
+                .get_mut(&set)
+                .unwrap() += cost;
+        }
+        leases.insert(self.victim, self.victim_lease);
+    }
+}
+
+fn calculate_cost(lease: &u64, base_lease: u64, ri_hist: &RIHist) -> u64 {
     // Example logic: Replace this with the actual cost calculation logic
     let mut cost = 0;
     for (ri, (count, _)) in ri_hist.iter() {
@@ -0,0 +1,53 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Fixed-width records of `u64` words written out to a temporary file, removed once dropped.
+/// Used to keep partial results of a pass over the trace within the memory limit.
+pub struct SpillRun {
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpillRun {
+    pub fn write<const N: usize>(records: impl IntoIterator<Item = [u64; N]>) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "lease_gen_spill_{}_{}.bin",
+            std::process::id(),
+            SPILL_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut writer = BufWriter::new(File::create(&path).unwrap());
+        let mut len = 0;
+        for record in records {
+            for word in record {
+                writer.write_all(&word.to_le_bytes()).unwrap();
+            }
+            len += 1;
+        }
+        writer.flush().unwrap();
+        Self { path, len }
+    }
+
+    /// The records in the order they were written.
+    pub fn read<const N: usize>(&self) -> impl Iterator<Item = [u64; N]> + '_ {
+        let mut reader = BufReader::new(File::open(&self.path).unwrap());
+        (0..self.len).map(move |_| {
+            let mut record = [0u64; N];
+            for word in record.iter_mut() {
+                let mut bytes = [0u8; 8];
+                reader.read_exact(&mut bytes).unwrap();
+                *word = u64::from_le_bytes(bytes);
+            }
+            record
+        })
+    }
+}
+
+impl Drop for SpillRun {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -1,6 +1,6 @@
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
+    use std::collections::BTreeMap;
 
     use crate::helpers::*;
     use crate::io::debug::*;
@@ -15,97 +15,1758 @@ mod tests {
         assert_eq!(binary_search(&a, 1025), None);
     }
 
+    #[test]
+    fn fixed_alpha_never_overallocates() {
+        for width in [1, 4, 9, 16] {
+            for cost in [1u64, 3, 7, 255, 1 << 20, u64::MAX / 3] {
+                for remaining in [0u64, 1, 2, cost / 3, cost - 1, cost, cost + 1] {
+                    let alpha = fixed_alpha(remaining, cost, width);
+                    assert!(alpha <= 1 << width);
+                    assert!(scale_by_alpha(cost, alpha, width) <= remaining);
+                }
+            }
+        }
+        assert_eq!(fixed_alpha(512, 1024, 9), 256);
+        assert_eq!(scale_by_alpha(1024, 256, 9), 512);
+        assert_eq!(alpha_to_f64(256, 9), 0.5);
+        assert!(!is_meaningful_alpha(0, 9));
+        assert!(is_meaningful_alpha(1, 9));
+    }
+
+    #[test]
+    fn per_entry_layout_holds_several_dual_leases() {
+        use crate::cli::Cli;
+        use crate::io::{LeaseLayout, build_lease_image};
+        let cli = Cli {
+            llt_size: 4,
+            mem_size: 1024,
+            max_dual_leases_per_phase: 2,
+            lease_layout: LeaseLayout::PerEntry,
+            ..Cli::default()
+        };
+        let lease_vector = vec![
+            (0, 0x100, 4, 9, 0.25),
+            (0, 0x104, 2, 0, 1.0),
+            (0, 0x108, 3, 7, 0.5),
+        ];
+        let image = build_lease_image(lease_vector, &BTreeMap::new(), &cli, 4).unwrap();
+        //1024 bytes hold 16 + 4 * 4 words per phase, so only 4 phases fit
+        assert_eq!(image.len(), 4);
+        let phase = &image[0];
+        assert_eq!(phase.header.len(), 16);
+        assert_eq!(phase.header[3].0, 3);
+        let columns: Vec<&str> = phase.columns.iter().map(|column| column.0).collect();
+        assert_eq!(
+            columns,
+            [
+                "reference address",
+                "lease0 value",
+                "lease1 value",
+                "lease0 probability"
+            ]
+        );
+        assert_eq!(phase.columns[2].1, [9, 0, 7, 0]);
+        assert_eq!(phase.columns[3].1, [127, 511, 255, 0]);
+    }
+
+    #[test]
+    fn deduplicated_layout_shares_identical_tables() {
+        use crate::cli::Cli;
+        use crate::io::{LeaseLayout, build_lease_image, deduplicate_tables, write_lease_bin_file};
+        let cli = Cli {
+            llt_size: 4,
+            mem_size: 1024,
+            lease_layout: LeaseLayout::Deduplicated,
+            ..Cli::default()
+        };
+        //30 phases, while 1024 bytes hold only 10 tables of 16 + 2 * 4 words
+        let lease_vector: Vec<(u64, u64, u64, u64, f64)> = (0..30)
+            .map(|phase| match phase % 2 {
+                0 => (phase, 0x100, 4, 0, 1.0),
+                _ => (phase, 0x200, 8, 0, 1.0),
+            })
+            .collect();
+        let image = build_lease_image(lease_vector, &BTreeMap::new(), &cli, 4).unwrap();
+        assert_eq!(image.len(), 30);
+        let (phase_map, tables) = deduplicate_tables(&image);
+        assert_eq!(tables.len(), 2);
+        assert!(
+            phase_map
+                .iter()
+                .enumerate()
+                .all(|(phase, &table)| table == phase as u64 % 2)
+        );
+
+        let path = std::env::temp_dir().join(format!("lease_gen_dedup_{}.bin", std::process::id()));
+        write_lease_bin_file(&image, cli.lease_layout, path.to_str().unwrap().to_string());
+        let words: Vec<u32> = std::fs::read(&path)
+            .unwrap()
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        //a 32-word phase map, then the two tables
+        assert_eq!(words.len(), 32 + 2 * 24);
+        assert_eq!(&words[..4], &[30, 2, 0, 1]);
+    }
+
+    #[test]
+    fn lease_image_is_written_from_the_lease_results() {
+        use crate::cli::Cli;
+        use crate::io::{LeaseLayout, dump_lease_image};
+        use crate::lease_gen::{LeaseResults, RefKey};
+        let cli = Cli {
+            llt_size: 4,
+            mem_size: 1024,
+            lease_layout: LeaseLayout::Deduplicated,
+            ..Cli::default()
+        };
+        let leases = (0..3)
+            .map(|phase| (RefKey::new(phase, 0x100, 0), 4))
+            .collect();
+        let mut lease_results = LeaseResults::new(leases, BTreeMap::new(), BTreeMap::new(), 0);
+        lease_results.phase_default_leases.insert(1, 2);
+
+        let dir = std::env::temp_dir().join(format!("lease_gen_image_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap();
+        dump_lease_image(&lease_results, &cli, 4, None, dir).unwrap();
+        let hex_words = || -> Vec<u32> {
+            std::fs::read_to_string(format!("{}/lease.hex", dir))
+                .unwrap()
+                .lines()
+                .map(|word| u32::from_str_radix(word, 16).unwrap())
+                .collect()
+        };
+        let words = hex_words();
+        assert!(std::fs::metadata(format!("{}/lease.c", dir)).is_ok());
+        assert_eq!(
+            std::fs::read(format!("{}/lease.bin", dir)).unwrap().len(),
+            4 * words.len()
+        );
+        //phase 1 has its own default lease, so its table is not shared
+        assert_eq!(&words[..5], &[3, 2, 0, 1, 0]);
+
+        //leases of merged phases: the phase map sends every original phase to its merged table
+        let mapping = BTreeMap::from([(0, 0), (1, 1), (2, 1), (3, 0)]);
+        dump_lease_image(&lease_results, &cli, 4, Some(&mapping), dir).unwrap();
+        assert_eq!(&hex_words()[..6], &[4, 2, 0, 1, 1, 0]);
+        //other layouts have no phase map to record the merge in
+        let merging = |lease_layout| Cli {
+            merge_phases: true,
+            lease_layout,
+            ..Cli::default()
+        };
+        assert!(merging(LeaseLayout::Single).validate().is_err());
+        merging(LeaseLayout::Deduplicated).validate().unwrap();
+
+        //a default lease the counters cannot hold is refused, not written
+        let coarse = Cli {
+            llt_size: 4,
+            mem_size: 1024,
+            lease_layout: LeaseLayout::Deduplicated,
+            lease_granularity: 2,
+            ..Cli::default()
+        };
+        lease_results.phase_default_leases.insert(1, 3);
+        assert_eq!(
+            dump_lease_image(&lease_results, &coarse, 4, None, dir).unwrap_err(),
+            "Default lease 3 of phase 1 does not fit the lease counters!"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn packed_layout_sizes_tables_to_their_references() {
+        use crate::cli::Cli;
+        use crate::io::{
+            LeaseLayout, build_lease_image, write_lease_bin_file, write_lease_hex_file,
+        };
+        let cli = Cli {
+            llt_size: 4,
+            mem_size: 1024,
+            lease_layout: LeaseLayout::Packed,
+            ..Cli::default()
+        };
+        //phase 1 has no leases and gets a one-entry dummy table
+        let lease_vector = vec![
+            (0, 0x100, 4, 0, 1.0),
+            (0, 0x104, 2, 0, 1.0),
+            (0, 0x108, 3, 0, 1.0),
+            (2, 0x200, 8, 0, 1.0),
+        ];
+        let image = build_lease_image(lease_vector, &BTreeMap::new(), &cli, 4).unwrap();
+        assert_eq!(image.len(), 3);
+        let lengths: Vec<u64> = image.iter().map(|phase| phase.header[3].0).collect();
+        let offsets: Vec<u64> = image.iter().map(|phase| phase.header[5].0).collect();
+        assert_eq!(lengths, [3, 1, 1]);
+        assert_eq!(offsets, [48, 54, 56]);
+        assert!(image.iter().all(|phase| phase.header.len() == 16));
+        assert_eq!(image[0].columns[0].1, [0x100, 0x104, 0x108]);
+
+        let dir = std::env::temp_dir();
+        let bin_path = dir.join(format!("lease_gen_packed_{}.bin", std::process::id()));
+        let hex_path = dir.join(format!("lease_gen_packed_{}.hex", std::process::id()));
+        write_lease_bin_file(
+            &image,
+            cli.lease_layout,
+            bin_path.to_str().unwrap().to_string(),
+        );
+        write_lease_hex_file(
+            &image,
+            cli.lease_layout,
+            hex_path.to_str().unwrap().to_string(),
+        );
+        let words: Vec<u32> = std::fs::read(&bin_path)
+            .unwrap()
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        let hex_words: Vec<u32> = std::fs::read_to_string(&hex_path)
+            .unwrap()
+            .lines()
+            .map(|line| u32::from_str_radix(line, 16).unwrap())
+            .collect();
+        std::fs::remove_file(&bin_path).unwrap();
+        std::fs::remove_file(&hex_path).unwrap();
+        //three headers, then two words per entry
+        assert_eq!(words.len(), 48 + 2 * 5);
+        assert_eq!(hex_words, words);
+        assert_eq!(&words[48..54], &[0x100, 0x104, 0x108, 4, 2, 3]);
+        assert_eq!(&words[56..58], &[0x200, 8]);
+    }
+
+    #[test]
+    fn header_default_lease_is_configurable() {
+        use crate::cli::Cli;
+        use crate::io::build_lease_image;
+        let lease_vector = vec![(0, 0x100, 4, 0, 1.0)];
+        let default_word = |cli: &Cli| {
+            build_lease_image(lease_vector.clone(), &BTreeMap::new(), cli, 4).unwrap()[0].header[0]
+                .0
+        };
+        assert_eq!(default_word(&Cli::default()), 1);
+        let bypass = Cli {
+            bypass: true,
+            ..Cli::default()
+        };
+        assert_eq!(default_word(&bypass), 0);
+        let explicit = Cli {
+            default_lease: Some(3),
+            ..Cli::default()
+        };
+        assert_eq!(default_word(&explicit), 3);
+        //a default lease the counters round away would start the allocator off the grid
+        let coarse = Cli {
+            default_lease: Some(3),
+            lease_granularity: 2,
+            ..Cli::default()
+        };
+        assert!(coarse.validate().is_err());
+    }
+
+    #[test]
+    fn knapsack_pruning_trades_dual_slots_for_entries() {
+        let reference = |reference| RefKey::new(0, reference, 0);
+        //two dual leases whose short leases alone gain nothing, and a single lease gaining 7
+        let lease_hits = BTreeMap::from([
+            (reference(1), BTreeMap::from([(10, 20)])),
+            (reference(2), BTreeMap::from([(10, 18)])),
+            (reference(3), BTreeMap::from([(5, 7)])),
+        ]);
+        let lease_results = LeaseResults::new(
+            BTreeMap::from([(reference(1), 2), (reference(2), 2), (reference(3), 5)]),
+            BTreeMap::from([(reference(1), (0.5, 10)), (reference(2), (0.5, 10))]),
+            lease_hits,
+            100,
+        );
+        let ri_hists = RIHists::new(BTreeMap::new());
+        let pruned = |strategy| {
+            let mut pruned = lease_results.clone();
+            pruned.prune_leases_to_fit_llt(&ri_hists, 2, strategy, 1);
+            pruned
+        };
+        //the greedy keeps the two largest gains, but the second dual lease has no slot left
+        let greedy = pruned(PruneStrategy::PredictedHits);
+        assert_eq!(
+            greedy
+                .leases
+                .keys()
+                .map(|key| key.reference)
+                .collect::<Vec<_>>(),
+            [1, 2]
+        );
+        //the knapsack gives the dual slot to the larger dual lease and the entry to the single lease
+        let knapsack = pruned(PruneStrategy::Knapsack);
+        assert_eq!(
+            knapsack
+                .leases
+                .keys()
+                .map(|key| key.reference)
+                .collect::<Vec<_>>(),
+            [1, 3]
+        );
+        assert_eq!(
+            knapsack.dual_leases.keys().collect::<Vec<_>>(),
+            [&reference(1)]
+        );
+        assert_eq!(knapsack.predicted_hits(9), 17);
+        assert_eq!(greedy.predicted_hits(9), 10);
+    }
+
+    #[test]
+    fn quantized_alpha_is_hardware_exact() {
+        let width = 9;
+        for (remaining_budget, cost) in [
+            (1, 1000),
+            (1, 10),
+            (3333, 10000),
+            (1, 2),
+            (77, 100),
+            (999, 1000),
+        ] {
+            let alpha = alpha_to_f64(fixed_alpha(remaining_budget, cost, width), width);
+            assert!(alpha <= remaining_budget as f64 / cost as f64);
+            let percentage = 1.0 - alpha;
+            assert_eq!(
+                crate::io::hardware_percentage(percentage, width),
+                percentage
+            );
+        }
+        assert_eq!(fixed_alpha(2, 1, width), 1 << width);
+    }
+
     #[test]
     fn test_process_sample_head_cost() {
-        let mut ri_hists = HashMap::new();
-        process_sample_cost(&mut ri_hists, 1, 12, 20, (100000, 1), true);
+        let mut ri_hists = BTreeMap::new();
+        let key = RefKey::new(0, 1, 0);
+        process_sample_cost(&mut ri_hists, key, 12, 20, (100000, 1), true);
         let hist_struct = RIHists::new(ri_hists);
 
-        assert_eq!(hist_struct.get_ref_ri_count(1, 12), 1);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 12, 0), (12, 0));
+        assert_eq!(hist_struct.get_ref_ri_count(key, 12), 1);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 12, 0), (12, 0));
     }
 
     #[test]
     fn test_cross_phase_head_cost() {
-        let mut ri_hists = HashMap::new();
-        process_sample_cost(&mut ri_hists, 1, 12, 8, (10, 1), true); //reference 1, phase 0
+        let mut ri_hists = BTreeMap::new();
+        let key = RefKey::new(0, 1, 0);
+        process_sample_cost(&mut ri_hists, key, 12, 8, (10, 1), true); //reference 1, phase 0
         let hist_struct = RIHists::new(ri_hists);
 
-        assert_eq!(hist_struct.get_ref_ri_count(1, 12), 1);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 12, 0), (2, 0));
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 12, 1), (10, 0));
+        assert_eq!(hist_struct.get_ref_ri_count(key, 12), 1);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 12, 0), (2, 0));
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 12, 1), (10, 0));
     }
 
     #[test]
     fn test_get_ppuc() {
-        let mut ri_hist = HashMap::new();
+        let mut ri_hist = BTreeMap::new();
 
-        ri_hist.insert(3, (1, HashMap::new()));
-        ri_hist.insert(5, (7, HashMap::new()));
-        ri_hist.insert(17, (4, HashMap::new()));
-        ri_hist.insert(19, (3, HashMap::new()));
-        let ppucs = get_ppuc(1, 0, &ri_hist);
+        ri_hist.insert(3, (1, BTreeMap::new()));
+        ri_hist.insert(5, (7, BTreeMap::new()));
+        ri_hist.insert(17, (4, BTreeMap::new()));
+        ri_hist.insert(19, (3, BTreeMap::new()));
+        let ppucs = get_ppuc(RefKey::new(0, 1, 0), 0, &ri_hist);
         println!("{:?}", ppucs); //According to Asplos19, the numbers are [.02,.11,.08,.09];
     }
 
+    #[test]
+    fn lease_grid_rounds_up_and_clamps() {
+        let grid = LeaseGrid {
+            max_lease: 100,
+            granularity: 3,
+        };
+        assert_eq!(grid.representable(0), 0);
+        assert_eq!(grid.representable(1), 8);
+        assert_eq!(grid.representable(8), 8);
+        assert_eq!(grid.representable(9), 16);
+        assert_eq!(grid.representable(200), 96);
+        assert_eq!(grid.counter_value(96), 12);
+
+        let mut ri_hist = BTreeMap::new();
+        ri_hist.insert(3, (1, BTreeMap::new()));
+        ri_hist.insert(5, (7, BTreeMap::new()));
+        ri_hist.insert(17, (4, BTreeMap::new()));
+        ri_hist.insert(190, (3, BTreeMap::new()));
+        let ppucs = get_ppuc_on_grid(RefKey::new(0, 1, 0), 0, &ri_hist, grid);
+        let leases: Vec<u64> = ppucs.iter().map(|ppuc| ppuc.lease).collect();
+        assert_eq!(leases, [8, 24, 96]);
+        let hits: Vec<u64> = ppucs.iter().map(|ppuc| ppuc.new_hits).collect();
+        //the RI of 190 is past the longest lease and never hits
+        assert_eq!(hits, [8, 12, 12]);
+        //the exact grid gives the same candidates as get_ppuc
+        let exact = get_ppuc_on_grid(RefKey::new(0, 1, 0), 0, &ri_hist, LeaseGrid::default());
+        assert_eq!(exact, get_ppuc(RefKey::new(0, 1, 0), 0, &ri_hist));
+    }
+
+    #[test]
+    fn ri_binning_keeps_counts_and_bounds_the_error() {
+        use crate::binning::*;
+        let mut ri_hist = BTreeMap::new();
+        for ri in 1..=200u64 {
+            ri_hist.insert(ri, (ri % 7 + 1, BTreeMap::from([(0, (ri, 0))])));
+        }
+        let ri_hists = RIHists::new(BTreeMap::from([(RefKey::new(0, 1, 0), ri_hist)]));
+        let total = |ri_hists: &RIHists| -> (u64, u64) {
+            ri_hists
+                .ri_hists
+                .values()
+                .flat_map(|h| h.values())
+                .fold((0, 0), |acc, (count, costs)| {
+                    (acc.0 + count, acc.1 + costs[&0].0)
+                })
+        };
+
+        let log = bin_ri_hists(&ri_hists, RIBinning::Log, 4);
+        assert_eq!(total(&log), total(&ri_hists));
+        assert!(num_entries(&log) < num_entries(&ri_hists));
+        assert!(max_relative_error(&ri_hists, &log) <= 2f64.powf(0.25) - 1.0);
+
+        let quantile = bin_ri_hists(&ri_hists, RIBinning::Quantile, 10);
+        assert_eq!(total(&quantile), total(&ri_hists));
+        assert!(num_entries(&quantile) <= 10);
+
+        let exact = bin_ri_hists(&ri_hists, RIBinning::Exact, 10);
+        assert_eq!(num_entries(&exact), num_entries(&ri_hists));
+        assert_eq!(max_relative_error(&ri_hists, &exact), 0.0);
+    }
+
+    #[test]
+    fn lease_image_rejects_leases_the_counters_cannot_hold() {
+        use crate::cli::Cli;
+        use crate::io::build_lease_image;
+        let cli = Cli {
+            lease_granularity: 2,
+            ..Cli::default()
+        };
+        let err =
+            build_lease_image(vec![(0, 0x100, 6, 0, 1.0)], &BTreeMap::new(), &cli, 4).unwrap_err();
+        assert_eq!(
+            err,
+            "Lease 6 of reference 100 in phase 0 does not fit the lease counters!"
+        );
+        //a per-phase default lease is checked the same way
+        let err =
+            build_lease_image(vec![(0, 0x100, 4, 0, 1.0)], &[(0, 3)].into(), &cli, 4).unwrap_err();
+        assert_eq!(
+            err,
+            "Default lease 3 of phase 0 does not fit the lease counters!"
+        );
+
+        //4 scopes are phases 0 to 3
+        let cli = Cli {
+            llt_size: 4,
+            mem_size: 1024,
+            ..Cli::default()
+        };
+        build_lease_image(vec![(3, 0x100, 4, 0, 1.0)], &BTreeMap::new(), &cli, 4).unwrap();
+        let err =
+            build_lease_image(vec![(4, 0x100, 4, 0, 1.0)], &BTreeMap::new(), &cli, 4).unwrap_err();
+        assert_eq!(err, "phases cannot fit in specified 1024 byte memory");
+    }
+
     #[test]
     fn test_process_sample_tail_cost() {
-        let mut ri_hists = HashMap::new();
+        let mut ri_hists = BTreeMap::new();
+        let key = RefKey::new(0, 1, 0);
         let ri_short = 10;
         let ri_long = 100;
         let ri_very_long = 1000;
-        process_sample_cost(&mut ri_hists, 1, ri_short, 10, (10000, 1), true);
-        process_sample_cost(&mut ri_hists, 1, ri_short, 20, (10000, 1), true);
-        process_sample_cost(&mut ri_hists, 1, ri_long, 200, (10000, 1), true);
-        process_sample_cost(&mut ri_hists, 1, ri_very_long, 2200, (10000, 1), true);
+        process_sample_cost(&mut ri_hists, key, ri_short, 10, (10000, 1), true);
+        process_sample_cost(&mut ri_hists, key, ri_short, 20, (10000, 1), true);
+        process_sample_cost(&mut ri_hists, key, ri_long, 200, (10000, 1), true);
+        process_sample_cost(&mut ri_hists, key, ri_very_long, 2200, (10000, 1), true);
 
-        process_sample_cost(&mut ri_hists, 1, ri_short, 10, (10000, 1), false);
-        process_sample_cost(&mut ri_hists, 1, ri_short, 20, (10000, 1), false);
-        process_sample_cost(&mut ri_hists, 1, ri_long, 200, (10000, 1), false);
-        process_sample_cost(&mut ri_hists, 1, ri_very_long, 2200, (10000, 1), false);
+        process_sample_cost(&mut ri_hists, key, ri_short, 10, (10000, 1), false);
+        process_sample_cost(&mut ri_hists, key, ri_short, 20, (10000, 1), false);
+        process_sample_cost(&mut ri_hists, key, ri_long, 200, (10000, 1), false);
+        process_sample_cost(&mut ri_hists, key, ri_very_long, 2200, (10000, 1), false);
 
         let hist_struct = RIHists::new(ri_hists);
 
         // print_ri_hists(&hist_struct);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, ri_short, 0).1, 20);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, ri_long, 0).1, 100);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, ri_very_long, 0).1, 0);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, ri_short, 0).1, 20);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, ri_long, 0).1, 100);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, ri_very_long, 0).1, 0);
     }
 
-    //pub fn process_sample_head_cost(ri_hists: &mut HashMap<u64,HashMap<u64,(u64,HashMap<u64,(u64,u64)>)>>,
+    //pub fn process_sample_head_cost(ri_hists: &mut BTreeMap<u64,BTreeMap<u64,(u64,BTreeMap<u64,(u64,u64)>)>>,
     //p                 phase_id_ref: u64,
     //p              ri: u64,
     //p           use_time: u64,
     //p        next_phase_tuple: (u64,u64)){
     #[test]
     fn tail_cost_cross_phase() {
-        let mut ri_hists = HashMap::new();
-        process_sample_cost(&mut ri_hists, 1, 100, 70, (100, 1), true);
-        process_sample_cost(&mut ri_hists, 1, 50, 70, (100, 1), true);
+        let mut ri_hists = BTreeMap::new();
+        let key = RefKey::new(0, 1, 0);
+        process_sample_cost(&mut ri_hists, key, 100, 70, (100, 1), true);
+        process_sample_cost(&mut ri_hists, key, 50, 70, (100, 1), true);
 
-        process_sample_cost(&mut ri_hists, 1, 100, 70, (100, 1), false);
-        process_sample_cost(&mut ri_hists, 1, 50, 70, (100, 1), false);
+        process_sample_cost(&mut ri_hists, key, 100, 70, (100, 1), false);
+        process_sample_cost(&mut ri_hists, key, 50, 70, (100, 1), false);
 
         let hist_struct = RIHists::new(ri_hists);
 
         print_ri_hists(&hist_struct);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 50, 0).1, 30);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 50, 1).1, 20);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 50, 0).1, 30);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 50, 1).1, 20);
     }
 
     #[test]
     fn negative_ri() {
-        let mut ri_hists = HashMap::new();
-        process_sample_cost(&mut ri_hists, 1, 50, 80, (100, 1), true);
-        process_sample_cost(&mut ri_hists, 1, i32::max as u64, 90, (100, 1), true);
+        let mut ri_hists = BTreeMap::new();
+        let key = RefKey::new(0, 1, 0);
+        process_sample_cost(&mut ri_hists, key, 50, 80, (100, 1), true);
+        process_sample_cost(&mut ri_hists, key, i32::max as u64, 90, (100, 1), true);
 
-        process_sample_cost(&mut ri_hists, 1, 50, 80, (100, 1), false);
-        process_sample_cost(&mut ri_hists, 1, i32::max as u64, 90, (100, 1), false);
+        process_sample_cost(&mut ri_hists, key, 50, 80, (100, 1), false);
+        process_sample_cost(&mut ri_hists, key, i32::max as u64, 90, (100, 1), false);
         let hist_struct = RIHists::new(ri_hists);
 
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 50, 0).0, 20);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 50, 1).0, 30);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 50, 0).1, 10);
-        assert_eq!(hist_struct.get_ref_ri_phase_cost(1, 50, 1).1, 40);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 50, 0).0, 20);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 50, 1).0, 30);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 50, 0).1, 10);
+        assert_eq!(hist_struct.get_ref_ri_phase_cost(key, 50, 1).1, 40);
+    }
+}
+
+#[cfg(test)]
+mod test_traces {
+    use crate::cli::Cli;
+    use crate::io::build_ri_hists_from_iter;
+    use crate::lease_gen::{LeaseOperationContext, RIHists};
+    use crate::utils::*;
+    use std::collections::BTreeMap;
+
+    // small LCG so the trace is reproducible without pulling in a rand dependency
+    pub fn synthetic_trace(len: usize, num_phases: usize, num_refs: u32) -> Vec<(u32, i32, u32)> {
+        let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+        let mut next = move || {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) as u32
+        };
+        (0..len)
+            .map(|i| {
+                let phase = (i * num_phases / len) as u32;
+                let reference = next() % num_refs;
+                let ri = (next() % 64 + 1) as i32;
+                let tag = next() % 256;
+                ((phase << 24) | reference, ri, tag)
+            })
+            .collect()
+    }
+
+    //histograms of a trace on the cache `cli` describes, for tests to borrow a context from
+    pub struct TraceHists {
+        pub ri_hists: RIHists,
+        pub samples_per_phase: BTreeMap<u64, u64>,
+        pub first_misses: usize,
+        pub sample_rate: u64,
+        pub set_mask: u32,
+        pub max_scopes: u64,
+    }
+
+    impl TraceHists {
+        pub fn new(cli: &Cli, cshel: bool, trace: &[(u32, i32, u32)]) -> Self {
+            let num_ways = calculate_num_ways(cli.set_associativity, cli.cache_size);
+            let set_mask = calculate_set_mask(cli.cache_size, num_ways);
+            let (ri_hists, samples_per_phase, first_misses, sample_rate) =
+                build_ri_hists_from_iter(trace, cshel, set_mask, 1, None, None);
+            Self {
+                ri_hists,
+                samples_per_phase,
+                first_misses,
+                sample_rate,
+                set_mask,
+                max_scopes: calculate_max_scopes(cli.mem_size, cli.llt_size),
+            }
+        }
+
+        pub fn context(&self) -> LeaseOperationContext<'_> {
+            self.context_on(&self.ri_hists)
+        }
+
+        //the same trace and cache, allocating on other histograms
+        pub fn context_on<'a>(&'a self, ri_hists: &'a RIHists) -> LeaseOperationContext<'a> {
+            LeaseOperationContext {
+                ri_hists,
+                sample_rate: self.sample_rate,
+                samples_per_phase: &self.samples_per_phase,
+                set_mask: self.set_mask,
+                misses_from_first_access: self.first_misses,
+                max_scopes: self.max_scopes,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod determinism_tests {
+    use super::test_traces::*;
+    use crate::cli::Cli;
+    use crate::io::dump_leases;
+    use crate::shel_cshel::shel_cshel;
+
+    fn leases_file(cli: &Cli, cshel: bool, trace: &[(u32, i32, u32)], run: usize) -> Vec<u8> {
+        let hists = TraceHists::new(cli, cshel, trace);
+        let context = hists.context();
+        let mut lease_results = shel_cshel(cshel, cli, &context).unwrap();
+        lease_results.prune_leases_to_fit_llt(
+            context.ri_hists,
+            cli.llt_size,
+            cli.prune_strategy,
+            cli.max_dual_leases_per_phase,
+        );
+
+        let out_dir = std::env::temp_dir().join(format!(
+            "lease_gen_determinism_{}_{}_{}",
+            std::process::id(),
+            cshel,
+            run
+        ));
+        std::fs::create_dir_all(&out_dir).unwrap();
+        dump_leases(
+            lease_results,
+            out_dir.to_str().unwrap(),
+            hists.sample_rate,
+            hists.first_misses,
+            cli.discretize_width,
+        );
+        let leases = std::fs::read(out_dir.join("leases.txt")).unwrap();
+        std::fs::remove_dir_all(&out_dir).unwrap();
+        leases
+    }
+
+    #[test]
+    fn lease_assignment_is_deterministic() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            ..Cli::default()
+        };
+        for cshel in [false, true] {
+            let first = leases_file(&cli, cshel, &trace, 0);
+            assert!(!first.is_empty());
+            for run in 1..5 {
+                assert_eq!(first, leases_file(&cli, cshel, &trace, run));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod hist_build_tests {
+    use super::test_traces::*;
+    use crate::cost_index::CostIndex;
+    use crate::io::build_ri_hists_from_iter;
+    use crate::lease_gen::{LeaseOperationContext, RefKey, cshel_phase_ref_cost};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn cshel_costs_on_a_fixed_trace() {
+        use crate::io::build_phase_transitions_from_iter;
+        //samples of phase 0 at times 1..=3, phase 1 from 4
+        let a = 0x10;
+        let b = 1 << 24 | 0x20;
+        let trace = vec![
+            (a, 2, 1),
+            (a, 3, 1),
+            (a, 5, 1),
+            (b, 2, 2),
+            (b, 3, 2),
+            (b, 1, 2),
+        ];
+        //transitions follow the phase of each use time (3, 5, 8 in phase 0, then 6, 8, 7 in
+        //phase 1, the last sample at 8 winning), not the sample order
+        assert_eq!(
+            build_phase_transitions_from_iter(&trace),
+            vec![(0, 0), (6, 1)]
+        );
+        let (ri_hists, samples_per_phase, _, _) =
+            build_ri_hists_from_iter(&trace, true, 0, 1, None, None);
+        //the same when every use time and cell is spilled on its own
+        let (spilled, _, _, _) = build_ri_hists_from_iter(&trace, true, 0, 1, Some(1), None);
+        assert_eq!(spilled.ri_hists, ri_hists.ri_hists);
+        let ref_a = RefKey::new(0, 0x10, 0);
+        let heads_tails =
+            |ri: u64, phase: u64| ri_hists.ri_hists[&ref_a][&ri].1.get(&phase).cloned();
+        assert_eq!(heads_tails(2, 0), Some((2, 3)));
+        assert_eq!(heads_tails(3, 0), Some((1, 3)));
+        assert_eq!(heads_tails(5, 0), Some((5, 0)));
+        //the reuses of b all start after the last transition; they stay in phase 1 instead of
+        //underflowing against a made-up transition at the sample time
+        let ref_b = RefKey::new(1, 0x20, 0);
+        for (ri, (_, phase_costs)) in ri_hists.ri_hists[&ref_b].iter() {
+            assert_eq!(phase_costs.keys().collect::<Vec<_>>(), vec![&1]);
+            assert_eq!(phase_costs[&1].0, *ri);
+        }
+
+        let context = LeaseOperationContext {
+            ri_hists: &ri_hists,
+            sample_rate: 1,
+            samples_per_phase: &samples_per_phase,
+            set_mask: 0,
+            misses_from_first_access: 0,
+            max_scopes: 4,
+        };
+        let cost_index = CostIndex::new(true, &context);
+        //(old lease, new lease, phase 0 cost); at an RI a lease pays its heads and that RI's tail
+        //under both rules. Between RIs (4) the exact-RI rule charged heads only: 0 -> 4 cost 3,
+        //less than 0 -> 3, and 3 -> 4 underflowed. It now pays the tail of RI 3 as well.
+        for (old_lease, new_lease, cost) in [(0, 2, 5), (0, 3, 6), (0, 4, 6), (3, 4, 0), (0, 5, 8)]
+        {
+            assert_eq!(
+                cshel_phase_ref_cost(1, 0, ref_a, old_lease, new_lease, &ri_hists),
+                cost
+            );
+            assert_eq!(cost_index.cost(0, ref_a, old_lease, new_lease), cost);
+        }
+    }
+
+    #[test]
+    fn trace_file_and_memory_build_the_same_hists() {
+        use crate::io::{TraceFormat, build_ri_hists};
+        use std::io::Write;
+        let trace = synthetic_trace(3000, 3, 16);
+        let path = std::env::temp_dir().join(format!(
+            "lease_gen_equivalence_{}.bin.zst",
+            std::process::id()
+        ));
+        let mut encoder =
+            zstd::stream::write::Encoder::new(std::fs::File::create(&path).unwrap(), 0).unwrap();
+        for &(phase_id_ref, ri, tag) in &trace {
+            for word in [phase_id_ref, ri as u32, tag] {
+                encoder.write_all(&word.to_le_bytes()).unwrap();
+            }
+        }
+        encoder.finish().unwrap();
+
+        for cshel in [false, true] {
+            let (file_hists, file_samples, file_misses, file_rate) = build_ri_hists(
+                path.to_str().unwrap(),
+                cshel,
+                1,
+                TraceFormat::Packed,
+                1,
+                None,
+                None,
+            );
+            let (iter_hists, iter_samples, iter_misses, iter_rate) =
+                build_ri_hists_from_iter(&trace, cshel, 1, 1, None, None);
+            assert_eq!(file_hists.ri_hists, iter_hists.ri_hists);
+            assert_eq!(file_samples, iter_samples);
+            assert_eq!((file_misses, file_rate), (iter_misses, iter_rate));
+            if cshel {
+                //the tail pass must have run over the file as well
+                let tails = file_hists
+                    .ri_hists
+                    .values()
+                    .flat_map(|hist| hist.values())
+                    .flat_map(|(_, phase_costs)| phase_costs.values())
+                    .map(|(_, tail)| tail)
+                    .sum::<u64>();
+                assert!(tails > 0);
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn threaded_build_matches_a_single_thread() {
+        let trace = synthetic_trace(3000, 3, 16);
+        for cshel in [false, true] {
+            let (hists, samples_per_phase, first_misses, sample_rate) =
+                build_ri_hists_from_iter(&trace, cshel, 3, 1, None, None);
+            for threads in [2, 3, 8] {
+                let (threaded_hists, threaded_samples, threaded_misses, threaded_rate) =
+                    build_ri_hists_from_iter(&trace, cshel, 3, threads, None, None);
+                assert_eq!(hists.ri_hists, threaded_hists.ri_hists);
+                assert_eq!(samples_per_phase, threaded_samples);
+                assert_eq!(
+                    (first_misses, sample_rate),
+                    (threaded_misses, threaded_rate)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn spilled_build_matches_the_nested_hists() {
+        use crate::helpers::binary_search;
+        use crate::io::build_phase_transitions_from_iter;
+        use crate::lease_gen::process_sample_cost;
+        let trace = synthetic_trace(3000, 3, 16);
+        let transitions = build_phase_transitions_from_iter(&trace);
+        for cshel in [false, true] {
+            //the nested maps, built sample by sample
+            let mut nested = BTreeMap::new();
+            for is_head in [true, false] {
+                for (idx, &(phase_id_ref, ri, tag)) in trace.iter().enumerate() {
+                    let key = RefKey::from_phase_id_ref(phase_id_ref, (tag & 3) as u64);
+                    let (ri, use_time) = (ri as u64, (idx + 1) as u64 + ri as u64);
+                    let next_phase_tuple =
+                        binary_search(&transitions, use_time).unwrap_or((use_time + ri + 1, 0));
+                    match cshel {
+                        true => process_sample_cost(
+                            &mut nested,
+                            key,
+                            ri,
+                            use_time,
+                            next_phase_tuple,
+                            is_head,
+                        ),
+                        false if is_head => {
+                            let ri_tuple: &mut (u64, BTreeMap<u64, (u64, u64)>) =
+                                nested.entry(key).or_default().entry(ri).or_default();
+                            ri_tuple.0 += 1;
+                            ri_tuple.1.entry(key.phase).or_insert((0, 0));
+                        }
+                        false => {}
+                    }
+                }
+            }
+            for (threads, memory_limit) in [(1, None), (1, Some(4096)), (3, Some(4096))] {
+                let (ri_hists, _, _, _) =
+                    build_ri_hists_from_iter(&trace, cshel, 3, threads, memory_limit, None);
+                assert_eq!(ri_hists.ri_hists, nested);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod phase_detection_tests {
+    use super::test_traces::*;
+    use crate::io::build_ri_hists_from_iter;
+    use crate::lease_gen::RIHists;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn phase_detection_finds_recurring_working_sets() {
+        use crate::io::TraceFormat;
+        use crate::io::detect_trace_phases_from_iter;
+        use crate::phase_detect::PhaseDetection;
+        //an un-instrumented trace: every sample in phase 0, two working sets taking turns
+        let trace: Vec<(u32, i32, u32)> = synthetic_trace(3000, 1, 8)
+            .into_iter()
+            .enumerate()
+            .map(|(idx, (reference, ri, tag))| match (idx / 1000) % 2 {
+                0 => (reference, ri, tag),
+                _ => (reference + 100, ri, tag),
+            })
+            .collect();
+        let detection = PhaseDetection {
+            window: 100,
+            sensitivity: 0.5,
+            max_phases: TraceFormat::Packed.max_phases(),
+        };
+        let (phases, detected) = detect_trace_phases_from_iter(&trace, detection);
+        assert_eq!(detected.len(), 2);
+        assert_eq!(phases.starts, vec![(1, 0), (1001, 1), (2001, 0)]);
+        assert_eq!(detected[0].segments, vec![(1, 1000), (2001, 3000)]);
+        assert_eq!((detected[0].samples, detected[1].samples), (2000, 1000));
+
+        let (_, samples_per_phase, _, _) =
+            build_ri_hists_from_iter(&trace, false, 1, 1, None, Some(&phases));
+        assert_eq!(
+            samples_per_phase,
+            std::collections::BTreeMap::from([(0, 2000), (1, 1000)])
+        );
+
+        //no more phases than the format's phase ids hold
+        let capped = PhaseDetection {
+            max_phases: 1,
+            ..detection
+        };
+        let (_, detected) = detect_trace_phases_from_iter(&trace, capped);
+        assert_eq!(detected.len(), 1);
+        assert!(TraceFormat::Wide.max_phases() > TraceFormat::Packed.max_phases());
+    }
+
+    #[test]
+    fn phase_merging_pairs_similar_phases() {
+        use crate::phase_merge::{merge_mapping, merge_phases};
+        //phases 0 and 2 share one working set, phases 1 and 3 another with longer RIs
+        let trace: Vec<(u32, i32, u32)> = synthetic_trace(3000, 4, 8)
+            .into_iter()
+            .map(|(phase_id_ref, ri, tag)| match (phase_id_ref >> 24) % 2 {
+                0 => (phase_id_ref, ri, tag),
+                _ => (phase_id_ref + 100, ri * 8, tag),
+            })
+            .collect();
+        let (ri_hists, samples_per_phase, _, _) =
+            build_ri_hists_from_iter(&trace, false, 1, 1, None, None);
+        assert_eq!(merge_mapping(&ri_hists, 4), None);
+
+        let mapping = merge_mapping(&ri_hists, 2).unwrap();
+        assert_eq!(mapping, BTreeMap::from([(0, 0), (1, 1), (2, 0), (3, 1)]));
+        let (merged_hists, merged_samples) = merge_phases(&ri_hists, &samples_per_phase, &mapping);
+        assert_eq!(merged_samples, BTreeMap::from([(0, 1500), (1, 1500)]));
+        let total = |hists: &RIHists| {
+            hists
+                .ri_hists
+                .values()
+                .flat_map(|hist| hist.values())
+                .map(|(count, _)| count)
+                .sum::<u64>()
+        };
+        assert_eq!(total(&merged_hists), total(&ri_hists));
+        assert!(merged_hists.ri_hists.keys().all(|key| key.phase < 2));
+    }
+}
+
+#[cfg(test)]
+mod allocation_tests {
+    use super::test_traces::*;
+    use crate::cli::Cli;
+    use crate::helpers::is_meaningful_alpha;
+    use crate::lease_gen::{LeaseResults, PruneStrategy};
+    use crate::shel_cshel::shel_cshel;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn allocation_respects_llt_size() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            llt_size: 2,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        //checked before pruning: the allocator itself must not overfill a phase's table
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        for &phase in hists.samples_per_phase.keys() {
+            let entries = lease_results
+                .leases
+                .iter()
+                .filter(|(key, lease)| {
+                    key.phase == phase
+                        && (**lease != 1 || lease_results.dual_leases.contains_key(key))
+                })
+                .count() as u64;
+            assert!(
+                entries <= cli.llt_size,
+                "phase {} has {} entries",
+                phase,
+                entries
+            );
+        }
+    }
+
+    #[test]
+    fn allocation_respects_max_dual_leases() {
+        use crate::io::LeaseLayout;
+        let trace = synthetic_trace(2000, 3, 16);
+        //only per-entry tables hold more than one dual lease per phase
+        for (lease_layout, max_duals) in [(LeaseLayout::PerEntry, 2), (LeaseLayout::Single, 1)] {
+            let cli = Cli {
+                cache_size: 8,
+                set_associativity: 2,
+                max_dual_leases_per_phase: 2,
+                lease_layout,
+                ..Cli::default()
+            };
+            assert_eq!(cli.max_dual_leases(), max_duals);
+            let hists = TraceHists::new(&cli, false, &trace);
+            let context = hists.context();
+            let mut lease_results = shel_cshel(false, &cli, &context).unwrap();
+            let most_duals = |lease_results: &LeaseResults| {
+                hists
+                    .samples_per_phase
+                    .keys()
+                    .map(|&phase| {
+                        lease_results
+                            .dual_leases
+                            .keys()
+                            .filter(|key| key.phase == phase)
+                            .count() as u64
+                    })
+                    .max()
+                    .unwrap()
+            };
+            assert!(most_duals(&lease_results) <= max_duals);
+            lease_results.prune_leases_to_fit_llt(
+                &hists.ri_hists,
+                cli.llt_size,
+                cli.prune_strategy,
+                1,
+            );
+            assert!(most_duals(&lease_results) <= 1);
+        }
+    }
+
+    #[test]
+    fn allocation_only_assigns_representable_leases() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 512,
+            set_associativity: 2,
+            max_lease: Some(40),
+            lease_granularity: 2,
+            max_dual_leases_per_phase: 2,
+            lease_layout: crate::io::LeaseLayout::PerEntry,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let grid = cli.lease_grid();
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        assert!(lease_results.leases.values().any(|&lease| lease > 4));
+        for &lease in lease_results.leases.values() {
+            assert!(grid.is_representable(lease), "lease {}", lease);
+        }
+        for &(_, long_lease) in lease_results.dual_leases.values() {
+            assert!(
+                grid.is_representable(long_lease),
+                "long lease {}",
+                long_lease
+            );
+        }
+    }
+
+    #[test]
+    fn shel_skips_dual_leases_that_would_not_be_meaningful() {
+        let trace = synthetic_trace(2000, 3, 16);
+        //on a one-bit alpha grid no dual lease is meaningful, SHEL has to skip every one it tries
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            llt_size: 2,
+            discretize_width: 1,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        for (key, &(alpha, _)) in lease_results.dual_leases.iter() {
+            assert!(
+                alpha < 1.0
+                    && is_meaningful_alpha(
+                        (alpha * (1u64 << cli.discretize_width) as f64) as u64,
+                        cli.discretize_width
+                    ),
+                "reference {} has dual lease alpha {}",
+                key,
+                alpha
+            );
+        }
+        //a skipped candidate gives back the table entry it evicted
+        for &phase in hists.samples_per_phase.keys() {
+            let entries = lease_results
+                .leases
+                .iter()
+                .filter(|(key, lease)| {
+                    key.phase == phase
+                        && (**lease != 1 || lease_results.dual_leases.contains_key(key))
+                })
+                .count() as u64;
+            assert!(entries <= cli.llt_size);
+        }
+    }
+
+    #[test]
+    fn bypass_allocation_starts_from_lease_zero() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            llt_size: 2,
+            bypass: true,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        assert_eq!(lease_results.default_lease, 0);
+        //references without a table entry bypass the cache and get no hits
+        assert!(lease_results.leases.values().any(|&lease| lease == 0));
+        for (key, &lease) in lease_results.leases.iter() {
+            assert!(lease == 0 || lease_results.lease_hits[key].contains_key(&lease));
+        }
+        for &phase in hists.samples_per_phase.keys() {
+            let entries = lease_results
+                .leases
+                .iter()
+                .filter(|(key, lease)| {
+                    key.phase == phase
+                        && (**lease != 0 || lease_results.dual_leases.contains_key(key))
+                })
+                .count() as u64;
+            assert!(entries <= cli.llt_size);
+        }
+    }
+
+    #[test]
+    fn default_lease_is_where_allocation_starts() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            llt_size: 2,
+            default_lease: Some(4),
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        assert_eq!(lease_results.default_lease, 4);
+        //references without a table entry keep the default lease in the hit prediction
+        for &phase in hists.samples_per_phase.keys() {
+            assert_eq!(lease_results.phase_default_lease(phase), 4);
+        }
+        assert!(lease_results.leases.values().all(|&lease| lease >= 4));
+    }
+
+    #[test]
+    fn optimized_default_lease_adds_hits_outside_the_table() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 32,
+            set_associativity: 2,
+            llt_size: 2,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let mut lease_results = shel_cshel(false, &cli, &context).unwrap();
+        lease_results.prune_leases_to_fit_llt(&hists.ri_hists, cli.llt_size, cli.prune_strategy, 1);
+        let before = lease_results.predicted_hits(cli.discretize_width);
+        lease_results.optimize_default_leases(false, &cli, &context);
+        let after = lease_results.predicted_hits(cli.discretize_width);
+        assert_eq!(
+            lease_results.phase_default_leases.len(),
+            hists.samples_per_phase.len()
+        );
+        assert!(after >= before, "{} < {}", after, before);
+    }
+
+    #[test]
+    fn prl_alphas_are_on_the_fixed_point_grid() {
+        use crate::helpers::alpha_to_f64;
+        use crate::io::get_prl_hists;
+        use crate::lease_gen::prl;
+        use std::io::Write;
+        let trace = synthetic_trace(3000, 1, 32);
+        let path =
+            std::env::temp_dir().join(format!("lease_gen_prl_{}.bin.zst", std::process::id()));
+        let mut encoder =
+            zstd::stream::write::Encoder::new(std::fs::File::create(&path).unwrap(), 0).unwrap();
+        for &(phase_id_ref, ri, tag) in &trace {
+            for word in [phase_id_ref, ri as u32, tag] {
+                encoder.write_all(&word.to_le_bytes()).unwrap();
+            }
+        }
+        //binning reads the length of the trace from the last 8 bytes
+        encoder
+            .write_all(&(trace.len() as u64).to_le_bytes())
+            .unwrap();
+        encoder.finish().unwrap();
+        let cli = Cli {
+            cache_size: 8,
+            prl: 4,
+            discretize_width: 4,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let (binned_ris, binned_freqs, bin_width) = get_prl_hists(
+            path.to_str().unwrap(),
+            cli.prl,
+            hists.set_mask,
+            cli.trace_format(),
+        );
+        std::fs::remove_file(&path).unwrap();
+        let lease_results = prl(&cli, &context, bin_width, &binned_ris, &binned_freqs).unwrap();
+        let one = 1u64 << cli.discretize_width;
+        for (key, &(alpha, _)) in lease_results.dual_leases.iter() {
+            let fixed = (alpha * one as f64) as u64;
+            assert_eq!(alpha_to_f64(fixed, cli.discretize_width), alpha);
+            assert!(is_meaningful_alpha(fixed, cli.discretize_width) && fixed < one);
+            let quantization = lease_results.alpha_quantization[key];
+            assert!(quantization.quantized <= quantization.continuous);
+            //the shift is an occupancy of one bin, so no more than the cache holds
+            assert!((0.0..=cli.cache_size as f64).contains(&quantization.occupancy_shift));
+        }
+        assert!(!lease_results.dual_leases.is_empty());
+    }
+
+    #[test]
+    fn packed_tables_share_the_lease_memory() {
+        use crate::io::{LeaseLayout, build_lease_image, lease_vector, packed_words};
+        use crate::lease_gen::get_num_leases_per_phase;
+        let trace = synthetic_trace(2000, 3, 16);
+        //three headers and room for 10 entries; llt_size does not bound packed tables
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            llt_size: 2,
+            mem_size: packed_words(3, 10) * 4,
+            lease_layout: LeaseLayout::Packed,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        //the allocator shares the entries out between the phases itself
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        let entries: BTreeMap<u64, u64> = hists
+            .samples_per_phase
+            .keys()
+            .map(|&phase| {
+                let entries = lease_results
+                    .leases
+                    .iter()
+                    .filter(|(key, lease)| {
+                        key.phase == phase
+                            && (**lease != 1 || lease_results.dual_leases.contains_key(key))
+                    })
+                    .count() as u64;
+                (phase, entries)
+            })
+            .collect();
+        assert!(entries.values().sum::<u64>() <= 10);
+        assert!(entries.values().any(|&entries| entries > cli.llt_size));
+
+        let table_sizes = lease_results.table_sizes_by_marginal_hits(10);
+        assert!(table_sizes.values().sum::<u64>() <= 10);
+        let mut pruned = lease_results.clone();
+        pruned.prune_leases_to_fit_memory(
+            &hists.ri_hists,
+            cli.mem_size,
+            PruneStrategy::Knapsack,
+            cli.max_dual_leases(),
+        );
+        let kept = get_num_leases_per_phase(&pruned.leases);
+        assert!(kept.values().sum::<u64>() <= 10);
+        for (phase, &size) in kept.iter() {
+            assert!(size <= *table_sizes.get(phase).unwrap_or(&0));
+        }
+        //tables longer than llt_size still make an image as long as the memory holds them
+        let image = build_lease_image(
+            lease_vector(&pruned, cli.discretize_width),
+            &BTreeMap::new(),
+            &cli,
+            hists.max_scopes,
+        )
+        .unwrap();
+        assert_eq!(image.len(), 3);
+    }
+}
+
+#[cfg(test)]
+mod capacity_tests {
+    use super::test_traces::*;
+    use crate::cli::Cli;
+    use crate::lease_gen::{LeaseResults, budget_per_cell};
+    use crate::shel_cshel::shel_cshel;
+
+    #[test]
+    fn capacity_schedule_limits_phase_budgets() {
+        use crate::capacity::{CapacitySchedule, parse_capacity_schedule};
+        let path =
+            std::env::temp_dir().join(format!("lease_gen_capacity_{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# phase capacity\n1 2\n2, 1, 1 # one set of phase 2\n",
+        )
+        .unwrap();
+        let schedule = parse_capacity_schedule(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(schedule.phases, [(1, 2)].into());
+        assert_eq!(schedule.sets, [((2, 1), 1)].into());
+
+        let trace = synthetic_trace(3000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 4,
+            ..Cli::default()
+        };
+        let scheduled_cli = Cli {
+            cache_size: 8,
+            set_associativity: 4,
+            capacity_schedule: Some(schedule),
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let schedule = scheduled_cli.capacity_schedule.as_ref().unwrap();
+        assert!(schedule.mismatches(&hists.samples_per_phase, 2).is_empty());
+        let stray = CapacitySchedule {
+            phases: [(1, 2), (7, 2)].into(),
+            sets: [((2, 1), 1), ((2, 2), 1)].into(),
+        };
+        assert_eq!(
+            stray.mismatches(&hists.samples_per_phase, 2),
+            [
+                "phase 7 is not in the trace",
+                "set 2 of phase 2 is not in the cache's 2 sets"
+            ]
+        );
+
+        //two sets: phase 1 gets a block per set, set 1 of phase 2 a single block
+        let num = |phase| hists.samples_per_phase[&phase];
+        let full = |phase| num(phase) * 8 / 2 * hists.sample_rate;
+        assert_eq!(
+            budget_per_cell(&scheduled_cli, &context),
+            [
+                full(0),
+                full(0),
+                num(1) * 2 / 2 * hists.sample_rate,
+                num(1) * 2 / 2 * hists.sample_rate,
+                full(2),
+                num(2) * hists.sample_rate
+            ]
+        );
+
+        //phase 1 fits in less cache, phase 0 is untouched
+        let unscheduled = shel_cshel(false, &cli, &context).unwrap();
+        let scheduled = shel_cshel(false, &scheduled_cli, &context).unwrap();
+        let used = unscheduled.cost_per_cell(false, &cli, &context);
+        let scheduled_used = scheduled.cost_per_cell(false, &scheduled_cli, &context);
+        assert!(scheduled_used[2] + scheduled_used[3] < used[2] + used[3]);
+        assert_eq!(scheduled_used[..2], used[..2]);
+        assert!(
+            scheduled.predicted_hits(cli.discretize_width)
+                < unscheduled.predicted_hits(cli.discretize_width)
+        );
+    }
+
+    #[test]
+    fn set_capacities_split_the_budgets() {
+        use crate::capacity::CapacitySchedule;
+        let trace = synthetic_trace(3000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 4,
+            set_capacities: Some(vec![4, 2]),
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        cli.validate().unwrap();
+        //one capacity per set, none empty or past the ways; ways may be disabled
+        let with_capacities = |capacities: Vec<u64>| Cli {
+            cache_size: 8,
+            set_associativity: 4,
+            set_capacities: Some(capacities),
+            ..Cli::default()
+        };
+        for (capacities, message) in [
+            (vec![8], "gives 1 capacities for a cache with 2 sets"),
+            (vec![4, 0], "gives set 1 no blocks"),
+            (vec![6, 2], "gives set 0 6 blocks, more than its 4 ways"),
+        ] {
+            let err = with_capacities(capacities).validate().unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+            assert!(err.to_string().contains(message));
+        }
+        with_capacities(vec![3, 4]).validate().unwrap();
+
+        let budget = budget_per_cell(&cli, &context);
+        for (idx, &num) in hists.samples_per_phase.values().enumerate() {
+            assert_eq!(budget[2 * idx], num * 4 * hists.sample_rate);
+            assert_eq!(budget[2 * idx + 1], num * 2 * hists.sample_rate);
+        }
+        //a phase's scheduled capacity is shared in proportion to the sets
+        let scheduled_cli = Cli {
+            cache_size: 8,
+            set_associativity: 4,
+            set_capacities: Some(vec![4, 2]),
+            capacity_schedule: Some(CapacitySchedule {
+                phases: [(0, 3)].into(),
+                ..CapacitySchedule::default()
+            }),
+            ..Cli::default()
+        };
+        let num = hists.samples_per_phase[&0];
+        assert_eq!(
+            budget_per_cell(&scheduled_cli, &context)[..2],
+            [num * 2 * hists.sample_rate, num * hists.sample_rate]
+        );
+
+        //the smaller set's occupancy stays within its budget past the base leases
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        let base_results = LeaseResults {
+            leases: lease_results
+                .leases
+                .keys()
+                .map(|&reference| (reference, cli.base_lease()))
+                .collect(),
+            dual_leases: Default::default(),
+            ..lease_results.clone()
+        };
+        let used = lease_results.cost_per_cell(false, &cli, &context);
+        let base_used = base_results.cost_per_cell(false, &cli, &context);
+        for cell in 0..budget.len() {
+            assert!(used[cell] <= (budget[cell] as f64).max(base_used[cell]) + 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod binning_tests {
+    use super::test_traces::*;
+    use crate::cli::Cli;
+    use crate::cost_index::CostIndex;
+    use crate::io::build_ri_hists_from_iter;
+    use crate::lease_gen::{LeaseGrid, budget_per_cell, get_ppuc, lease_cost_per_cell, ppuc_hulls};
+    use crate::shel_cshel::shel_cshel;
+
+    #[test]
+    fn binned_leases_fit_the_exact_budgets() {
+        use crate::binning::{RIBinning, bin_ri_hists};
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let binned_hists = bin_ri_hists(&hists.ri_hists, RIBinning::Log, 2);
+        let (exact_context, binned_context) = (hists.context(), hists.context_on(&binned_hists));
+        let lease_results = shel_cshel(false, &cli, &binned_context).unwrap();
+
+        //binning only lengthens RIs, so costs on the binned histograms bound the exact ones
+        let budget = budget_per_cell(&cli, &exact_context);
+        let mut used = vec![0.0; budget.len()];
+        let mut binned_used = vec![0.0; budget.len()];
+        let exact_index = CostIndex::new(false, &exact_context);
+        let binned_index = CostIndex::new(false, &binned_context);
+        for &ref_id in lease_results.leases.keys() {
+            for (lease, weight) in lease_results.lease_weights(ref_id, None) {
+                for (cost_index, context, used) in [
+                    (&exact_index, &exact_context, &mut used),
+                    (&binned_index, &binned_context, &mut binned_used),
+                ] {
+                    let cost = lease_cost_per_cell(cost_index, context, ref_id, 0, lease);
+                    for (used, cost) in used.iter_mut().zip(cost) {
+                        *used += weight * cost as f64;
+                    }
+                }
+            }
+        }
+        for (used, binned_used) in used.iter().zip(binned_used.iter()) {
+            assert!(used <= binned_used);
+        }
+
+        //without the report, only the binned allocation runs; hits still come from the exact
+        //histograms
+        let binned_cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            ri_binning: RIBinning::Log,
+            ri_bins: 2,
+            ..Cli::default()
+        };
+        let binned_results = crate::binned_leases(false, &binned_cli, &exact_context);
+        assert_eq!(binned_results.leases, lease_results.leases);
+        assert_eq!(
+            binned_results.lease_hits,
+            shel_cshel(false, &cli, &exact_context).unwrap().lease_hits
+        );
+    }
+
+    #[test]
+    fn ppuc_hull_takes_the_highest_ppuc() {
+        let trace = synthetic_trace(3000, 3, 24);
+        let (ri_hists, _, _, _) = build_ri_hists_from_iter(&trace, false, 7, 1, None, None);
+        for base_lease in [0, 1] {
+            let hulls = ppuc_hulls(&ri_hists, base_lease, LeaseGrid::default());
+            for (&ref_id, ri_hist) in ri_hists.ri_hists.iter() {
+                let hull = &hulls[&ref_id];
+                assert!(hull.len() <= ri_hist.len() + 1);
+                //walk the hull the way the allocator does, checking every step against get_ppuc
+                let mut lease = base_lease;
+                loop {
+                    let highest = get_ppuc(ref_id, lease, ri_hist)
+                        .into_iter()
+                        .max_by(|a, b| a.ppuc.partial_cmp(&b.ppuc).unwrap());
+                    let next = hull.next_ppuc(lease);
+                    assert_eq!(next, highest);
+                    match next {
+                        Some(ppuc) => lease = ppuc.lease,
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod cost_index_tests {
+    use crate::cost_index::CostIndex;
+    use crate::lease_gen::{
+        LeaseOperationContext, RIHists, RefKey, cshel_phase_ref_cost, lease_cost_per_cell,
+        shel_phase_ref_cost,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn cost_index_matches_the_cost_functions() {
+        let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+        let mut next = move |bound: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (state >> 33) % bound
+        };
+        let mut hists = BTreeMap::new();
+        for reference in 0..6 {
+            for set in 0..2 {
+                let mut ri_hist = BTreeMap::new();
+                let phases: Vec<u64> = (0..3).filter(|_| next(2) == 0).collect();
+                for _ in 0..20 {
+                    //a tail cost below every head cost keeps costs growing with the lease
+                    let phase_costs = phases
+                        .iter()
+                        .map(|&phase| (phase, (next(50) + 50, next(50))))
+                        .collect();
+                    ri_hist.insert(next(80) + 1, (next(9) + 1, phase_costs));
+                }
+                hists.insert(RefKey::new(reference % 3, reference, set), ri_hist);
+            }
+        }
+        let ri_hists = RIHists::new(hists);
+        let samples_per_phase = BTreeMap::from([(0, 100), (1, 100), (2, 100)]);
+        let context = LeaseOperationContext {
+            ri_hists: &ri_hists,
+            sample_rate: 4,
+            samples_per_phase: &samples_per_phase,
+            set_mask: 1,
+            misses_from_first_access: 0,
+            max_scopes: 4,
+        };
+        for cshel in [false, true] {
+            let cost_index = CostIndex::new(cshel, &context);
+            for &ref_id in ri_hists.ri_hists.keys() {
+                let mut leases: Vec<u64> = ri_hists.ri_hists[&ref_id].keys().cloned().collect();
+                //leases between RIs too: another set's RIs are candidates for this one
+                leases.extend([0, 1, 45, 200]);
+                leases.sort();
+                for phase in 0..4 {
+                    for (idx, &old_lease) in leases.iter().enumerate() {
+                        for &new_lease in &leases[idx..] {
+                            let expected = match cshel {
+                                true => cshel_phase_ref_cost(
+                                    4, phase, ref_id, old_lease, new_lease, &ri_hists,
+                                ),
+                                false => shel_phase_ref_cost(
+                                    4, phase, ref_id, old_lease, new_lease, &ri_hists,
+                                ),
+                            };
+                            assert_eq!(
+                                cost_index.cost(phase, ref_id, old_lease, new_lease),
+                                expected
+                            );
+                        }
+                    }
+                }
+                //the per-cell costs only look at the cells the reference touches
+                let phase_ref = ref_id.phase_ref();
+                let cells = lease_cost_per_cell(&cost_index, &context, phase_ref, 1, 45);
+                for (cell, &cost) in cells.iter().enumerate() {
+                    let (phase, set) = (cell as u64 / 2, cell as u64 % 2);
+                    let expected = match cshel {
+                        true => cshel_phase_ref_cost(
+                            4,
+                            phase,
+                            phase_ref.with_set(set),
+                            1,
+                            45,
+                            &ri_hists,
+                        ),
+                        false => {
+                            shel_phase_ref_cost(4, phase, phase_ref.with_set(set), 1, 45, &ri_hists)
+                        }
+                    };
+                    assert_eq!(cost, expected);
+                    if cost > 0 {
+                        assert!(cost_index.touched_cells(phase_ref).contains(&(phase, set)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod pruning_tests {
+    use super::test_traces::*;
+    use crate::cli::Cli;
+    use crate::lease_gen::PruneStrategy;
+    use crate::shel_cshel::shel_cshel;
+
+    #[test]
+    fn knapsack_pruning_keeps_the_most_hits() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let lease_results = shel_cshel(false, &cli, &context).unwrap();
+        let unpruned_hits = lease_results.predicted_hits(cli.discretize_width);
+
+        let pruned_hits = |strategy| {
+            let mut pruned = lease_results.clone();
+            pruned.prune_leases_to_fit_llt(
+                &hists.ri_hists,
+                2,
+                strategy,
+                cli.max_dual_leases_per_phase,
+            );
+            pruned.predicted_hits(cli.discretize_width)
+        };
+        let knapsack_hits = pruned_hits(PruneStrategy::Knapsack);
+        assert!(knapsack_hits <= unpruned_hits);
+        for strategy in PruneStrategy::ALL {
+            assert!(
+                pruned_hits(strategy) <= knapsack_hits,
+                "{:?} {} {}",
+                strategy,
+                pruned_hits(strategy),
+                knapsack_hits
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod optimal_tests {
+    use super::test_traces::*;
+    use crate::cli::Cli;
+    use crate::cost_index::CostIndex;
+    use crate::distribution::distribution_leases;
+    use crate::io::dump_optimality_gap;
+    use crate::lease_gen::{LeaseResults, budget_per_cell, lease_cost_per_cell};
+    use crate::optimal::optimal_leases;
+    use crate::refine::{RefineLimits, refine_leases};
+    use crate::shel_cshel::shel_cshel;
+    use std::time::Duration;
+
+    #[test]
+    fn optimal_leases_bound_the_greedy() {
+        let trace = synthetic_trace(300, 2, 4);
+        let cli = Cli {
+            cache_size: 4,
+            set_associativity: 2,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let greedy = shel_cshel(false, &cli, &context).unwrap();
+        let optimal = optimal_leases(false, &cli, &context).unwrap();
+        assert!(
+            optimal.predicted_hits(cli.discretize_width)
+                >= greedy.predicted_hits(cli.discretize_width)
+        );
+        assert!(optimal.dual_leases.len() <= hists.samples_per_phase.len());
+    }
+
+    #[test]
+    fn optimality_gap_is_labelled_a_bound_with_competing_duals() {
+        let trace = synthetic_trace(300, 2, 4);
+        let cli = Cli {
+            cache_size: 4,
+            set_associativity: 2,
+            max_dual_leases_per_phase: 2,
+            lease_layout: crate::io::LeaseLayout::PerEntry,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let greedy = shel_cshel(false, &cli, &context).unwrap();
+        let optimal = optimal_leases(false, &cli, &context).unwrap();
+        let dir = std::env::temp_dir().join(format!("lease_gen_gap_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let report = |cshel, cli: &Cli| {
+            dump_optimality_gap(&greedy, &optimal, cshel, cli, dir.to_str().unwrap());
+            std::fs::read_to_string(dir.join("optimality_gap.txt")).unwrap()
+        };
+        let single_dual = Cli {
+            cache_size: 4,
+            set_associativity: 2,
+            ..Cli::default()
+        };
+        assert!(report(false, &cli).contains("optimal hits (lower bound):"));
+        assert!(report(true, &single_dual).contains("gap (lower bound):"));
+        assert!(!report(false, &single_dual).contains("lower bound"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refinement_only_raises_predicted_hits() {
+        let trace = synthetic_trace(300, 2, 4);
+        let cli = Cli {
+            cache_size: 4,
+            set_associativity: 2,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let greedy = shel_cshel(false, &cli, &context).unwrap();
+        let optimal = optimal_leases(false, &cli, &context).unwrap();
+        let mut refined = greedy.clone();
+        let limits = RefineLimits {
+            max_iterations: 10,
+            time_limit: Duration::from_secs(10),
+        };
+        refine_leases(false, &cli, &context, &mut refined, limits);
+
+        let hits = |results: &LeaseResults| results.predicted_hits(cli.discretize_width);
+        assert!(hits(&refined) >= hits(&greedy));
+        assert!(hits(&refined) <= hits(&optimal));
+    }
+
+    #[test]
+    fn lease_distributions_stay_within_budget() {
+        let trace = synthetic_trace(2000, 3, 16);
+        let cli = Cli {
+            cache_size: 8,
+            set_associativity: 2,
+            lease_distributions: true,
+            ..Cli::default()
+        };
+        let hists = TraceHists::new(&cli, false, &trace);
+        let context = hists.context();
+        let lease_results = distribution_leases(false, &cli, &context).unwrap();
+        let one = (1u64 << cli.discretize_width) as f64;
+        let budget = budget_per_cell(&cli, &context);
+        let mut used = vec![0.0; budget.len()];
+        let mut baseline = vec![0; budget.len()];
+        let cost_index = CostIndex::new(false, &context);
+        for &ref_id in lease_results.leases.keys() {
+            let cost = lease_cost_per_cell(&cost_index, &context, ref_id, 0, 1);
+            for (baseline, cost) in baseline.iter_mut().zip(cost) {
+                *baseline += cost;
+            }
+            let weights = lease_results.lease_weights(ref_id, None);
+            let total: f64 = weights.iter().map(|(_, weight)| weight).sum();
+            assert!((total - 1.0).abs() < 1e-9);
+            //every probability is on the fixed-point grid the hardware uses
+            for (_, weight) in weights.iter() {
+                assert_eq!((weight * one).fract(), 0.0);
+            }
+            for (lease, weight) in weights {
+                let cost = lease_cost_per_cell(&cost_index, &context, ref_id, 0, lease);
+                for (used, cost) in used.iter_mut().zip(cost) {
+                    *used += weight * cost as f64;
+                }
+            }
+        }
+        //the default leases alone may overrun a cell; no probability moves into those
+        let rounding = lease_results.leases.len() as f64;
+        for ((used, budget), baseline) in used.iter().zip(budget.iter()).zip(baseline.iter()) {
+            assert!(*used <= *budget.max(baseline) as f64 + rounding);
+        }
+        assert!(lease_results.predicted_hits(cli.discretize_width) > 0);
     }
 }